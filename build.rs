@@ -27,6 +27,8 @@ fn main() {
         "proto/astarteplatform/msghub/astarte_message.proto",
         "proto/astarteplatform/msghub/astarte_type.proto",
         "proto/astarteplatform/msghub/config.proto",
+        "proto/astarteplatform/msghub/connection.proto",
+        "proto/astarteplatform/msghub/admin.proto",
     ];
 
     let mut config = tonic_build::configure();
@@ -37,6 +39,25 @@ fn main() {
         config = config.protoc_arg("--experimental_allow_proto3_optional");
     }
 
+    // The configuration messages are converted from and to the configuration options
+    for message in [
+        "ConfigMessage",
+        "QueueConfig",
+        "SessionConfig",
+        "MetricsConfig",
+        "AdminConfig",
+        "ProvisioningListenerConfig",
+        "ProvisioningConfig",
+        "AuthConfig",
+        "DeviceIdProviderConfig",
+        "SecretsConfig",
+    ] {
+        config = config.type_attribute(
+            format!(".astarteplatform.msghub.{}", message),
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default)]",
+        );
+    }
+
     config
         .compile_well_known_types(true)
         .extern_path(".google.protobuf", "::pbjson_types")
@@ -20,10 +20,16 @@
 
 //! Astarte Message Hub client example, will send the uptime every 3 seconds to Astarte.
 
+use std::path::PathBuf;
 use std::time;
 
 use clap::Parser;
+use tokio::net::UnixStream;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Request;
 
+use astarte_message_hub::auth::{AUTHORIZATION_METADATA, NODE_ID_METADATA};
 use astarte_message_hub::proto_message_hub::astarte_message::Payload;
 use astarte_message_hub::proto_message_hub::message_hub_client::MessageHubClient;
 use astarte_message_hub::proto_message_hub::AstarteMessage;
@@ -44,6 +50,29 @@ struct Cli {
     /// Milliseconds to wait between messages.
     #[clap(short, long, default_value = "3000")]
     time: u64,
+
+    /// Connect to the message hub through the Unix domain socket at the given path.
+    #[clap(short, long)]
+    socket: Option<PathBuf>,
+
+    /// Token used to authenticate the node with the message hub.
+    #[clap(long)]
+    token: Option<String>,
+}
+
+/// Connect to the message hub, over TCP or over a Unix domain socket.
+async fn connect(socket: Option<PathBuf>) -> Result<Channel, tonic::transport::Error> {
+    match socket {
+        Some(path) => {
+            // The URI is required by tonic but ignored by the connector
+            Endpoint::from_static("http://[::]:50051")
+                .connect_with_connector(tower::service_fn(move |_| {
+                    UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+        None => Endpoint::from_static("http://[::1]:50051").connect().await,
+    }
 }
 
 #[tokio::main]
@@ -51,9 +80,25 @@ async fn main() {
     env_logger::init();
     let args = Cli::parse();
 
-    let mut client = MessageHubClient::connect("http://[::1]:50051")
-        .await
-        .unwrap();
+    let channel = connect(args.socket.clone()).await.unwrap();
+
+    let authorization: Option<MetadataValue<Ascii>> = args
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token).parse().unwrap());
+    let node_id: MetadataValue<Ascii> = args.uuid.parse().unwrap();
+    #[allow(clippy::result_large_err)]
+    let mut client = MessageHubClient::with_interceptor(channel, move |mut req: Request<()>| {
+        // Identify the node sending the messages
+        req.metadata_mut().insert(NODE_ID_METADATA, node_id.clone());
+
+        if let Some(authorization) = &authorization {
+            req.metadata_mut()
+                .insert(AUTHORIZATION_METADATA, authorization.clone());
+        }
+
+        Ok(req)
+    });
 
     let interface_jsons = [
         include_str!(
@@ -0,0 +1,668 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Administration API of the Astarte message hub.
+//!
+//! The API lists the attached nodes, with their introspection, attach time and message counters,
+//! and detaches a misbehaving node. It is served as the `MessageHubAdmin` gRPC service and on the
+//! following HTTP routes:
+//!
+//! - `GET /nodes`: list the attached nodes.
+//! - `GET /nodes/{uuid}`: get an attached node.
+//! - `GET /nodes/{uuid}/introspection`: get the interfaces declared by a node.
+//! - `DELETE /nodes/{uuid}`: force the detach of a node.
+//!
+//! Both are protected by the [AdminAuthenticator], separately from the nodes authentication.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Json, Router, Server};
+use log::info;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tonic::{Code, Request, Response, Status};
+use uuid::Uuid;
+
+use crate::astarte_message_hub::AstarteNode;
+use crate::auth::AdminAuthenticator;
+use crate::data::astarte::{AstartePublisher, AstarteRunner, AstarteSubscriber};
+use crate::error::AstarteMessageHubError;
+use crate::interface::Ownership;
+use crate::metrics::Metrics;
+use crate::proto_message_hub::message_hub_admin_server::MessageHubAdmin;
+use crate::proto_message_hub::{InterfaceInfo, Introspection, NodeId, NodeInfo, NodeList};
+use crate::AstarteMessageHub;
+
+/// Administration API of the message hub, created with [AstarteMessageHub::admin].
+#[derive(Clone)]
+pub struct AstarteMessageHubAdmin<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber> {
+    /// The nodes attached to the message hub.
+    nodes: Arc<RwLock<HashMap<Uuid, AstarteNode>>>,
+    /// The Astarte handler of the message hub.
+    astarte_handler: T,
+    /// Metrics of the message hub.
+    metrics: Metrics,
+}
+
+/// State of the admin HTTP server.
+#[derive(Clone)]
+struct AdminHttpState<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber> {
+    admin: AstarteMessageHubAdmin<T>,
+    authenticator: AdminAuthenticator,
+}
+
+impl<T: 'static> AstarteMessageHubAdmin<T>
+where
+    T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber,
+{
+    pub(crate) fn new(
+        nodes: Arc<RwLock<HashMap<Uuid, AstarteNode>>>,
+        astarte_handler: T,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            nodes,
+            astarte_handler,
+            metrics,
+        }
+    }
+
+    /// Returns the attached nodes, sorted by UUID.
+    async fn list_nodes(&self) -> NodeList {
+        let nodes = self.nodes.read().await;
+
+        let mut nodes: Vec<NodeInfo> = nodes.values().map(node_info).collect();
+        nodes.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+        NodeList { nodes }
+    }
+
+    /// Returns an attached node.
+    async fn get_node(&self, id: &Uuid) -> Result<NodeInfo, Status> {
+        self.nodes
+            .read()
+            .await
+            .get(id)
+            .map(node_info)
+            .ok_or_else(|| node_not_found(id))
+    }
+
+    /// Returns the interfaces declared by an attached node.
+    async fn get_introspection(&self, id: &Uuid) -> Result<Introspection, Status> {
+        let nodes = self.nodes.read().await;
+        let node = nodes.get(id).ok_or_else(|| node_not_found(id))?;
+
+        Ok(Introspection {
+            uuid: node.id.to_string(),
+            interface_jsons: node.introspection.iter().map(|i| i.0.clone()).collect(),
+        })
+    }
+
+    /// Detach a node, closing its stream.
+    async fn force_detach(&self, id: &Uuid) -> Result<(), Status> {
+        if !self.nodes.read().await.contains_key(id) {
+            return Err(node_not_found(id));
+        }
+
+        info!("Forcing the detach of node {}", id);
+
+        AstarteMessageHub::remove_node(&self.nodes, &self.astarte_handler, &self.metrics, id, None)
+            .await
+    }
+
+    /// Serve the administration API on an HTTP server listening on the address.
+    pub async fn serve_http(
+        self,
+        address: SocketAddr,
+        authenticator: AdminAuthenticator,
+    ) -> Result<(), AstarteMessageHubError> {
+        let server = Server::try_bind(&address)?;
+        info!("Admin HTTP server listening on {}", address);
+
+        server
+            .serve(self.router(authenticator).into_make_service())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Routes of the admin HTTP server.
+    fn router(self, authenticator: AdminAuthenticator) -> Router {
+        Router::new()
+            .route("/nodes", get(Self::http_list_nodes))
+            .route(
+                "/nodes/:uuid",
+                get(Self::http_get_node).delete(Self::http_force_detach),
+            )
+            .route(
+                "/nodes/:uuid/introspection",
+                get(Self::http_get_introspection),
+            )
+            .layer(Extension(AdminHttpState {
+                admin: self,
+                authenticator,
+            }))
+    }
+
+    /// HTTP endpoint listing the attached nodes.
+    async fn http_list_nodes(
+        Extension(state): Extension<AdminHttpState<T>>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        http_response(async {
+            authorize_http(&state.authenticator, &headers)?;
+
+            let nodes = state.admin.list_nodes().await;
+
+            Ok(Value::Array(nodes.nodes.iter().map(node_json).collect()))
+        })
+        .await
+    }
+
+    /// HTTP endpoint returning an attached node.
+    async fn http_get_node(
+        Extension(state): Extension<AdminHttpState<T>>,
+        headers: HeaderMap,
+        Path(uuid): Path<String>,
+    ) -> impl IntoResponse {
+        http_response(async {
+            authorize_http(&state.authenticator, &headers)?;
+
+            let node = state.admin.get_node(&parse_uuid(&uuid)?).await?;
+
+            Ok(node_json(&node))
+        })
+        .await
+    }
+
+    /// HTTP endpoint returning the interfaces declared by an attached node.
+    async fn http_get_introspection(
+        Extension(state): Extension<AdminHttpState<T>>,
+        headers: HeaderMap,
+        Path(uuid): Path<String>,
+    ) -> impl IntoResponse {
+        http_response(async {
+            authorize_http(&state.authenticator, &headers)?;
+
+            let introspection = state.admin.get_introspection(&parse_uuid(&uuid)?).await?;
+
+            // The interfaces are valid JSON, they are checked when the node attaches
+            let interfaces = introspection
+                .interface_jsons
+                .iter()
+                .filter_map(|json| serde_json::from_slice::<Value>(json).ok())
+                .collect();
+
+            Ok(Value::Array(interfaces))
+        })
+        .await
+    }
+
+    /// HTTP endpoint forcing the detach of a node.
+    async fn http_force_detach(
+        Extension(state): Extension<AdminHttpState<T>>,
+        headers: HeaderMap,
+        Path(uuid): Path<String>,
+    ) -> impl IntoResponse {
+        http_response(async {
+            authorize_http(&state.authenticator, &headers)?;
+
+            state.admin.force_detach(&parse_uuid(&uuid)?).await?;
+
+            Ok(json!({ "result": "OK" }))
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static> MessageHubAdmin
+    for AstarteMessageHubAdmin<T>
+{
+    /// List the nodes attached to the message hub.
+    async fn list_nodes(
+        &self,
+        request: Request<pbjson_types::Empty>,
+    ) -> Result<Response<NodeList>, Status> {
+        info!("Admin List Nodes Request => {:?}", request);
+
+        Ok(Response::new(
+            AstarteMessageHubAdmin::list_nodes(self).await,
+        ))
+    }
+
+    /// Get the status of an attached node.
+    async fn get_node(&self, request: Request<NodeId>) -> Result<Response<NodeInfo>, Status> {
+        info!("Admin Get Node Request => {:?}", request);
+
+        let id = parse_uuid(&request.get_ref().uuid)?;
+
+        AstarteMessageHubAdmin::get_node(self, &id)
+            .await
+            .map(Response::new)
+    }
+
+    /// Detach a node, closing its stream and removing its interfaces.
+    async fn force_detach(
+        &self,
+        request: Request<NodeId>,
+    ) -> Result<Response<pbjson_types::Empty>, Status> {
+        info!("Admin Force Detach Request => {:?}", request);
+
+        let id = parse_uuid(&request.get_ref().uuid)?;
+
+        AstarteMessageHubAdmin::force_detach(self, &id).await?;
+
+        Ok(Response::new(pbjson_types::Empty {}))
+    }
+
+    /// Get the interfaces declared by an attached node.
+    async fn get_introspection(
+        &self,
+        request: Request<NodeId>,
+    ) -> Result<Response<Introspection>, Status> {
+        info!("Admin Get Introspection Request => {:?}", request);
+
+        let id = parse_uuid(&request.get_ref().uuid)?;
+
+        AstarteMessageHubAdmin::get_introspection(self, &id)
+            .await
+            .map(Response::new)
+    }
+}
+
+/// Status of an attached node.
+fn node_info(node: &AstarteNode) -> NodeInfo {
+    let mut interfaces: Vec<InterfaceInfo> = node
+        .interfaces
+        .values()
+        .map(|interface| InterfaceInfo {
+            interface_name: interface.interface_name.clone(),
+            version_major: interface.version_major,
+            version_minor: interface.version_minor,
+            server_owned: interface.ownership == Ownership::Server,
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.interface_name.cmp(&b.interface_name));
+
+    NodeInfo {
+        uuid: node.id.to_string(),
+        attached_at: Some(node.attached_at.into()),
+        interfaces,
+        sent_messages: node.counters.sent_messages.load(Ordering::Relaxed),
+        received_events: node.counters.received_events.load(Ordering::Relaxed),
+    }
+}
+
+/// JSON representation of an attached node, returned by the HTTP API.
+fn node_json(node: &NodeInfo) -> Value {
+    let interfaces: Vec<Value> = node
+        .interfaces
+        .iter()
+        .map(|interface| {
+            json!({
+                "interface_name": interface.interface_name,
+                "version_major": interface.version_major,
+                "version_minor": interface.version_minor,
+                "server_owned": interface.server_owned,
+            })
+        })
+        .collect();
+
+    json!({
+        "uuid": node.uuid,
+        "attached_at": node.attached_at,
+        "interfaces": interfaces,
+        "sent_messages": node.sent_messages,
+        "received_events": node.received_events,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_uuid(uuid: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(uuid).map_err(|err| {
+        Status::invalid_argument(format!(
+            "Unable to parse UUID value, err {:?}",
+            err.to_string()
+        ))
+    })
+}
+
+fn node_not_found(id: &Uuid) -> Status {
+    Status::not_found(format!("Node {} is not attached", id))
+}
+
+/// Check the `Authorization` header of an HTTP request.
+#[allow(clippy::result_large_err)]
+fn authorize_http(authenticator: &AdminAuthenticator, headers: &HeaderMap) -> Result<(), Status> {
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    authenticator.check(authorization)
+}
+
+/// Convert the result of an HTTP endpoint in a JSON response.
+async fn http_response<F>(result: F) -> (StatusCode, Json<Value>)
+where
+    F: std::future::Future<Output = Result<Value, Status>>,
+{
+    match result.await {
+        Ok(value) => (StatusCode::OK, Json(value)),
+        Err(status) => {
+            let code = match status.code() {
+                Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+                Code::InvalidArgument => StatusCode::BAD_REQUEST,
+                Code::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            (
+                code,
+                Json(json!({ "result": "KO", "message": status.message() })),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use axum::http::Request as HttpRequest;
+    use mockall::mock;
+    use tokio::sync::mpsc::{self, Receiver};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::proto_message_hub;
+    use crate::proto_message_hub::message_hub_server::MessageHub;
+
+    mock! {
+        AstarteHandler { }
+
+        impl Clone for AstarteHandler {
+            fn clone(&self) -> Self;
+        }
+
+        #[async_trait]
+        impl AstarteRunner for AstarteHandler {
+            async fn run(&mut self);
+
+            fn watch_connection(&self) -> watch::Receiver<proto_message_hub::ConnectionEvent>;
+        }
+
+        #[async_trait]
+        impl AstartePublisher for AstarteHandler {
+            async fn publish(
+                &self,
+                data: &proto_message_hub::AstarteMessage
+            ) -> Result<(), AstarteMessageHubError>;
+        }
+
+        #[async_trait]
+        impl AstarteSubscriber for AstarteHandler {
+            async fn subscribe(
+                &self,
+                astarte_node: &AstarteNode,
+            ) -> Result<Receiver<Result<proto_message_hub::AstarteMessage, Status>>, AstarteMessageHubError>;
+
+            async fn unsubscribe(&self, astarte_node: &AstarteNode) -> Result<(), AstarteMessageHubError>;
+        }
+    }
+
+    const DEVICE_DATASTREAM_IFACE: &str = r#"
+        {
+            "interface_name": "io.demo.Values",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "datastream",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/test",
+                    "type": "integer"
+                }
+            ]
+        }
+        "#;
+
+    const NODE_ID: &str = "550e8400-e29b-41d4-a716-446655440000";
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    /// Clone of the handler, used by the admin API to unsubscribe the nodes.
+    fn cloned_handler() -> MockAstarteHandler {
+        let mut mock = MockAstarteHandler::new();
+        mock.expect_unsubscribe().returning(|_| Ok(()));
+        mock.expect_clone().returning(cloned_handler);
+        mock
+    }
+
+    /// Create a message hub with an attached node, returns it with the event sender of the node.
+    async fn hub_with_node() -> (
+        AstarteMessageHub<MockAstarteHandler>,
+        mpsc::Sender<Result<proto_message_hub::AstarteMessage, Status>>,
+    ) {
+        let (tx, rx) = mpsc::channel(2);
+        let rx = std::sync::Mutex::new(Some(rx));
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte
+            .expect_subscribe()
+            .returning(move |_| Ok(rx.lock().unwrap().take().unwrap()));
+        mock_astarte.expect_clone().returning(cloned_handler);
+        mock_astarte.expect_publish().returning(|_| Ok(()));
+
+        let hub = AstarteMessageHub::new(mock_astarte);
+
+        let node = proto_message_hub::Node {
+            uuid: NODE_ID.to_string(),
+            interface_jsons: vec![DEVICE_DATASTREAM_IFACE.as_bytes().to_vec()],
+        };
+
+        let mut stream = hub.attach(Request::new(node)).await.unwrap().into_inner();
+
+        // Deliver an event to the node
+        tx.send(Ok(proto_message_hub::AstarteMessage::default()))
+            .await
+            .unwrap();
+        tokio_stream::StreamExt::next(&mut stream).await;
+
+        // The stream is dropped, but the node is kept attached during the grace period
+        (hub, tx)
+    }
+
+    #[tokio::test]
+    async fn list_and_get_nodes() {
+        let (hub, _tx) = hub_with_node().await;
+        let admin = hub.admin();
+
+        let message = proto_message_hub::AstarteMessage {
+            interface_name: "io.demo.Values".to_string(),
+            path: "/test".to_string(),
+            timestamp: None,
+            payload: Some(proto_message_hub::astarte_message::Payload::AstarteData(
+                10.into(),
+            )),
+        };
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(crate::auth::NODE_ID_METADATA, NODE_ID.parse().unwrap());
+        hub.send(request).await.unwrap();
+
+        let nodes = MessageHubAdmin::list_nodes(&admin, Request::new(pbjson_types::Empty {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .nodes;
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].uuid, NODE_ID);
+        assert!(nodes[0].attached_at.is_some());
+        assert_eq!(nodes[0].sent_messages, 1);
+        assert_eq!(nodes[0].received_events, 1);
+        assert_eq!(
+            nodes[0].interfaces,
+            vec![InterfaceInfo {
+                interface_name: "io.demo.Values".to_string(),
+                version_major: 0,
+                version_minor: 1,
+                server_owned: false,
+            }]
+        );
+
+        let node = MessageHubAdmin::get_node(
+            &admin,
+            Request::new(NodeId {
+                uuid: NODE_ID.to_string(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(node, nodes[0]);
+
+        let introspection = MessageHubAdmin::get_introspection(
+            &admin,
+            Request::new(NodeId {
+                uuid: NODE_ID.to_string(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            introspection.interface_jsons,
+            vec![DEVICE_DATASTREAM_IFACE.as_bytes().to_vec()]
+        );
+
+        let status = MessageHubAdmin::get_node(
+            &admin,
+            Request::new(NodeId {
+                uuid: Uuid::new_v4().to_string(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn force_detach_node() {
+        let (hub, _tx) = hub_with_node().await;
+        let admin = hub.admin();
+
+        let request = |uuid: &str| {
+            Request::new(NodeId {
+                uuid: uuid.to_string(),
+            })
+        };
+
+        let status = MessageHubAdmin::force_detach(&admin, request("a1"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        MessageHubAdmin::force_detach(&admin, request(NODE_ID))
+            .await
+            .unwrap();
+
+        let nodes = admin.list_nodes().await.nodes;
+        assert!(nodes.is_empty());
+
+        let status = MessageHubAdmin::force_detach(&admin, request(NODE_ID))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn http_routes_require_admin_token() {
+        let (hub, _tx) = hub_with_node().await;
+        let authenticator = AdminAuthenticator::new(vec![ADMIN_TOKEN.to_string()]).unwrap();
+        let router = hub.admin().router(authenticator);
+
+        let request = |method: &str, uri: &str, token: Option<&str>| {
+            let mut builder = HttpRequest::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = router
+            .clone()
+            .oneshot(request("GET", "/nodes", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = router
+            .clone()
+            .oneshot(request("GET", "/nodes", Some("wrong")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = router
+            .clone()
+            .oneshot(request("GET", "/nodes", Some(ADMIN_TOKEN)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let nodes: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(nodes[0]["uuid"], NODE_ID);
+        assert_eq!(nodes[0]["received_events"], 1);
+
+        let uri = format!("/nodes/{}/introspection", NODE_ID);
+        let response = router
+            .clone()
+            .oneshot(request("GET", &uri, Some(ADMIN_TOKEN)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let interfaces: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(interfaces[0]["interface_name"], "io.demo.Values");
+
+        let uri = format!("/nodes/{}", NODE_ID);
+        let response = router
+            .clone()
+            .oneshot(request("DELETE", &uri, Some(ADMIN_TOKEN)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router
+            .oneshot(request("GET", &uri, Some(ADMIN_TOKEN)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -20,24 +20,105 @@
 //! Contains the implementation for the Astarte message hub.
 
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use log::info;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
+use crate::admin::AstarteMessageHubAdmin;
+use crate::auth::{authorize, node_id};
 use crate::data::astarte::{AstartePublisher, AstarteRunner, AstarteSubscriber};
+use crate::error::AstarteMessageHubError;
+use crate::interface::{InterfaceDefinition, Ownership};
+use crate::metrics::Metrics;
 use crate::proto_message_hub;
 use crate::types::InterfaceJson;
 
+/// Default time a node is kept attached after its stream has been dropped.
+const DEFAULT_DETACH_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 /// Main struct for the Astarte message hub.
 pub struct AstarteMessageHub<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber> {
     /// The nodes connected to the message hub.
     nodes: Arc<RwLock<HashMap<Uuid, AstarteNode>>>,
     /// The Astarte handler used to communicate with Astarte.
     astarte_handler: T,
+    /// Counter used to identify each attach of a node.
+    next_session: AtomicU64,
+    /// Time a node is kept attached after its stream has been dropped.
+    detach_grace_period: Duration,
+    /// Channel notifying the streams dropped by the nodes.
+    dropped_streams: UnboundedSender<DroppedStream>,
+    /// Metrics of the attached nodes and of their messages.
+    metrics: Metrics,
+    /// Task running the Astarte handler.
+    runner: JoinHandle<()>,
+    /// Task detaching the nodes whose stream was dropped.
+    detacher: JoinHandle<()>,
+}
+
+/// Stream of a node dropped without detaching.
+#[derive(Clone)]
+struct DroppedStream {
+    id: Uuid,
+    session: u64,
+    grace_period: Duration,
+    metrics: Metrics,
+}
+
+/// Stream of the events received from Astarte for an attached node.
+///
+/// When the stream is dropped, e.g. because the node crashed or closed the connection, the node
+/// is detached after the grace period, unless it attached again in the meantime.
+pub struct NodeStream {
+    inner: ReceiverStream<Result<proto_message_hub::AstarteMessage, Status>>,
+    counters: Arc<NodeCounters>,
+    dropped: DroppedStream,
+    dropped_streams: UnboundedSender<DroppedStream>,
+}
+
+impl Stream for NodeStream {
+    type Item = Result<proto_message_hub::AstarteMessage, Status>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+
+        if let Poll::Ready(Some(Ok(_))) = poll {
+            this.counters
+                .received_events
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        poll
+    }
+}
+
+impl Drop for NodeStream {
+    fn drop(&mut self) {
+        // The hub could have been dropped already
+        let _ = self.dropped_streams.send(self.dropped.clone());
+    }
+}
+
+/// Counters of the messages of an attached node.
+#[derive(Debug, Default)]
+pub(crate) struct NodeCounters {
+    /// Messages sent by the node.
+    pub(crate) sent_messages: AtomicU64,
+    /// Events delivered to the node.
+    pub(crate) received_events: AtomicU64,
 }
 
 /// A single node that can be connected to the Astarte message hub.
@@ -46,16 +127,69 @@ pub struct AstarteNode {
     pub id: Uuid,
     /// A vector of interfaces for this node.
     pub introspection: Vec<InterfaceJson>,
+    /// Definitions of the interfaces in the introspection, indexed by name.
+    pub(crate) interfaces: HashMap<String, InterfaceDefinition>,
+    /// Identifier of the attach of the node.
+    session: u64,
+    /// When the node attached.
+    pub(crate) attached_at: DateTime<Utc>,
+    /// Counters of the messages of the node since it attached.
+    pub(crate) counters: Arc<NodeCounters>,
 }
 
 impl AstarteNode {
     /// Instantiate a new node.
     pub fn new(uuid: Uuid, introspection: Vec<Vec<u8>>) -> Self {
+        // Invalid interfaces are rejected when subscribing the node
+        let interfaces = introspection
+            .iter()
+            .filter_map(|json| InterfaceDefinition::from_json(json).ok())
+            .map(|interface| (interface.interface_name.clone(), interface))
+            .collect();
+
         AstarteNode {
             id: uuid,
             introspection: introspection.into_iter().map(InterfaceJson).collect(),
+            interfaces,
+            session: 0,
+            attached_at: Utc::now(),
+            counters: Arc::new(NodeCounters::default()),
         }
     }
+
+    /// Check that the node can send a message, the interface must be in the node introspection,
+    /// owned by the device and have a mapping for the path.
+    #[allow(clippy::result_large_err)]
+    fn check_message(
+        &self,
+        astarte_message: &proto_message_hub::AstarteMessage,
+    ) -> Result<(), Status> {
+        let interface = self
+            .interfaces
+            .get(&astarte_message.interface_name)
+            .ok_or_else(|| {
+                Status::permission_denied(format!(
+                    "Interface {} is not in the introspection of node {}",
+                    astarte_message.interface_name, self.id
+                ))
+            })?;
+
+        if interface.ownership != Ownership::Device {
+            return Err(Status::permission_denied(format!(
+                "Interface {} is server owned",
+                astarte_message.interface_name
+            )));
+        }
+
+        if !interface.has_path(&astarte_message.path) {
+            return Err(Status::invalid_argument(format!(
+                "Path {} does not match any mapping of interface {}",
+                astarte_message.path, astarte_message.interface_name
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl<T: 'static> AstarteMessageHub<T>
@@ -69,24 +203,136 @@ where
     /// handler.
     pub fn new(astarte_handler: T) -> Self {
         let mut astarte_handler_cpy = astarte_handler.clone();
-        tokio::task::spawn(async move {
+        let runner = tokio::task::spawn(async move {
             loop {
                 astarte_handler_cpy.run().await;
             }
         });
 
+        let nodes = Arc::new(RwLock::new(HashMap::new()));
+
+        let (dropped_streams, mut dropped_rx) = unbounded_channel::<DroppedStream>();
+        let (expired_tx, mut expired_rx) = unbounded_channel::<DroppedStream>();
+
+        let nodes_cpy = nodes.clone();
+        let astarte_handler_cpy = astarte_handler.clone();
+        let detacher = tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    dropped = dropped_rx.recv() => {
+                        // The message hub and all the streams were dropped
+                        let dropped = match dropped {
+                            Some(dropped) => dropped,
+                            None => break,
+                        };
+
+                        let expired_tx = expired_tx.clone();
+                        tokio::task::spawn(async move {
+                            tokio::time::sleep(dropped.grace_period).await;
+                            let _ = expired_tx.send(dropped);
+                        });
+                    }
+                    Some(expired) = expired_rx.recv() => {
+                        info!("Node {} dropped its stream, detaching it", expired.id);
+
+                        let res = Self::remove_node(
+                            &nodes_cpy,
+                            &astarte_handler_cpy,
+                            &expired.metrics,
+                            &expired.id,
+                            Some(expired.session),
+                        )
+                        .await;
+
+                        if let Err(err) = res {
+                            warn!("Unable to detach node {}: {}", expired.id, err.message());
+                        }
+                    }
+                }
+            }
+        });
+
         AstarteMessageHub {
-            nodes: Arc::new(RwLock::new(HashMap::new())),
+            nodes,
             astarte_handler,
+            next_session: AtomicU64::new(0),
+            detach_grace_period: DEFAULT_DETACH_GRACE_PERIOD,
+            dropped_streams,
+            metrics: Metrics::new(),
+            runner,
+            detacher,
         }
     }
+
+    /// Collect the metrics of the nodes in the given metrics, shared with the Astarte handler.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set the time a node is kept attached after its stream has been dropped without detaching.
+    ///
+    /// A node attaching again within the grace period keeps its interfaces registered.
+    pub fn with_detach_grace_period(mut self, detach_grace_period: Duration) -> Self {
+        self.detach_grace_period = detach_grace_period;
+        self
+    }
+
+    /// Returns the administration API of the message hub, sharing its attached nodes.
+    pub fn admin(&self) -> AstarteMessageHubAdmin<T> {
+        AstarteMessageHubAdmin::new(
+            self.nodes.clone(),
+            self.astarte_handler.clone(),
+            self.metrics.clone(),
+        )
+    }
+
+    /// Remove a node and unsubscribe it from the Astarte handler.
+    ///
+    /// When a session is given the node is removed only if it did not attach again.
+    pub(crate) async fn remove_node(
+        nodes: &RwLock<HashMap<Uuid, AstarteNode>>,
+        astarte_handler: &T,
+        metrics: &Metrics,
+        id: &Uuid,
+        session: Option<u64>,
+    ) -> Result<(), Status> {
+        let mut nodes = nodes.write().await;
+
+        match nodes.get(id) {
+            Some(node) if session.map_or(true, |session| session == node.session) => {}
+            Some(_) => return Ok(()),
+            None if session.is_some() => return Ok(()),
+            None => return Err(Status::internal("Unable to find AstarteNode")),
+        }
+
+        let astarte_node = nodes.remove(id).expect("node present");
+        metrics.node_detached(nodes.len());
+
+        if let Err(err) = astarte_handler.unsubscribe(&astarte_node).await {
+            let err_msg = format!("Unable to unsubscribe, err: {err:?}");
+            Err(Status::internal(err_msg))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T> Drop for AstarteMessageHub<T>
+where
+    T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber,
+{
+    fn drop(&mut self) {
+        self.runner.abort();
+        self.detacher.abort();
+    }
 }
 
 #[tonic::async_trait]
 impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static>
     proto_message_hub::message_hub_server::MessageHub for AstarteMessageHub<T>
 {
-    type AttachStream = ReceiverStream<Result<proto_message_hub::AstarteMessage, Status>>;
+    type AttachStream = NodeStream;
 
     /// Attach a node to the Message hub. If the node was successfully attached,
     /// the method returns a gRPC stream into which the events received
@@ -125,32 +371,68 @@ impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static>
         request: Request<proto_message_hub::Node>,
     ) -> Result<Response<Self::AttachStream>, Status> {
         info!("Node Attach Request => {:?}", request);
-        let node = request.into_inner();
 
-        let id = Uuid::parse_str(&node.uuid).map_err(|err| {
+        let id = Uuid::parse_str(&request.get_ref().uuid).map_err(|err| {
             Status::invalid_argument(format!(
                 "Unable to parse UUID value, err {:?}",
                 err.to_string()
             ))
         })?;
 
-        let astarte_node = AstarteNode::new(id, node.interface_jsons);
+        authorize(&request, &id)?;
+
+        let node = request.into_inner();
+
+        let mut astarte_node = AstarteNode::new(id, node.interface_jsons);
+        astarte_node.session = self.next_session.fetch_add(1, Ordering::Relaxed);
+
+        // Hold the nodes until the new session is inserted, so the expired grace period of the
+        // previous session cannot unsubscribe the node while it attaches again
+        let mut nodes = self.nodes.write().await;
         let subscribe_result = self.astarte_handler.subscribe(&astarte_node).await;
 
-        if let Ok(rx) = subscribe_result {
-            let mut nodes = self.nodes.write().await;
-            nodes.insert(astarte_node.id.to_owned(), astarte_node);
-            Ok(Response::new(ReceiverStream::new(rx)))
-        } else {
-            Err(Status::aborted(format!(
+        match subscribe_result {
+            Ok(rx) => {
+                let dropped = DroppedStream {
+                    id,
+                    session: astarte_node.session,
+                    grace_period: self.detach_grace_period,
+                    metrics: self.metrics.clone(),
+                };
+                let counters = astarte_node.counters.clone();
+
+                // The stream of a node attached again is replaced, the previous one is closed
+                if nodes
+                    .insert(astarte_node.id.to_owned(), astarte_node)
+                    .is_some()
+                {
+                    info!("Node {} attached again, replacing its stream", id);
+                }
+                self.metrics.node_attached(nodes.len());
+
+                Ok(Response::new(NodeStream {
+                    inner: ReceiverStream::new(rx),
+                    counters,
+                    dropped,
+                    dropped_streams: self.dropped_streams.clone(),
+                }))
+            }
+            Err(err @ AstarteMessageHubError::InterfaceConflict { .. }) => {
+                Err(Status::failed_precondition(err.to_string()))
+            }
+            Err(err) => Err(Status::aborted(format!(
                 "Unable to subscribe, err: {:?}",
-                subscribe_result.err()
-            )))
+                Some(err)
+            ))),
         }
     }
 
     /// Send a message to Astarte for a node attached to the Astarte Message Hub.
     ///
+    /// The UUID of the sending node is taken from its authenticated identity or from the `node-id`
+    /// metadata. The message is rejected if the node is not attached, if the interface is not in
+    /// the node introspection or is server owned, and if the path does not match any mapping.
+    ///
     /// ```no_run
     /// use astarte_message_hub::proto_message_hub::message_hub_client::MessageHubClient;
     /// use astarte_message_hub::proto_message_hub::Node;
@@ -177,12 +459,17 @@ impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static>
     ///
     ///     let astarte_message = AstarteMessage {
     ///         interface_name: "org.astarteplatform.esp32.examples.DeviceDatastream".to_string(),
-    ///         path: "uptimeSeconds".to_string(),
+    ///         path: "/uptimeSeconds".to_string(),
     ///         timestamp: None,
     ///         payload: Some(Payload::AstarteData(100.into()))
     ///     };
     ///
-    ///     let  _ = message_hub_client.send(astarte_message).await;
+    ///     let mut request = tonic::Request::new(astarte_message);
+    ///     request
+    ///         .metadata_mut()
+    ///         .insert("node-id", "a2d4769f-0338-4f7f-b71d-9f81b41ae13f".parse().unwrap());
+    ///
+    ///     let  _ = message_hub_client.send(request).await;
     ///
     ///     Ok(())
     ///
@@ -193,13 +480,30 @@ impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static>
     ) -> Result<Response<pbjson_types::Empty>, Status> {
         info!("Node Send Request => {:?}", request);
 
+        let id = node_id(&request)?;
         let astarte_message = request.into_inner();
 
-        if let Err(err) = self.astarte_handler.publish(&astarte_message).await {
-            let err_msg = format!("Unable to publish astarte message, err: {:?}", err);
-            Err(Status::internal(err_msg))
-        } else {
-            Ok(Response::new(pbjson_types::Empty {}))
+        {
+            let nodes = self.nodes.read().await;
+            let node = nodes
+                .get(&id)
+                .ok_or_else(|| Status::permission_denied(format!("Node {} is not attached", id)))?;
+
+            node.check_message(&astarte_message)?;
+            node.counters.sent_messages.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.metrics.node_message(&astarte_message.interface_name);
+
+        match self.astarte_handler.publish(&astarte_message).await {
+            Ok(()) => Ok(Response::new(pbjson_types::Empty {})),
+            Err(AstarteMessageHubError::PayloadValidationError(err)) => Err(
+                Status::invalid_argument(format!("Invalid astarte message: {}", err)),
+            ),
+            Err(err) => {
+                let err_msg = format!("Unable to publish astarte message, err: {:?}", err);
+                Err(Status::internal(err_msg))
+            }
         }
     }
 
@@ -209,25 +513,45 @@ impl<T: Clone + AstarteRunner + AstartePublisher + AstarteSubscriber + 'static>
         request: Request<proto_message_hub::Node>,
     ) -> Result<Response<pbjson_types::Empty>, Status> {
         info!("Node Detach Request => {:?}", request);
-        let node = request.into_inner();
 
-        let id = Uuid::parse_str(&node.uuid).map_err(|err| {
+        let id = Uuid::parse_str(&request.get_ref().uuid).map_err(|err| {
             let err_msg = format!("Unable to parse UUID value, err {:?}", err);
             Status::invalid_argument(err_msg)
         })?;
 
-        let mut nodes = self.nodes.write().await;
+        authorize(&request, &id)?;
 
-        if let Some(astarte_node) = nodes.remove(&id) {
-            if let Err(err) = self.astarte_handler.unsubscribe(&astarte_node).await {
-                let err_msg = format!("Unable to unsubscribe, err: {err:?}");
-                Err(Status::internal(err_msg))
-            } else {
-                Ok(Response::new(pbjson_types::Empty {}))
+        Self::remove_node(&self.nodes, &self.astarte_handler, &self.metrics, &id, None).await?;
+
+        Ok(Response::new(pbjson_types::Empty {}))
+    }
+
+    type WatchConnectionStream = ReceiverStream<Result<proto_message_hub::ConnectionEvent, Status>>;
+
+    /// Stream the state of the connection to Astarte, starting from the current one.
+    ///
+    /// The state changes only with the events of the device, publishing while disconnected does
+    /// not report a connection.
+    async fn watch_connection(
+        &self,
+        request: Request<pbjson_types::Empty>,
+    ) -> Result<Response<Self::WatchConnectionStream>, Status> {
+        info!("Watch Connection Request => {:?}", request);
+
+        let mut connection_rx = self.astarte_handler.watch_connection();
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+
+        tokio::task::spawn(async move {
+            loop {
+                let event = connection_rx.borrow_and_update().clone();
+
+                if tx.send(Ok(event)).await.is_err() || connection_rx.changed().await.is_err() {
+                    break;
+                }
             }
-        } else {
-            Err(Status::internal("Unable to find AstarteNode"))
-        }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
 
@@ -237,12 +561,15 @@ mod test {
 
     use std::io::Error;
     use std::io::ErrorKind;
+    use std::time::Duration;
 
     use async_trait::async_trait;
     use mockall::mock;
     use tokio::sync::mpsc;
     use tokio::sync::mpsc::Receiver;
+    use tokio::sync::watch;
     use tonic::{Request, Status};
+    use uuid::Uuid;
 
     use crate::astarte_message_hub::AstarteNode;
     use crate::data::astarte::{AstartePublisher, AstarteRunner, AstarteSubscriber};
@@ -259,6 +586,8 @@ mod test {
         #[async_trait]
         impl AstarteRunner for AstarteHandler {
             async fn run(&mut self);
+
+            fn watch_connection(&self) -> watch::Receiver<proto_message_hub::ConnectionEvent>;
         }
 
         #[async_trait]
@@ -325,6 +654,53 @@ mod test {
         }
         "#;
 
+    const DEVICE_DATASTREAM_IFACE: &str = r#"
+        {
+            "interface_name": "io.demo.Values",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "datastream",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/test",
+                    "type": "integer"
+                }
+            ]
+        }
+        "#;
+
+    const NODE_ID: &str = "550e8400-e29b-41d4-a716-446655440000";
+
+    /// Attach a node declaring the device datastream and the server properties interfaces.
+    async fn attach_node(astarte_message_hub: &AstarteMessageHub<MockAstarteHandler>) {
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::Node;
+
+        let node_introspection = Node {
+            uuid: NODE_ID.to_owned(),
+            interface_jsons: vec![
+                DEVICE_DATASTREAM_IFACE.to_string().into_bytes(),
+                SERV_PROPS_IFACE.to_string().into_bytes(),
+            ],
+        };
+
+        astarte_message_hub
+            .attach(Request::new(node_introspection))
+            .await
+            .unwrap();
+    }
+
+    fn send_request(
+        astarte_message: proto_message_hub::AstarteMessage,
+    ) -> Request<proto_message_hub::AstarteMessage> {
+        let mut request = Request::new(astarte_message);
+        request
+            .metadata_mut()
+            .insert(crate::auth::NODE_ID_METADATA, NODE_ID.parse().unwrap());
+        request
+    }
+
     #[tokio::test]
     async fn attach_success_node() {
         use crate::proto_message_hub::message_hub_server::MessageHub;
@@ -420,12 +796,91 @@ mod test {
         )
     }
 
+    #[tokio::test]
+    async fn attach_reject_other_node_identity() {
+        use crate::auth::NodeIdentity;
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::Node;
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte
+            .expect_clone()
+            .returning(MockAstarteHandler::new);
+
+        let astarte_message: AstarteMessageHub<MockAstarteHandler> =
+            AstarteMessageHub::new(mock_astarte);
+
+        let node_introspection = Node {
+            uuid: "550e8400-e29b-41d4-a716-446655440000".to_owned(),
+            interface_jsons: vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        };
+
+        let mut req_node = Request::new(node_introspection.clone());
+        req_node
+            .extensions_mut()
+            .insert(NodeIdentity(uuid::Uuid::new_v4()));
+        let attach_result = astarte_message.attach(req_node).await;
+
+        assert_eq!(
+            attach_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        let mut req_node = Request::new(node_introspection);
+        req_node
+            .extensions_mut()
+            .insert(NodeIdentity(uuid::Uuid::new_v4()));
+        let detach_result = astarte_message.detach(req_node).await;
+
+        assert_eq!(
+            detach_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn send_message_reject_unattached_identity() {
+        use crate::auth::NodeIdentity;
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte
+            .expect_clone()
+            .returning(MockAstarteHandler::new);
+
+        let astarte_message_hub: AstarteMessageHub<MockAstarteHandler> =
+            AstarteMessageHub::new(mock_astarte);
+
+        let astarte_message = proto_message_hub::AstarteMessage {
+            interface_name: "io.demo.Values".to_owned(),
+            path: "/test".to_string(),
+            payload: Some(Payload::AstarteData(5.into())),
+            timestamp: None,
+        };
+
+        let mut req_astarte_message = Request::new(astarte_message);
+        req_astarte_message
+            .extensions_mut()
+            .insert(NodeIdentity(uuid::Uuid::new_v4()));
+        let send_result = astarte_message_hub.send(req_astarte_message).await;
+
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
     #[tokio::test]
     async fn send_message_success() {
         use crate::proto_message_hub::astarte_message::Payload;
         use crate::proto_message_hub::message_hub_server::MessageHub;
 
         let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte.expect_subscribe().returning(|_| {
+            let (_, rx) = mpsc::channel(2);
+            Ok(rx)
+        });
         mock_astarte.expect_publish().returning(|_| Ok(()));
         mock_astarte
             .expect_clone()
@@ -434,6 +889,8 @@ mod test {
         let astarte_message_hub: AstarteMessageHub<MockAstarteHandler> =
             AstarteMessageHub::new(mock_astarte);
 
+        attach_node(&astarte_message_hub).await;
+
         let interface_name = "io.demo.Values".to_owned();
 
         let astarte_message = proto_message_hub::AstarteMessage {
@@ -443,18 +900,98 @@ mod test {
             timestamp: None,
         };
 
-        let req_astarte_message = Request::new(astarte_message);
+        let req_astarte_message = send_request(astarte_message);
         let send_result = astarte_message_hub.send(req_astarte_message).await;
 
         assert!(send_result.is_ok())
     }
 
+    #[tokio::test]
+    async fn send_message_reject_not_allowed() {
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte.expect_subscribe().returning(|_| {
+            let (_, rx) = mpsc::channel(2);
+            Ok(rx)
+        });
+        mock_astarte.expect_publish().never();
+        mock_astarte
+            .expect_clone()
+            .returning(MockAstarteHandler::new);
+
+        let astarte_message_hub: AstarteMessageHub<MockAstarteHandler> =
+            AstarteMessageHub::new(mock_astarte);
+
+        let message = |interface_name: &str, path: &str| proto_message_hub::AstarteMessage {
+            interface_name: interface_name.to_string(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteData(5.into())),
+            timestamp: None,
+        };
+
+        // Node not attached
+        let send_result = astarte_message_hub
+            .send(send_request(message("io.demo.Values", "/test")))
+            .await;
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        attach_node(&astarte_message_hub).await;
+
+        // Missing node UUID
+        let send_result = astarte_message_hub
+            .send(Request::new(message("io.demo.Values", "/test")))
+            .await;
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        // Interface not in the node introspection
+        let send_result = astarte_message_hub
+            .send(send_request(message("io.demo.Other", "/test")))
+            .await;
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        // Server owned interface
+        let send_result = astarte_message_hub
+            .send(send_request(message(
+                "org.astarte-platform.test.test",
+                "/button",
+            )))
+            .await;
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        // Path without mapping
+        let send_result = astarte_message_hub
+            .send(send_request(message("io.demo.Values", "/other")))
+            .await;
+        assert_eq!(
+            send_result.err().unwrap().code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+
     #[tokio::test]
     async fn send_message_reject() {
         use crate::proto_message_hub::astarte_message::Payload;
         use crate::proto_message_hub::message_hub_server::MessageHub;
 
         let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte.expect_subscribe().returning(|_| {
+            let (_, rx) = mpsc::channel(2);
+            Ok(rx)
+        });
         mock_astarte.expect_publish().returning(|_| {
             Err(AstarteMessageHubError::IOError(Error::new(
                 ErrorKind::InvalidData,
@@ -468,6 +1005,8 @@ mod test {
         let astarte_message_hub: AstarteMessageHub<MockAstarteHandler> =
             AstarteMessageHub::new(mock_astarte);
 
+        attach_node(&astarte_message_hub).await;
+
         let interface_name = "io.demo.Values".to_owned();
 
         let value: i32 = 5;
@@ -478,7 +1017,7 @@ mod test {
             timestamp: None,
         };
 
-        let req_astarte_message = Request::new(astarte_message);
+        let req_astarte_message = send_request(astarte_message);
         let send_result = astarte_message_hub.send(req_astarte_message).await;
 
         assert!(send_result.is_err());
@@ -623,4 +1162,216 @@ mod test {
             err.message()
         )
     }
+
+    /// Mock handler whose clones notify the unsubscribed nodes.
+    fn mock_notify_unsubscribe(tx: mpsc::UnboundedSender<Uuid>) -> MockAstarteHandler {
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte.expect_subscribe().returning(|_| {
+            let (_, rx) = mpsc::channel(2);
+            Ok(rx)
+        });
+        mock_astarte.expect_clone().returning(move || {
+            let tx = tx.clone();
+            let mut mock_astarte = MockAstarteHandler::new();
+            mock_astarte.expect_unsubscribe().returning(move |node| {
+                let _ = tx.send(node.id);
+                Ok(())
+            });
+            mock_astarte
+        });
+
+        mock_astarte
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_detaches_node() {
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::Node;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let astarte_message = AstarteMessageHub::new(mock_notify_unsubscribe(tx))
+            .with_detach_grace_period(Duration::from_millis(10));
+
+        let node_introspection = Node {
+            uuid: "550e8400-e29b-41d4-a716-446655440000".to_owned(),
+            interface_jsons: vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        };
+
+        let stream = astarte_message
+            .attach(Request::new(node_introspection))
+            .await
+            .unwrap()
+            .into_inner();
+        drop(stream);
+
+        let id = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            id,
+            Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap()
+        );
+        assert!(astarte_message.nodes.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reattach_within_grace_period_keeps_node() {
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::Node;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let astarte_message = AstarteMessageHub::new(mock_notify_unsubscribe(tx))
+            .with_detach_grace_period(Duration::from_millis(10));
+
+        let node_introspection = Node {
+            uuid: "550e8400-e29b-41d4-a716-446655440000".to_owned(),
+            interface_jsons: vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        };
+
+        let stream = astarte_message
+            .attach(Request::new(node_introspection.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        let _new_stream = astarte_message
+            .attach(Request::new(node_introspection))
+            .await
+            .unwrap()
+            .into_inner();
+        drop(stream);
+
+        let res = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+
+        assert!(res.is_err());
+        assert_eq!(astarte_message.nodes.read().await.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn grace_period_expired_during_reattach_keeps_node() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::Node;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut mock_astarte = MockAstarteHandler::new();
+
+        // The second subscription lasts longer than the grace period of the first session
+        let calls = AtomicUsize::new(0);
+        mock_astarte.expect_subscribe().returning(move |_| {
+            if calls.fetch_add(1, Ordering::SeqCst) == 1 {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            let (_, rx) = mpsc::channel(2);
+            Ok(rx)
+        });
+        mock_astarte.expect_clone().returning(move || {
+            let tx = tx.clone();
+            let mut mock_astarte = MockAstarteHandler::new();
+            mock_astarte.expect_unsubscribe().returning(move |node| {
+                let _ = tx.send(node.id);
+                Ok(())
+            });
+            mock_astarte
+        });
+
+        let astarte_message = AstarteMessageHub::new(mock_astarte)
+            .with_detach_grace_period(Duration::from_millis(10));
+
+        let node_introspection = Node {
+            uuid: "550e8400-e29b-41d4-a716-446655440000".to_owned(),
+            interface_jsons: vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        };
+
+        let stream = astarte_message
+            .attach(Request::new(node_introspection.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        drop(stream);
+
+        let _new_stream = astarte_message
+            .attach(Request::new(node_introspection))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let res = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+
+        assert!(res.is_err());
+        let nodes = astarte_message.nodes.read().await;
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes.values().next().unwrap().session, 1);
+    }
+
+    #[tokio::test]
+    async fn watch_connection_streams_state_changes() {
+        use tokio_stream::StreamExt;
+
+        use crate::proto_message_hub::message_hub_server::MessageHub;
+        use crate::proto_message_hub::{ConnectionEvent, ConnectionState};
+
+        let (connection_tx, connection_rx) = watch::channel(ConnectionEvent {
+            state: ConnectionState::Connected.into(),
+            reason: String::new(),
+            timestamp: None,
+        });
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        mock_astarte
+            .expect_watch_connection()
+            .returning(move || connection_rx.clone());
+        mock_astarte
+            .expect_clone()
+            .returning(MockAstarteHandler::new);
+
+        let astarte_message = AstarteMessageHub::new(mock_astarte);
+
+        let mut stream = astarte_message
+            .watch_connection(Request::new(pbjson_types::Empty {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.state(), ConnectionState::Connected);
+
+        connection_tx.send_replace(ConnectionEvent {
+            state: ConnectionState::Disconnected.into(),
+            reason: "connection lost".to_string(),
+            timestamp: None,
+        });
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.state(), ConnectionState::Disconnected);
+        assert_eq!(event.reason, "connection lost");
+    }
+
+    #[tokio::test]
+    async fn drop_stops_the_tasks() {
+        let handlers = std::sync::Arc::new(());
+
+        let mut mock_astarte = MockAstarteHandler::new();
+        let outer = handlers.clone();
+        mock_astarte.expect_clone().returning(move || {
+            let inner = outer.clone();
+            let mut handler = MockAstarteHandler::new();
+            handler.expect_clone().returning(move || {
+                let _ = &inner;
+                MockAstarteHandler::new()
+            });
+            handler
+        });
+
+        let astarte_message = AstarteMessageHub::new(mock_astarte);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(std::sync::Arc::strong_count(&handlers) > 1);
+
+        drop(astarte_message);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(std::sync::Arc::strong_count(&handlers), 1);
+    }
 }
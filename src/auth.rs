@@ -0,0 +1,500 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Authentication of the nodes connecting to the Astarte message hub.
+//!
+//! Each node is identified by a pre-shared token, sent in the `authorization` metadata of every
+//! request as `Bearer <token>`. The [NodeAuthenticator] interceptor resolves the token to the UUID
+//! of the node and stores it as a [NodeIdentity] in the request extensions, the message hub then
+//! checks that the node UUID used in the request matches the authenticated one.
+//!
+//! The administration API is protected separately by the [AdminAuthenticator], accepting only the
+//! admin tokens.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use ring::constant_time;
+use ring::digest::{digest, SHA256};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+use uuid::Uuid;
+
+use crate::config::{AdminOptions, AuthOptions};
+use crate::error::AstarteMessageHubError;
+
+/// Metadata key containing the token of the node.
+pub const AUTHORIZATION_METADATA: &str = "authorization";
+
+/// Metadata key containing the UUID of the node sending a message.
+pub const NODE_ID_METADATA: &str = "node-id";
+
+/// Default directory, inside the store directory, containing the tokens of the nodes.
+const TOKENS_DIRECTORY: &str = "node_tokens";
+
+/// Identity of an authenticated node, added to the extensions of the authenticated requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeIdentity(pub Uuid);
+
+/// Interceptor authenticating the requests of the nodes with a pre-shared token.
+///
+/// When disabled all the requests are let through without an identity.
+#[derive(Debug, Clone, Default)]
+pub struct NodeAuthenticator {
+    tokens: Option<Arc<HashMap<String, Uuid>>>,
+}
+
+impl NodeAuthenticator {
+    /// Create an authenticator letting through all the requests.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Create an authenticator accepting the given tokens for each node.
+    pub fn new<I>(tokens: I) -> Result<Self, AstarteMessageHubError>
+    where
+        I: IntoIterator<Item = (Uuid, String)>,
+    {
+        let mut map = HashMap::new();
+
+        for (id, token) in tokens {
+            if token.is_empty() {
+                return Err(AstarteMessageHubError::AstarteInvalidData(format!(
+                    "empty token for node {}",
+                    id
+                )));
+            }
+
+            if let Some(other) = map.insert(token, id) {
+                if other != id {
+                    return Err(AstarteMessageHubError::AstarteInvalidData(format!(
+                        "nodes {} and {} share the same token",
+                        other, id
+                    )));
+                }
+            }
+        }
+
+        Ok(Self {
+            tokens: Some(Arc::new(map)),
+        })
+    }
+
+    /// Create the authenticator from the configuration.
+    ///
+    /// The tokens in the configuration are merged with the ones in the tokens directory, where
+    /// each file is named after the UUID of a node and contains its token. A relative tokens
+    /// directory is resolved from the store directory.
+    pub fn from_options(
+        options: &AuthOptions,
+        store_directory: &Path,
+    ) -> Result<Self, AstarteMessageHubError> {
+        if !options.enabled {
+            return Ok(Self::disabled());
+        }
+
+        let mut tokens = options
+            .tokens
+            .iter()
+            .map(|(id, token)| {
+                let id = Uuid::parse_str(id).map_err(|err| {
+                    AstarteMessageHubError::AstarteInvalidData(format!(
+                        "invalid node UUID {}: {}",
+                        id, err
+                    ))
+                })?;
+
+                Ok((id, token.clone()))
+            })
+            .collect::<Result<Vec<_>, AstarteMessageHubError>>()?;
+
+        let tokens_directory = match &options.tokens_directory {
+            Some(dir) => store_directory.join(dir),
+            None => store_directory.join(TOKENS_DIRECTORY),
+        };
+
+        tokens.extend(read_tokens_directory(&tokens_directory)?);
+
+        Self::new(tokens)
+    }
+
+    /// Returns the node owning the token, [None] also when the authentication is disabled.
+    fn authenticate(&self, token: &str) -> Option<Uuid> {
+        let tokens = self.tokens.as_ref()?;
+
+        tokens.iter().fold(None, |found, (candidate, id)| {
+            if token_matches(candidate, token) {
+                Some(*id)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+impl Interceptor for NodeAuthenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if self.tokens.is_none() {
+            return Ok(request);
+        }
+
+        let token = request
+            .metadata()
+            .get(AUTHORIZATION_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing node token"))?;
+
+        let id = self
+            .authenticate(token.trim())
+            .ok_or_else(|| Status::unauthenticated("Invalid node token"))?;
+
+        request.extensions_mut().insert(NodeIdentity(id));
+
+        Ok(request)
+    }
+}
+
+/// Interceptor authenticating the requests to the administration API.
+///
+/// The requests must send one of the admin tokens in the `authorization` metadata as
+/// `Bearer <token>`, the same check is used for the `Authorization` header of the HTTP API.
+#[derive(Debug, Clone)]
+pub struct AdminAuthenticator {
+    tokens: Arc<Vec<String>>,
+}
+
+impl AdminAuthenticator {
+    /// Create an authenticator accepting the given tokens.
+    pub fn new(tokens: Vec<String>) -> Result<Self, AstarteMessageHubError> {
+        if tokens.is_empty() || tokens.iter().any(|token| token.is_empty()) {
+            return Err(AstarteMessageHubError::AstarteInvalidData(
+                "the admin api requires at least one non-empty token".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            tokens: Arc::new(tokens),
+        })
+    }
+
+    /// Create the authenticator from the configuration.
+    pub fn from_options(options: &AdminOptions) -> Result<Self, AstarteMessageHubError> {
+        Self::new(options.tokens.clone())
+    }
+
+    /// Check the value of the `authorization` metadata or header.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check(&self, authorization: Option<&str>) -> Result<(), Status> {
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing admin token"))?;
+
+        let valid = self.tokens.iter().fold(false, |valid, candidate| {
+            token_matches(candidate, token.trim()) | valid
+        });
+
+        if valid {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("Invalid admin token"))
+        }
+    }
+}
+
+impl Interceptor for AdminAuthenticator {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let authorization = request
+            .metadata()
+            .get(AUTHORIZATION_METADATA)
+            .and_then(|value| value.to_str().ok());
+
+        self.check(authorization)?;
+
+        Ok(request)
+    }
+}
+
+/// Check that the node UUID used in a request belongs to the authenticated node, if any.
+#[allow(clippy::result_large_err)]
+pub(crate) fn authorize<T>(request: &Request<T>, id: &Uuid) -> Result<(), Status> {
+    match request.extensions().get::<NodeIdentity>() {
+        Some(NodeIdentity(identity)) if identity != id => Err(Status::permission_denied(format!(
+            "Node {} is not authorized to act as {}",
+            identity, id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the UUID of the node sending the request.
+///
+/// The UUID is the authenticated identity of the node, if any, otherwise the one in the
+/// [NODE_ID_METADATA] metadata.
+#[allow(clippy::result_large_err)]
+pub(crate) fn node_id<T>(request: &Request<T>) -> Result<Uuid, Status> {
+    let metadata_id = request
+        .metadata()
+        .get(NODE_ID_METADATA)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| Uuid::parse_str(value).ok())
+                .ok_or_else(|| Status::invalid_argument("Unable to parse the node UUID"))
+        })
+        .transpose()?;
+
+    match (request.extensions().get::<NodeIdentity>(), metadata_id) {
+        (Some(NodeIdentity(identity)), Some(id)) if *identity != id => {
+            Err(Status::permission_denied(format!(
+                "Node {} is not authorized to act as {}",
+                identity, id
+            )))
+        }
+        (Some(NodeIdentity(identity)), _) => Ok(*identity),
+        (None, Some(id)) => Ok(id),
+        (None, None) => Err(Status::invalid_argument(format!(
+            "Missing the `{}` metadata, it must contain the UUID of the sending node",
+            NODE_ID_METADATA
+        ))),
+    }
+}
+
+/// Read the tokens of the nodes from the files in a directory, a missing directory has no tokens.
+fn read_tokens_directory(dir: &Path) -> Result<Vec<(Uuid, String)>, AstarteMessageHubError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!("no tokens directory found in {:?}", dir);
+
+            return Ok(Vec::new());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut tokens = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if !path.is_file() {
+            continue;
+        }
+
+        let id = match path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| Uuid::parse_str(name).ok())
+        {
+            Some(id) => id,
+            None => {
+                warn!("ignoring token file {:?}, the name is not a UUID", path);
+                continue;
+            }
+        };
+
+        let token = fs::read_to_string(&path)?.trim().to_string();
+
+        tokens.push((id, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Whether the token matches the candidate one.
+///
+/// The callers compare the token with every candidate, to avoid leaking which one matched through
+/// the timing. The digests of the tokens are compared in constant time, so the comparison does not
+/// leak their lengths either.
+fn token_matches(candidate: &str, token: &str) -> bool {
+    let candidate = digest(&SHA256, candidate.as_bytes());
+    let token = digest(&SHA256, token.as_bytes());
+
+    constant_time::verify_slices_are_equal(candidate.as_ref(), token.as_ref()).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const NODE_ID: &str = "550e8400-e29b-41d4-a716-446655440000";
+
+    fn with_token(token: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+
+        if let Some(token) = token {
+            request.metadata_mut().insert(
+                AUTHORIZATION_METADATA,
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+
+        request
+    }
+
+    fn request_without_bearer() -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_METADATA, "secret".parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn disabled_authenticator_lets_through() {
+        let mut authenticator = NodeAuthenticator::disabled();
+
+        let request = authenticator.call(with_token(None)).unwrap();
+        assert!(request.extensions().get::<NodeIdentity>().is_none());
+    }
+
+    #[test]
+    fn authenticate_node_token() {
+        let id = Uuid::parse_str(NODE_ID).unwrap();
+        let mut authenticator = NodeAuthenticator::new([(id, "secret".to_string())]).unwrap();
+
+        let request = authenticator.call(with_token(Some("secret"))).unwrap();
+        assert_eq!(
+            request.extensions().get::<NodeIdentity>(),
+            Some(&NodeIdentity(id))
+        );
+        assert!(authorize(&request, &id).is_ok());
+
+        let other = Uuid::new_v4();
+        let status = authorize(&request, &other).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = authenticator.call(request_without_bearer()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = authenticator.call(with_token(None)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // Tokens of a different length are compared through their digests
+        for token in ["wrong", "secre", "secrets"] {
+            let status = authenticator.call(with_token(Some(token))).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn node_id_from_identity_or_metadata() {
+        let id = Uuid::parse_str(NODE_ID).unwrap();
+
+        let status = node_id(&Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains(NODE_ID_METADATA));
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(NODE_ID_METADATA, NODE_ID.parse().unwrap());
+        assert_eq!(node_id(&request).unwrap(), id);
+
+        request.extensions_mut().insert(NodeIdentity(id));
+        assert_eq!(node_id(&request).unwrap(), id);
+
+        request
+            .extensions_mut()
+            .insert(NodeIdentity(Uuid::new_v4()));
+        let status = node_id(&request).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(NODE_ID_METADATA, "a1".parse().unwrap());
+        let status = node_id(&request).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn authenticate_admin_token() {
+        let mut authenticator =
+            AdminAuthenticator::new(vec!["first".to_string(), "second".to_string()]).unwrap();
+
+        assert!(authenticator.call(with_token(Some("first"))).is_ok());
+        assert!(authenticator.call(with_token(Some("second"))).is_ok());
+
+        let status = authenticator.call(with_token(Some("wrong"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = authenticator.call(with_token(None)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = authenticator.call(request_without_bearer()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        assert!(AdminAuthenticator::new(Vec::new()).is_err());
+        assert!(AdminAuthenticator::new(vec![String::new()]).is_err());
+    }
+
+    #[test]
+    fn shared_tokens_are_rejected() {
+        let res = NodeAuthenticator::new([
+            (Uuid::new_v4(), "secret".to_string()),
+            (Uuid::new_v4(), "secret".to_string()),
+        ]);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn tokens_from_options_and_directory() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let tokens_dir = dir.path().join(TOKENS_DIRECTORY);
+        fs::create_dir(&tokens_dir).unwrap();
+
+        let dir_id = Uuid::new_v4();
+        fs::write(tokens_dir.join(dir_id.to_string()), "from-directory\n").unwrap();
+        fs::write(tokens_dir.join("not-a-uuid"), "ignored").unwrap();
+
+        let options = AuthOptions {
+            enabled: true,
+            tokens_directory: None,
+            tokens: BTreeMap::from([(NODE_ID.to_string(), "from-config".to_string())]),
+        };
+
+        let mut authenticator = NodeAuthenticator::from_options(&options, dir.path()).unwrap();
+
+        let request_config = authenticator.call(with_token(Some("from-config"))).unwrap();
+        assert_eq!(
+            request_config.extensions().get::<NodeIdentity>(),
+            Some(&NodeIdentity(Uuid::parse_str(NODE_ID).unwrap()))
+        );
+
+        let request_dir = authenticator
+            .call(with_token(Some("from-directory")))
+            .unwrap();
+        assert_eq!(
+            request_dir.extensions().get::<NodeIdentity>(),
+            Some(&NodeIdentity(dir_id))
+        );
+
+        assert!(authenticator.call(with_token(Some("ignored"))).is_err());
+    }
+}
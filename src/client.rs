@@ -0,0 +1,575 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Client of the message hub, used by a node to send data to Astarte and receive the server data.
+//!
+//! Available with the `client` feature.
+//!
+//! ```no_run
+//! use astarte_device_sdk::types::AstarteType;
+//! use astarte_message_hub::client::MessageHubNode;
+//! use tokio_stream::StreamExt;
+//! use uuid::Uuid;
+//!
+//! # async fn run() -> Result<(), astarte_message_hub::error::AstarteMessageHubError> {
+//! let interfaces = [r#"{"interface_name": "org.example.Sensor", ...}"#];
+//! let uuid = Uuid::parse_str("c5d3b7a6-0a77-4dbb-b3a9-0e4b5f4d6e43").unwrap();
+//!
+//! let (node, mut events) = MessageHubNode::connect("http://[::1]:50051", uuid, &interfaces).await?;
+//!
+//! node.send_individual("org.example.Sensor", "/temperature", 21.5).await?;
+//!
+//! while let Some(event) = events.next().await {
+//!     println!("received {:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use astarte_device_sdk::types::AstarteType;
+use astarte_device_sdk::{AstarteAggregate, AstarteDeviceDataEvent};
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use tokio_stream::Stream;
+use tonic::codec::Streaming;
+use tonic::codegen::{InterceptedService, StdError};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+use uuid::Uuid;
+
+use crate::auth::{AUTHORIZATION_METADATA, NODE_ID_METADATA};
+use crate::error::AstarteMessageHubError;
+use crate::proto_message_hub::astarte_message::Payload;
+use crate::proto_message_hub::message_hub_client::MessageHubClient;
+use crate::proto_message_hub::{AstarteMessage, Node};
+
+/// Adds the identity of the node to its requests.
+#[derive(Debug, Clone)]
+pub struct NodeInterceptor {
+    node_id: MetadataValue<Ascii>,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for NodeInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let metadata = request.metadata_mut();
+
+        metadata.insert(NODE_ID_METADATA, self.node_id.clone());
+        if let Some(authorization) = &self.authorization {
+            metadata.insert(AUTHORIZATION_METADATA, authorization.clone());
+        }
+
+        Ok(request)
+    }
+}
+
+type NodeClient = MessageHubClient<InterceptedService<Channel, NodeInterceptor>>;
+
+/// Node attached to the message hub.
+///
+/// The node is detached when dropped, unless it was already detached with
+/// [`detach`](MessageHubNode::detach).
+#[derive(Debug)]
+pub struct MessageHubNode {
+    client: NodeClient,
+    node: Node,
+    detached: bool,
+}
+
+impl MessageHubNode {
+    /// Connect to the message hub at the endpoint and attach the node with its interfaces.
+    ///
+    /// Returns the node and the stream of the data sent by Astarte on the server owned
+    /// interfaces.
+    pub async fn connect<D, B>(
+        endpoint: D,
+        uuid: Uuid,
+        interfaces: &[B],
+    ) -> Result<(Self, NodeEvents), AstarteMessageHubError>
+    where
+        D: TryInto<Endpoint>,
+        D::Error: Into<StdError>,
+        B: Clone + Into<Vec<u8>>,
+    {
+        let channel = Endpoint::new(endpoint)?.connect().await?;
+
+        Self::with_channel(channel, uuid, interfaces, None).await
+    }
+
+    /// Attach the node through a connected channel, e.g. to a Unix domain socket.
+    ///
+    /// The token authenticates the node when the message hub requires it.
+    pub async fn with_channel<B>(
+        channel: Channel,
+        uuid: Uuid,
+        interfaces: &[B],
+        token: Option<&str>,
+    ) -> Result<(Self, NodeEvents), AstarteMessageHubError>
+    where
+        B: Clone + Into<Vec<u8>>,
+    {
+        let node_id = uuid
+            .to_string()
+            .parse()
+            .map_err(|_| AstarteMessageHubError::FatalError(format!("invalid node id {}", uuid)))?;
+        let authorization = token
+            .map(|token| format!("Bearer {}", token).parse())
+            .transpose()
+            .map_err(|_| AstarteMessageHubError::FatalError("invalid node token".to_string()))?;
+
+        let interceptor = NodeInterceptor {
+            node_id,
+            authorization,
+        };
+        let mut client = MessageHubClient::with_interceptor(channel, interceptor);
+
+        let node = Node::new(uuid, interfaces);
+        let stream = client.attach(node.clone()).await?.into_inner();
+
+        debug!("node {} attached", node.uuid);
+
+        let node = Self {
+            client,
+            node,
+            detached: false,
+        };
+
+        Ok((node, NodeEvents { stream }))
+    }
+
+    /// UUID of the node.
+    pub fn uuid(&self) -> &str {
+        &self.node.uuid
+    }
+
+    /// Send an individual value on an interface path.
+    pub async fn send_individual<D>(
+        &self,
+        interface: &str,
+        path: &str,
+        data: D,
+    ) -> Result<(), AstarteMessageHubError>
+    where
+        D: TryInto<AstarteType>,
+        D::Error: Into<AstarteMessageHubError>,
+    {
+        let payload = individual_payload(data)?;
+
+        self.send_payload(interface, path, payload, None).await
+    }
+
+    /// Send an individual value on an interface path, with an explicit timestamp.
+    pub async fn send_individual_with_timestamp<D>(
+        &self,
+        interface: &str,
+        path: &str,
+        data: D,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteMessageHubError>
+    where
+        D: TryInto<AstarteType>,
+        D::Error: Into<AstarteMessageHubError>,
+    {
+        let payload = individual_payload(data)?;
+
+        self.send_payload(interface, path, payload, Some(timestamp))
+            .await
+    }
+
+    /// Send an object on an interface path.
+    pub async fn send_object<T>(
+        &self,
+        interface: &str,
+        path: &str,
+        data: T,
+    ) -> Result<(), AstarteMessageHubError>
+    where
+        T: AstarteAggregate,
+    {
+        let payload = Payload::try_from(data.astarte_aggregate()?)?;
+
+        self.send_payload(interface, path, payload, None).await
+    }
+
+    /// Send an object on an interface path, with an explicit timestamp.
+    pub async fn send_object_with_timestamp<T>(
+        &self,
+        interface: &str,
+        path: &str,
+        data: T,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AstarteMessageHubError>
+    where
+        T: AstarteAggregate,
+    {
+        let payload = Payload::try_from(data.astarte_aggregate()?)?;
+
+        self.send_payload(interface, path, payload, Some(timestamp))
+            .await
+    }
+
+    /// Unset a property.
+    pub async fn unset(&self, interface: &str, path: &str) -> Result<(), AstarteMessageHubError> {
+        let payload = Payload::try_from(AstarteType::Unset)?;
+
+        self.send_payload(interface, path, payload, None).await
+    }
+
+    async fn send_payload(
+        &self,
+        interface: &str,
+        path: &str,
+        payload: Payload,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<(), AstarteMessageHubError> {
+        let message = AstarteMessage {
+            interface_name: interface.to_string(),
+            path: path.to_string(),
+            payload: Some(payload),
+            timestamp: timestamp.map(Into::into),
+        };
+
+        // The client is a handle to the shared channel
+        self.client.clone().send(message).await?;
+
+        Ok(())
+    }
+
+    /// Detach the node from the message hub, ending its stream of events.
+    pub async fn detach(mut self) -> Result<(), AstarteMessageHubError> {
+        self.detached = true;
+        self.client.detach(self.node.clone()).await?;
+
+        debug!("node {} detached", self.node.uuid);
+
+        Ok(())
+    }
+}
+
+impl Drop for MessageHubNode {
+    fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
+
+        // The detach can only be sent from within a runtime
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => {
+                warn!("node {} dropped outside of a runtime", self.node.uuid);
+                return;
+            }
+        };
+
+        let mut client = self.client.clone();
+        let node = self.node.clone();
+        handle.spawn(async move {
+            if let Err(err) = client.detach(node.clone()).await {
+                warn!("unable to detach node {}: {}", node.uuid, err);
+            }
+        });
+    }
+}
+
+fn individual_payload<D>(data: D) -> Result<Payload, AstarteMessageHubError>
+where
+    D: TryInto<AstarteType>,
+    D::Error: Into<AstarteMessageHubError>,
+{
+    let data: AstarteType = data.try_into().map_err(Into::into)?;
+
+    Payload::try_from(data)
+}
+
+/// Stream of the data sent by Astarte to the node, ending when the node is detached.
+///
+/// An unset property is received as an individual [`AstarteType::Unset`].
+#[derive(Debug)]
+pub struct NodeEvents {
+    stream: Streaming<AstarteMessage>,
+}
+
+impl Stream for NodeEvents {
+    type Item = Result<AstarteDeviceDataEvent, AstarteMessageHubError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx).map(|message| {
+            message.map(|message| {
+                message
+                    .map_err(AstarteMessageHubError::from)
+                    .and_then(AstarteDeviceDataEvent::try_from)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::sync::Mutex;
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tokio_stream::StreamExt;
+    use tonic::transport::Server;
+    use tonic::Response;
+
+    use super::*;
+    use crate::proto_message_hub::message_hub_server::{MessageHub, MessageHubServer};
+    use crate::proto_message_hub::ConnectionEvent;
+
+    const INTERFACE: &str = "org.astarte-platform.test.Test";
+    const UUID: &str = "a2f8c4b8-6e3f-4b2e-9b5c-1d2e3f4a5b6c";
+
+    #[derive(Debug)]
+    enum Call {
+        Attach(Node),
+        Send(AstarteMessage),
+        Detach(Node),
+    }
+
+    /// Message hub recording the calls, with the node id and the authorization of each one.
+    struct MockMessageHub {
+        calls: mpsc::UnboundedSender<(Call, String, Option<String>)>,
+        events: Mutex<Option<mpsc::Receiver<Result<AstarteMessage, Status>>>>,
+    }
+
+    impl MockMessageHub {
+        fn record<T>(&self, request: &Request<T>, call: Call) {
+            let metadata = |key| {
+                request
+                    .metadata()
+                    .get(key)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+
+            let node_id = metadata(NODE_ID_METADATA).unwrap_or_default();
+            let _ = self
+                .calls
+                .send((call, node_id, metadata(AUTHORIZATION_METADATA)));
+        }
+    }
+
+    #[tonic::async_trait]
+    impl MessageHub for MockMessageHub {
+        type AttachStream = ReceiverStream<Result<AstarteMessage, Status>>;
+        type WatchConnectionStream = ReceiverStream<Result<ConnectionEvent, Status>>;
+
+        async fn attach(
+            &self,
+            request: Request<Node>,
+        ) -> Result<Response<Self::AttachStream>, Status> {
+            self.record(&request, Call::Attach(request.get_ref().clone()));
+
+            let events = self.events.lock().await.take().unwrap();
+
+            Ok(Response::new(ReceiverStream::new(events)))
+        }
+
+        async fn send(
+            &self,
+            request: Request<AstarteMessage>,
+        ) -> Result<Response<pbjson_types::Empty>, Status> {
+            self.record(&request, Call::Send(request.get_ref().clone()));
+
+            Ok(Response::new(pbjson_types::Empty {}))
+        }
+
+        async fn detach(
+            &self,
+            request: Request<Node>,
+        ) -> Result<Response<pbjson_types::Empty>, Status> {
+            self.record(&request, Call::Detach(request.get_ref().clone()));
+
+            Ok(Response::new(pbjson_types::Empty {}))
+        }
+
+        async fn watch_connection(
+            &self,
+            _request: Request<pbjson_types::Empty>,
+        ) -> Result<Response<Self::WatchConnectionStream>, Status> {
+            Err(Status::unimplemented("watch connection"))
+        }
+    }
+
+    type Calls = mpsc::UnboundedReceiver<(Call, String, Option<String>)>;
+
+    /// Serve the mocked message hub, returning its address, the recorded calls and the sender of
+    /// the events.
+    async fn serve_mock() -> (String, Calls, mpsc::Sender<Result<AstarteMessage, Status>>) {
+        let (calls_tx, calls) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::channel(8);
+
+        let hub = MockMessageHub {
+            calls: calls_tx,
+            events: Mutex::new(Some(events)),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(MessageHubServer::new(hub))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        (format!("http://{}", address), calls, events_tx)
+    }
+
+    async fn next_call(calls: &mut Calls) -> (Call, String, Option<String>) {
+        tokio::time::timeout(Duration::from_secs(5), calls.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn individual(message: &AstarteMessage) -> AstarteType {
+        message
+            .data()
+            .and_then(|data| data.individual())
+            .and_then(|individual| individual.individual_data.clone())
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_and_receive() {
+        let (address, mut calls, events_tx) = serve_mock().await;
+        let uuid = Uuid::parse_str(UUID).unwrap();
+
+        let (node, mut events) = MessageHubNode::connect(address, uuid, &["{}"])
+            .await
+            .unwrap();
+        assert_eq!(node.uuid(), UUID);
+
+        match next_call(&mut calls).await {
+            (Call::Attach(attached), node_id, None) => {
+                assert_eq!(attached.uuid, UUID);
+                assert_eq!(attached.interface_jsons, vec![b"{}".to_vec()]);
+                assert_eq!(node_id, UUID);
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+
+        node.send_individual(INTERFACE, "/integer", 42)
+            .await
+            .unwrap();
+        match next_call(&mut calls).await {
+            (Call::Send(message), node_id, None) => {
+                assert_eq!(node_id, UUID);
+                assert_eq!(message.interface_name, INTERFACE);
+                assert_eq!(message.path, "/integer");
+                assert!(message.timestamp.is_none());
+                assert_eq!(individual(&message), AstarteType::Integer(42));
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+
+        let timestamp = Utc::now();
+        let object = HashMap::from([("double".to_string(), AstarteType::Double(1.5))]);
+        node.send_object_with_timestamp(INTERFACE, "/object", object, timestamp)
+            .await
+            .unwrap();
+        match next_call(&mut calls).await {
+            (Call::Send(message), _, _) => {
+                assert_eq!(message.timestamp, Some(timestamp.into()));
+
+                let object = message.take_data().and_then(|data| data.take_object());
+                let object = crate::types::map_values_to_astarte_type(object.unwrap().object_data);
+                assert_eq!(object.unwrap()["double"], AstarteType::Double(1.5));
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+
+        assert!(node
+            .send_individual(INTERFACE, "/double", f64::NAN)
+            .await
+            .is_err());
+
+        node.unset(INTERFACE, "/property").await.unwrap();
+        match next_call(&mut calls).await {
+            (Call::Send(message), _, _) => assert!(message.unset().is_some()),
+            call => panic!("unexpected call {:?}", call),
+        }
+
+        // The server data is converted to the Astarte types
+        let event = AstarteMessage {
+            interface_name: INTERFACE.to_string(),
+            path: "/server".to_string(),
+            payload: Some(AstarteType::String("value".to_string()).try_into().unwrap()),
+            timestamp: None,
+        };
+        events_tx.send(Ok(event)).await.unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.interface, INTERFACE);
+        assert_eq!(event.path, "/server");
+        assert!(matches!(
+            event.data,
+            astarte_device_sdk::Aggregation::Individual(AstarteType::String(value)) if value == "value"
+        ));
+
+        // Dropping the node detaches it
+        drop(node);
+        match next_call(&mut calls).await {
+            (Call::Detach(detached), node_id, None) => {
+                assert_eq!(detached.uuid, UUID);
+                assert_eq!(node_id, UUID);
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticated_detach() {
+        let (address, mut calls, _events_tx) = serve_mock().await;
+        let uuid = Uuid::parse_str(UUID).unwrap();
+
+        let channel = Endpoint::new(address).unwrap().connect().await.unwrap();
+        let (node, _events) = MessageHubNode::with_channel(channel, uuid, &["{}"], Some("token"))
+            .await
+            .unwrap();
+
+        let (_, _, authorization) = next_call(&mut calls).await;
+        assert_eq!(authorization.as_deref(), Some("Bearer token"));
+
+        node.detach().await.unwrap();
+        match next_call(&mut calls).await {
+            (Call::Detach(_), _, authorization) => {
+                assert_eq!(authorization.as_deref(), Some("Bearer token"))
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+
+        // The node is detached only once
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(calls.try_recv().is_err());
+    }
+}
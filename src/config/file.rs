@@ -23,6 +23,10 @@
 use std::fs;
 use std::path::Path;
 
+use toml::value::Table;
+use toml::Value;
+
+use crate::config::overrides::ConfigOverrides;
 use crate::config::MessageHubOptions;
 use crate::error::AstarteMessageHubError;
 
@@ -45,7 +49,20 @@ pub fn get_options_from_base_toml() -> Result<MessageHubOptions, AstarteMessageH
 
 /// Get the message hub options from the toml file passed as input.
 pub fn get_options_from_toml(toml_str: &str) -> Result<MessageHubOptions, AstarteMessageHubError> {
-    toml::from_str::<MessageHubOptions>(toml_str)
+    get_options_with_overrides(toml_str, &ConfigOverrides::new())
+}
+
+/// Get the message hub options from the toml file passed as input, replacing the overridden
+/// options.
+pub fn get_options_with_overrides(
+    toml_str: &str,
+    overrides: &ConfigOverrides,
+) -> Result<MessageHubOptions, AstarteMessageHubError> {
+    let mut table = toml::from_str::<Table>(toml_str)?;
+    overrides.apply(&mut table)?;
+
+    Value::Table(table)
+        .try_into::<MessageHubOptions>()
         .map_err(AstarteMessageHubError::ConfigFileError)
         .and_then(|opt| {
             opt.validate().map_err(|err| {
@@ -58,13 +75,15 @@ pub fn get_options_from_toml(toml_str: &str) -> Result<MessageHubOptions, Astart
 
 #[cfg(test)]
 mod test {
+    use std::path::PathBuf;
+
     use super::*;
 
     #[test]
     fn test_read_options_from_toml_cred_secred_ok() {
         const TOML_FILE: &str = r#"
             realm = "1"
-            device_id = "2"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
             pairing_url = "3"
             credentials_secret = "4"
             astarte_ignore_ssl = false
@@ -74,19 +93,22 @@ mod test {
         let res = get_options_from_toml(TOML_FILE);
         let options = res.expect("Parsing of TOML file failed");
         assert_eq!(options.realm, "1");
-        assert_eq!(options.device_id, Some("2".to_string()));
+        assert_eq!(
+            options.device_id,
+            Some("2TBn-jNESuuHamE2Zo1anA".to_string())
+        );
         assert_eq!(options.pairing_url, "3");
         assert_eq!(options.credentials_secret, Some("4".to_string()));
         assert_eq!(options.pairing_token, None);
         assert!(!options.astarte_ignore_ssl);
-        assert_eq!(options.grpc_socket_port, 5);
+        assert_eq!(options.grpc_socket_port, Some(5));
     }
 
     #[test]
     fn test_read_options_from_toml_pairing_token_ok() {
         const TOML_FILE: &str = r#"
             realm = "1"
-            device_id = "2"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
             pairing_url = "3"
             pairing_token = "4"
             astarte_ignore_ssl = true
@@ -96,19 +118,22 @@ mod test {
         let res = get_options_from_toml(TOML_FILE);
         let options = res.expect("Parsing of TOML file failed");
         assert_eq!(options.realm, "1");
-        assert_eq!(options.device_id, Some("2".to_string()));
+        assert_eq!(
+            options.device_id,
+            Some("2TBn-jNESuuHamE2Zo1anA".to_string())
+        );
         assert_eq!(options.pairing_url, "3");
         assert_eq!(options.credentials_secret, None);
         assert_eq!(options.pairing_token, Some("4".to_string()));
         assert!(options.astarte_ignore_ssl);
-        assert_eq!(options.grpc_socket_port, 5);
+        assert_eq!(options.grpc_socket_port, Some(5));
     }
 
     #[test]
     fn test_read_options_from_toml_both_pairing_and_cred_sec_ok() {
         const TOML_FILE: &str = r#"
             realm = "1"
-            device_id = "2"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
             pairing_url = "3"
             credentials_secret = "4"
             pairing_token = "5"
@@ -119,19 +144,48 @@ mod test {
         let res = get_options_from_toml(TOML_FILE);
         let options = res.expect("Parsing of TOML file failed");
         assert_eq!(options.realm, "1");
-        assert_eq!(options.device_id, Some("2".to_string()));
+        assert_eq!(
+            options.device_id,
+            Some("2TBn-jNESuuHamE2Zo1anA".to_string())
+        );
         assert_eq!(options.pairing_url, "3");
         assert_eq!(options.credentials_secret, Some("4".to_string()));
         assert_eq!(options.pairing_token, Some("5".to_string()));
         assert!(options.astarte_ignore_ssl);
-        assert_eq!(options.grpc_socket_port, 6);
+        assert_eq!(options.grpc_socket_port, Some(6));
+    }
+
+    #[test]
+    fn test_read_options_from_toml_unix_socket_ok() {
+        const TOML_FILE: &str = r#"
+            realm = "1"
+            pairing_url = "2"
+            credentials_secret = "3"
+            grpc_unix_socket_path = "/run/message-hub/grpc.sock"
+            grpc_unix_socket_mode = 0o660
+            grpc_unix_socket_group = "message-hub"
+        "#;
+
+        let res = get_options_from_toml(TOML_FILE);
+        let options = res.expect("Parsing of TOML file failed");
+        assert_eq!(options.grpc_socket_port, None);
+        assert_eq!(
+            options.grpc_unix_socket_path,
+            Some(PathBuf::from("/run/message-hub/grpc.sock"))
+        );
+        assert_eq!(options.grpc_unix_socket_mode, Some(0o660));
+        assert_eq!(options.grpc_unix_socket_owner, None);
+        assert_eq!(
+            options.grpc_unix_socket_group,
+            Some("message-hub".to_string())
+        );
     }
 
     #[test]
     fn test_read_options_from_toml_missing_pairing_and_cred_sec_err() {
         const TOML_FILE: &str = r#"
             realm = "1"
-            device_id = "2"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
             pairing_url = "3"
             astarte_ignore_ssl = true
             grpc_socket_port = 4
@@ -144,7 +198,7 @@ mod test {
     #[test]
     fn test_read_options_from_toml_missing_realm_err() {
         const TOML_FILE: &str = r#"
-            device_id = "1"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
             pairing_url = "2"
             credentials_secret = "3"
             pairing_token = "4"
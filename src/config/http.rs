@@ -20,20 +20,25 @@
 
 //! Provides an HTTP API to set The Message Hub configurations
 
-use std::io::Write;
 use std::net::SocketAddr;
-use std::panic;
 use std::path::Path;
 use std::str::FromStr;
 
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Extension, Json, Router, Server};
+use hyper::server::accept::{self, Accept};
+use hyper::server::Builder;
+use log::error;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::wrappers::UnixListenerStream;
 
-use crate::config::MessageHubOptions;
+use crate::config::bind_unix_socket;
+use crate::config::provisioning::{options_from_json, ProvisionedFile, ProvisioningError};
+use crate::error::AstarteMessageHubError;
 
 #[derive(Deserialize, Serialize)]
 struct ConfigResponse {
@@ -44,7 +49,7 @@ struct ConfigResponse {
 #[derive(Clone)]
 struct ConfigServerExtension {
     configuration_ready_channel: Sender<()>,
-    toml_file: String,
+    toml_file: ProvisionedFile,
 }
 
 /// Provides an HTTP API to set The Message Hub configurations
@@ -52,16 +57,6 @@ pub struct HttpConfigProvider {
     shutdown_channel: Sender<()>,
 }
 
-#[derive(Deserialize)]
-struct ConfigPayload {
-    realm: String,
-    device_id: Option<String>,
-    credentials_secret: Option<String>,
-    pairing_url: String,
-    pairing_token: Option<String>,
-    grpc_socket_port: u16,
-}
-
 impl Default for ConfigResponse {
     fn default() -> Self {
         ConfigResponse {
@@ -71,73 +66,58 @@ impl Default for ConfigResponse {
     }
 }
 
+impl IntoResponse for ProvisioningError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ProvisioningError::Invalid(_) => StatusCode::BAD_REQUEST,
+            ProvisioningError::NotFound => StatusCode::NOT_FOUND,
+            ProvisioningError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let body = ConfigResponse {
+            result: "KO".to_string(),
+            message: Some(self.to_string()),
+        };
+
+        (status, Json(body)).into_response()
+    }
+}
+
 impl HttpConfigProvider {
     /// HTTP API endpoint that allows to set The Message Hub configurations
+    ///
+    /// The payload has the fields of the configuration file, the missing ones take their default
+    /// value.
     pub(self) async fn set_config(
         Extension(state): Extension<ConfigServerExtension>,
-        Json(payload): Json<ConfigPayload>,
-    ) -> impl IntoResponse {
-        let message_hub_options = MessageHubOptions {
-            realm: payload.realm,
-            device_id: payload.device_id,
-            credentials_secret: payload.credentials_secret,
-            pairing_url: payload.pairing_url,
-            pairing_token: payload.pairing_token,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: payload.grpc_socket_port,
-            store_directory: MessageHubOptions::default_store_directory(),
-        };
+        Json(payload): Json<serde_json::Value>,
+    ) -> Result<impl IntoResponse, ProvisioningError> {
+        let message_hub_options = state.toml_file.prepare(options_from_json(payload)?)?;
 
-        if let Err(err) = message_hub_options.validate() {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(ConfigResponse {
-                    result: "KO".to_string(),
-                    message: Some(format!("Invalid configuration: {}", err)),
-                }),
-            );
-        }
+        state.toml_file.write(&message_hub_options)?;
 
-        let result = std::fs::File::create(Path::new(&state.toml_file));
-        if result.is_err() {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ConfigResponse {
-                    result: "KO".to_string(),
-                    message: Some(format!("Unable to create file {}", state.toml_file)),
-                }),
-            );
-        }
+        let _ = state.configuration_ready_channel.send(()).await;
 
-        let mut file = result.unwrap();
-
-        let result = toml::to_string(&message_hub_options);
-        if result.is_err() {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ConfigResponse {
-                    result: "KO".to_string(),
-                    message: Some("Error in config serialization".to_string()),
-                }),
-            );
-        }
+        Ok((StatusCode::OK, Json(ConfigResponse::default())))
+    }
 
-        let cfg = result.unwrap();
+    /// HTTP API endpoint returning the provided configuration, with the secrets redacted
+    async fn get_config(
+        Extension(state): Extension<ConfigServerExtension>,
+    ) -> Result<impl IntoResponse, ProvisioningError> {
+        let message_hub_options = state.toml_file.read_redacted()?;
 
-        if write!(file, "{cfg}").is_err() {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ConfigResponse {
-                    result: "KO".to_string(),
-                    message: Some(format!("Unable to write in file {}", state.toml_file)),
-                }),
-            );
-        }
+        Ok((StatusCode::OK, Json(message_hub_options)))
+    }
 
-        let _ = state.configuration_ready_channel.send(()).await;
+    /// HTTP API endpoint validating a configuration without setting it
+    async fn validate_config(
+        Extension(state): Extension<ConfigServerExtension>,
+        Json(payload): Json<serde_json::Value>,
+    ) -> Result<impl IntoResponse, ProvisioningError> {
+        state.toml_file.prepare(options_from_json(payload)?)?;
 
-        (StatusCode::OK, Json(ConfigResponse::default()))
+        Ok((StatusCode::OK, Json(ConfigResponse::default())))
     }
 
     /// HTTP API endpoint that respond on a request done on the root (used for test purposes)
@@ -146,29 +126,69 @@ impl HttpConfigProvider {
     }
 
     /// Start a new HTTP API Server to allow a third party to feed the Message Hub configurations
+    ///
+    /// Returns an error if the address is not valid or cannot be bound.
     pub fn new(
         address: &str,
         configuration_ready_channel: Sender<()>,
         toml_file: &str,
-    ) -> HttpConfigProvider {
+    ) -> Result<HttpConfigProvider, AstarteMessageHubError> {
+        let addr = SocketAddr::from_str(address).map_err(|err| {
+            AstarteMessageHubError::FatalError(format!(
+                "Invalid address {} for the http service: {}",
+                address, err
+            ))
+        })?;
+        let server = Server::try_bind(&addr)?;
+
+        Ok(Self::serve(server, configuration_ready_channel, toml_file))
+    }
+
+    /// Start a new HTTP API Server listening on a Unix domain socket
+    pub fn with_unix_socket(
+        path: &Path,
+        configuration_ready_channel: Sender<()>,
+        toml_file: &str,
+    ) -> Result<HttpConfigProvider, AstarteMessageHubError> {
+        let listener = bind_unix_socket(path, None, None, None)?;
+        let server = Server::builder(accept::from_stream(UnixListenerStream::new(listener)));
+
+        Ok(Self::serve(server, configuration_ready_channel, toml_file))
+    }
+
+    fn serve<I>(
+        server: Builder<I>,
+        configuration_ready_channel: Sender<()>,
+        toml_file: &str,
+    ) -> HttpConfigProvider
+    where
+        I: Accept + Send + 'static,
+        I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let extension = ConfigServerExtension {
             configuration_ready_channel,
-            toml_file: toml_file.to_string(),
+            toml_file: ProvisionedFile::new(toml_file),
         };
         let app = Router::new()
             .route("/", get(Self::root))
-            .route("/config", post(Self::set_config))
+            .route("/config", get(Self::get_config).post(Self::set_config))
+            .route("/config/validate", post(Self::validate_config))
             .layer(Extension(extension));
 
         let (tx, mut rx) = channel::<()>(1);
-        let addr = SocketAddr::from_str(address).unwrap();
 
         tokio::spawn(async move {
-            Server::bind(&addr)
+            let result = server
                 .serve(app.into_make_service())
-                .with_graceful_shutdown(async { rx.recv().await.unwrap() })
-                .await
-                .unwrap_or_else(|e| panic!("Unable to start http service: {}", e));
+                .with_graceful_shutdown(async {
+                    rx.recv().await;
+                })
+                .await;
+
+            if let Err(err) = result {
+                error!("Unable to run the http service: {}", err);
+            }
         });
 
         HttpConfigProvider {
@@ -195,6 +215,7 @@ mod test {
     use serde_json::{Map, Number, Value};
 
     use crate::config::file::CONFIG_FILE_NAMES;
+    use crate::config::MessageHubOptions;
 
     #[tokio::test]
     #[serial]
@@ -208,13 +229,13 @@ mod test {
             .to_string_lossy()
             .to_string();
 
-        let server = HttpConfigProvider::new("127.0.0.1:8080", tx, &toml_file);
+        let server = HttpConfigProvider::new("127.0.0.1:8080", tx, &toml_file).unwrap();
 
         let mut body = Map::new();
         body.insert("realm".to_string(), Value::String("realm".to_string()));
         body.insert(
             "device_id".to_string(),
-            Value::String("device_id".to_string()),
+            Value::String("2TBn-jNESuuHamE2Zo1anA".to_string()),
         );
         body.insert(
             "credentials_secret".to_string(),
@@ -260,7 +281,7 @@ mod test {
             .to_string_lossy()
             .to_string();
 
-        let server = HttpConfigProvider::new("127.0.0.1:8081", tx, &toml_file);
+        let server = HttpConfigProvider::new("127.0.0.1:8081", tx, &toml_file).unwrap();
 
         let mut body = HashMap::new();
         body.insert("device_id", "device_id");
@@ -294,13 +315,13 @@ mod test {
             .to_string_lossy()
             .to_string();
 
-        let server = HttpConfigProvider::new("127.0.0.1:8080", tx, &toml_file);
+        let server = HttpConfigProvider::new("127.0.0.1:8080", tx, &toml_file).unwrap();
 
         let mut body = Map::new();
         body.insert("realm".to_string(), Value::String("".to_string()));
         body.insert(
             "device_id".to_string(),
-            Value::String("device_id".to_string()),
+            Value::String("2TBn-jNESuuHamE2Zo1anA".to_string()),
         );
         body.insert(
             "credentials_secret".to_string(),
@@ -329,4 +350,72 @@ mod test {
         assert_eq!(json.result, "KO".to_string());
         server.stop().await;
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn get_and_validate_config_test() {
+        let (tx, _) = channel(1);
+
+        let dir = TempDir::new().unwrap();
+        let toml_file = dir.path().join(CONFIG_FILE_NAMES[0]);
+
+        let server =
+            HttpConfigProvider::new("127.0.0.1:8082", tx, &toml_file.to_string_lossy()).unwrap();
+
+        let client = reqwest::Client::new();
+        let resp = client
+            .get("http://localhost:8082/config")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let body = serde_json::json!({
+            "realm": "realm",
+            "pairing_url": "pairing_url",
+            "pairing_token": "pairing_token",
+            "grpc_socket_port": 22,
+            "astarte_ignore_ssl": true,
+            "session": {"buffer_size": 16},
+        });
+
+        let resp = client
+            .post("http://localhost:8082/config/validate")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!toml_file.exists());
+
+        let resp = client
+            .post("http://localhost:8082/config/validate")
+            .json(&serde_json::json!({"realm": "", "pairing_url": "pairing_url"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = client
+            .post("http://localhost:8082/config")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = client
+            .get("http://localhost:8082/config")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let config: MessageHubOptions = resp.json().await.unwrap();
+        assert_eq!(config.pairing_token.as_deref(), Some("<redacted>"));
+        assert!(config.astarte_ignore_ssl);
+        assert_eq!(config.session.buffer_size, 16);
+        assert_eq!(config.store_directory, dir.path());
+
+        server.stop().await;
+    }
 }
@@ -19,24 +19,85 @@
  */
 //! Helper module to retreive the configuration of the Astarte message hub.
 
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{fs, io};
 
-use log::debug;
+use astarte_device_sdk::options::{AstarteOptions, AstarteOptionsError};
+use astarte_device_sdk::{AstarteDeviceSdk, AstarteError};
+use log::{debug, warn, LevelFilter};
+use nix::sys::stat::{self, Mode};
+use nix::unistd::{self, Gid, Uid};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tokio::net::UnixListener;
 use tokio::sync::mpsc::channel;
+use toml::value::Table;
+use toml::Value;
+use uuid::Uuid;
 
 use crate::config::http::HttpConfigProvider;
 use crate::config::protobuf::ProtobufConfigProvider;
+use crate::data::properties::ServerProperties;
+use crate::device_id::{self, DeviceIdChain, DeviceIdProvider};
 use crate::error::{AstarteMessageHubError, ConfigValidationError};
+use crate::persist::{self, Exposure};
+use crate::secrets::{self, SecretStore};
 
 pub mod file;
 pub mod http;
+pub mod overrides;
 pub mod protobuf;
+mod provisioning;
+pub mod reload;
 
 use file::CONFIG_FILE_NAMES;
+use overrides::ConfigOverrides;
 
 const CREDENTIAL_FILE: &str = "credentials_secret";
+/// Placeholder of the secrets in the printed configuration.
+const REDACTED: &str = "<redacted>";
+
+/// Dotted paths of the options containing secrets, redacted when printed and not accepted on the
+/// command line.
+pub const SECRET_OPTIONS: [&str; 4] = [
+    "credentials_secret",
+    "pairing_token",
+    "admin.tokens",
+    "auth.tokens",
+];
+
+/// Whether the option with the dotted path `key` contains a secret, or a table containing one.
+pub fn is_secret_option(key: &str) -> bool {
+    let key = key.trim();
+
+    SECRET_OPTIONS.iter().any(|secret| {
+        let nested = |parent: &str, child: &str| {
+            child
+                .strip_prefix(parent)
+                .map_or(false, |rest| rest.starts_with('.'))
+        };
+
+        *secret == key || nested(secret, key) || nested(key, secret)
+    })
+}
+
+/// Maximum number of times the device is registered again when Astarte rejects its credentials
+/// secret.
+pub const MAX_REGISTRATION_ATTEMPTS: u32 = 3;
+
+/// Delay before registering the device again, multiplied by the number of attempts.
+#[cfg(not(test))]
+const REGISTRATION_BACKOFF: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const REGISTRATION_BACKOFF: Duration = Duration::from_millis(10);
 
 /// A macro to simplify the creation of a `Result` with an `AstarteMessageHubError` error type.
 macro_rules! ensure {
@@ -47,8 +108,48 @@ macro_rules! ensure {
     };
 }
 
+/// Serializes the changes of the umask, so the one of the process is always restored.
+static UMASK_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Bind a Unix domain socket, removing the one left by a previous instance.
+///
+/// The socket is created with a restrictive umask, so no other user can connect before its
+/// ownership and its mode are set. Without a mode, the one given by the umask of the process is
+/// set.
+pub fn bind_unix_socket(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        _ => {}
+    }
+
+    // The umask is shared by the threads, the files created meanwhile are only more restricted
+    let (umask, listener) = {
+        let _guard = UMASK_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let umask = stat::umask(Mode::from_bits_truncate(0o077));
+        let listener = UnixListener::bind(path);
+        stat::umask(umask);
+        (umask, listener)
+    };
+    let listener = listener?;
+
+    if owner.is_some() || group.is_some() {
+        unistd::chown(path, owner, group)?;
+    }
+
+    let mode = mode.unwrap_or(0o777 & !umask.bits());
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+
+    Ok(listener)
+}
+
 /// Struct containing all the configuration options for the Astarte message hub.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct MessageHubOptions {
     /// The Astarte realm the device belongs to.
     pub realm: String,
@@ -65,11 +166,286 @@ pub struct MessageHubOptions {
     /// Whether to ignore SSL errors when connecting to Astarte.
     #[serde(default)]
     pub astarte_ignore_ssl: bool,
-    /// The gRPC port to use.
-    pub grpc_socket_port: u16,
+    /// The gRPC port to use, listening on the loopback interface.
+    #[serde(default)]
+    pub grpc_socket_port: Option<u16>,
+    /// Path of the Unix domain socket the gRPC server listens on.
+    ///
+    /// Can be used together with the `grpc_socket_port` to listen on both sockets.
+    #[serde(default)]
+    pub grpc_unix_socket_path: Option<PathBuf>,
+    /// File mode of the Unix domain socket, e.g. `0o660`.
+    #[serde(default)]
+    pub grpc_unix_socket_mode: Option<u32>,
+    /// Name or id of the user owning the Unix domain socket.
+    #[serde(default)]
+    pub grpc_unix_socket_owner: Option<String>,
+    /// Name or id of the group owning the Unix domain socket.
+    #[serde(default)]
+    pub grpc_unix_socket_group: Option<String>,
     /// Directory used by Astarte-Message-Hub to retain configuration and other persistent data.
     #[serde(default = "MessageHubOptions::default_store_directory")]
     pub store_directory: PathBuf,
+    /// Maximum level of the logs, e.g. `info`, ignored when the `RUST_LOG` variable is set.
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Namespace the device id is generated in, from the hardware id returned by the providers.
+    #[serde(default)]
+    pub device_id_namespace: Option<Uuid>,
+    /// Providers tried in order to obtain the device id when it is not configured.
+    ///
+    /// If empty, the device id is obtained from the Edgehog device service.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub device_id_providers: Vec<DeviceIdSource>,
+    /// Options for the queue of the messages sent while Astarte is not reachable.
+    #[serde(default)]
+    pub queue: QueueOptions,
+    /// Options for the sessions of the attached nodes.
+    #[serde(default)]
+    pub session: SessionOptions,
+    /// Options for the Prometheus metrics endpoint.
+    #[serde(default)]
+    pub metrics: MetricsOptions,
+    /// Options for the administration API.
+    #[serde(default)]
+    pub admin: AdminOptions,
+    /// Options for the servers waiting for the configuration.
+    #[serde(default)]
+    pub provisioning: ProvisioningOptions,
+    /// Options for the storage of the credentials secret and of the pairing token.
+    #[serde(default)]
+    pub secrets: SecretsOptions,
+    /// Options for the authentication of the nodes.
+    #[serde(default)]
+    pub auth: AuthOptions,
+}
+
+/// Options for the authentication of the nodes connecting to the message hub.
+///
+/// When enabled, every node must send its pre-shared token in the `authorization` metadata of
+/// its requests.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthOptions {
+    /// Require the nodes to authenticate.
+    pub enabled: bool,
+    /// Directory with a file for each node, named after the node UUID and containing its token.
+    ///
+    /// Relative paths are resolved from the store directory, defaults to `node_tokens`.
+    pub tokens_directory: Option<PathBuf>,
+    /// Tokens of the nodes, indexed by the node UUID.
+    pub tokens: BTreeMap<String, String>,
+}
+
+/// Options for the administration API, used to inspect and detach the attached nodes.
+///
+/// The API is served over gRPC, HTTP or both, every request must send one of the tokens in the
+/// `authorization` metadata or header as `Bearer <TOKEN>`.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminOptions {
+    /// Serve the administration API.
+    pub enabled: bool,
+    /// Address the admin gRPC server listens on.
+    pub grpc_address: Option<SocketAddr>,
+    /// Address the admin HTTP server listens on.
+    pub http_address: Option<SocketAddr>,
+    /// Tokens allowed to use the administration API.
+    pub tokens: Vec<String>,
+}
+
+/// Options for the storage of the secrets of the device.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsOptions {
+    /// Backend storing the secrets.
+    pub backend: SecretBackend,
+    /// File containing the key of the encrypted backend.
+    pub key_file: Option<PathBuf>,
+    /// Description of the `user` key of the kernel keyring containing the key of the encrypted
+    /// backend, used if the key file is not set.
+    pub keyring_key: Option<String>,
+}
+
+/// Backend storing the secrets of the device.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretBackend {
+    /// Plaintext files in the store directory, only readable by the owner.
+    Plaintext,
+    /// Encrypted files in the store directory.
+    Encrypted,
+}
+
+impl Default for SecretBackend {
+    fn default() -> Self {
+        Self::Plaintext
+    }
+}
+
+/// Source of the device id, used when the device id is not configured.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DeviceIdSource {
+    /// Hardware id returned by the Edgehog device service over D-Bus.
+    Edgehog {
+        /// Namespace the hardware id is generated in.
+        #[serde(default)]
+        namespace: String,
+    },
+    /// Id derived from the `/etc/machine-id` of the system.
+    MachineId,
+    /// Product UUID of the DMI table.
+    Dmi,
+    /// Fixed device id.
+    Static {
+        /// The device id.
+        device_id: String,
+    },
+}
+
+/// Options for the HTTP and Protobuf servers waiting for the configuration when the store
+/// directory does not contain one.
+///
+/// Since the servers run before the configuration is provided, these options are only read from
+/// the environment variables and the command line flags.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisioningOptions {
+    /// Listener of the HTTP server, on `127.0.0.1:40041` by default.
+    pub http: ProvisioningListener,
+    /// Listener of the Protobuf server, on `[::1]:40042` by default.
+    pub protobuf: ProvisioningListener,
+}
+
+impl ProvisioningOptions {
+    /// Address of the HTTP server.
+    pub fn http_address(&self) -> SocketAddr {
+        self.http
+            .address
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 40041)))
+    }
+
+    /// Address of the Protobuf server.
+    pub fn protobuf_address(&self) -> SocketAddr {
+        self.protobuf
+            .address
+            .unwrap_or_else(|| SocketAddr::from((Ipv6Addr::LOCALHOST, 40042)))
+    }
+}
+
+/// Listener of a provisioning server.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisioningListener {
+    /// Run the server.
+    pub enabled: bool,
+    /// Address the server listens on, defaults to the one of the server.
+    pub address: Option<SocketAddr>,
+    /// Path of a Unix domain socket the server listens on, instead of the address.
+    pub unix_socket_path: Option<PathBuf>,
+}
+
+impl Default for ProvisioningListener {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: None,
+            unix_socket_path: None,
+        }
+    }
+}
+
+/// Options for the HTTP endpoint exposing the Prometheus metrics.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsOptions {
+    /// Serve the metrics.
+    pub enabled: bool,
+    /// Address the HTTP server listens on, the metrics are on the `/metrics` route.
+    pub address: SocketAddr,
+}
+
+impl Default for MetricsOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, 9657)),
+        }
+    }
+}
+
+/// Options for the sessions of the nodes attached to the message hub.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionOptions {
+    /// Seconds a node is kept attached after dropping its stream without detaching.
+    pub detach_grace_period: u64,
+    /// Maximum number of events buffered during the grace period and replayed to the node when it
+    /// attaches again, `0` disables the replay.
+    pub replay_buffer_size: usize,
+    /// Maximum number of events waiting to be delivered to a node not reading its stream.
+    pub buffer_size: usize,
+    /// Policy applied to the new events when the buffer of a node is full.
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            detach_grace_period: 5,
+            replay_buffer_size: 0,
+            buffer_size: 128,
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// Options for the persistent queue of the messages that could not be sent to Astarte.
+///
+/// Only the messages on properties interfaces and on datastream mappings with a `guaranteed` or
+/// `unique` reliability are queued, the `unreliable` ones are discarded.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueOptions {
+    /// Maximum number of messages retained in the queue, `0` disables the queue.
+    pub max_size: usize,
+    /// Policy used to make room for a new message when the queue is full.
+    pub eviction_policy: EvictionPolicy,
+    /// Interval in seconds between the attempts to send the queued messages.
+    pub retry_interval: u64,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 1000,
+            eviction_policy: EvictionPolicy::DropOldest,
+            retry_interval: 5,
+        }
+    }
+}
+
+/// Policy used to make room for a new message in a full queue.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Remove the oldest message in the queue.
+    DropOldest,
+    /// Reject the new message.
+    DropNewest,
+}
+
+/// Policy applied to a new event for a node whose buffer is full.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Remove the oldest event in the buffer.
+    DropOldest,
+    /// Discard the new event.
+    DropNewest,
+    /// Close the stream of the node, discarding the buffered events.
+    Disconnect,
 }
 
 impl MessageHubOptions {
@@ -83,45 +459,147 @@ impl MessageHubOptions {
     /// If no valid configuration file is found in either of these locations, or if the content
     /// of the first found file is not valid HTTP and Protobuf APIs are exposed to provide a valid
     /// configuration.
+    ///
+    /// The options read from the file are replaced by the overridden ones, see [MessageHubOptions::load].
     pub async fn get(
         toml_file: Option<String>,
         store_directory: Option<&Path>,
+        overrides: &ConfigOverrides,
     ) -> Result<MessageHubOptions, AstarteMessageHubError> {
-        let mut opt = if let Some(toml_file) = toml_file {
-            let toml_str = std::fs::read_to_string(toml_file)?;
-            file::get_options_from_toml(&toml_str)
-        } else if let Some(store_directory) = store_directory {
+        if let (None, Some(store_directory)) = (&toml_file, store_directory) {
             if !store_directory.is_dir() {
                 let err_msg = "Provided store directory for HTTP and ProtoBuf does not exists.";
                 return Err(AstarteMessageHubError::FatalError(err_msg.to_string()));
             }
             let configuration_file = store_directory.join(CONFIG_FILE_NAMES[0]);
-            if !configuration_file.exists() {
-                let (tx, mut rx) = channel(1);
 
-                let web_server = HttpConfigProvider::new(
-                    "127.0.0.1:40041",
-                    tx.clone(),
-                    configuration_file.to_str().unwrap(),
-                );
-                let protobuf_server = ProtobufConfigProvider::new(
-                    "[::1]:50051",
+            // The overrides alone could provide a valid configuration
+            let overridden = file::get_options_with_overrides("", overrides);
+            if !configuration_file.exists() && overridden.is_err() {
+                let provisioning = Self::provisioning_options(overrides)?;
+
+                Self::wait_for_configuration(&provisioning, &configuration_file).await?;
+            }
+        }
+
+        Self::load(toml_file.as_deref(), store_directory, overrides)
+    }
+
+    /// Read the options of the provisioning servers from the overrides.
+    fn provisioning_options(
+        overrides: &ConfigOverrides,
+    ) -> Result<ProvisioningOptions, AstarteMessageHubError> {
+        let mut table = Table::new();
+        overrides.apply(&mut table)?;
+
+        table
+            .remove("provisioning")
+            .map(Value::try_into)
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(AstarteMessageHubError::ConfigFileError)
+    }
+
+    /// Run the enabled provisioning servers until the configuration file is written.
+    async fn wait_for_configuration(
+        provisioning: &ProvisioningOptions,
+        configuration_file: &Path,
+    ) -> Result<(), AstarteMessageHubError> {
+        ensure!(
+            provisioning.http.enabled || provisioning.protobuf.enabled,
+            AstarteMessageHubError::FatalError(
+                "No configuration in the store directory and the provisioning is disabled."
+                    .to_string()
+            )
+        );
+
+        let toml_file = configuration_file.to_string_lossy();
+        let (tx, mut rx) = channel(1);
+
+        let web_server = if provisioning.http.enabled {
+            let server = match &provisioning.http.unix_socket_path {
+                Some(path) => HttpConfigProvider::with_unix_socket(path, tx.clone(), &toml_file),
+                None => HttpConfigProvider::new(
+                    &provisioning.http_address().to_string(),
                     tx.clone(),
-                    configuration_file.to_str().unwrap(),
-                )
-                .await;
+                    &toml_file,
+                ),
+            }?;
 
-                rx.recv().await.unwrap();
+            Some(server)
+        } else {
+            None
+        };
 
-                web_server.stop().await;
-                protobuf_server.stop().await;
+        let protobuf_server = if provisioning.protobuf.enabled {
+            let server = match &provisioning.protobuf.unix_socket_path {
+                Some(path) => {
+                    ProtobufConfigProvider::with_unix_socket(path, tx.clone(), &toml_file).await
+                }
+                None => {
+                    ProtobufConfigProvider::new(
+                        &provisioning.protobuf_address().to_string(),
+                        tx.clone(),
+                        &toml_file,
+                    )
+                    .await
+                }
+            };
+
+            match server {
+                Ok(server) => Some(server),
+                Err(err) => {
+                    if let Some(web_server) = &web_server {
+                        web_server.stop().await;
+                    }
+
+                    return Err(err);
+                }
             }
-            let toml_str = std::fs::read_to_string(configuration_file)?;
-
-            file::get_options_from_toml(&toml_str)
         } else {
-            file::get_options_from_base_toml()
-        }?;
+            None
+        };
+
+        // The channel is closed if all the servers stopped
+        drop(tx);
+        let received = rx.recv().await;
+
+        if let Some(web_server) = web_server {
+            web_server.stop().await;
+        }
+        if let Some(protobuf_server) = protobuf_server {
+            protobuf_server.stop().await;
+        }
+
+        received.ok_or_else(|| {
+            AstarteMessageHubError::FatalError(
+                "The provisioning servers stopped before receiving the configuration.".to_string(),
+            )
+        })
+    }
+
+    /// Load the layered configuration, without waiting for it to be provisioned.
+    ///
+    /// The defaults are replaced by the options in the configuration file, if any, and then by
+    /// the overrides from the environment and the command line.
+    pub fn load(
+        toml_file: Option<&str>,
+        store_directory: Option<&Path>,
+        overrides: &ConfigOverrides,
+    ) -> Result<MessageHubOptions, AstarteMessageHubError> {
+        let configuration_file = Self::config_file(toml_file, store_directory)
+            .filter(|file| toml_file.is_some() || file.exists());
+
+        let toml_str = match configuration_file {
+            Some(file) => fs::read_to_string(file)?,
+            None if overrides.is_empty() => {
+                let err_msg = "No configuration file found in the base locations.";
+                return Err(AstarteMessageHubError::FatalError(err_msg.to_string()));
+            }
+            None => String::new(),
+        };
+
+        let mut opt = file::get_options_with_overrides(&toml_str, overrides)?;
 
         if let Some(store_directory) = store_directory {
             opt.store_directory = store_directory.to_path_buf();
@@ -130,6 +608,85 @@ impl MessageHubOptions {
         Ok(opt)
     }
 
+    /// Returns a copy of the options with the secrets replaced by a placeholder, to be printed.
+    ///
+    /// The [SECRET_OPTIONS] are redacted.
+    pub fn redacted(&self) -> MessageHubOptions {
+        let redact = |secret: &String| {
+            if secret.is_empty() {
+                String::new()
+            } else {
+                REDACTED.to_string()
+            }
+        };
+
+        let mut opt = self.clone();
+        opt.credentials_secret = opt.credentials_secret.as_ref().map(redact);
+        opt.pairing_token = opt.pairing_token.as_ref().map(redact);
+        opt.admin.tokens = opt.admin.tokens.iter().map(redact).collect();
+        opt.auth
+            .tokens
+            .values_mut()
+            .for_each(|token| *token = redact(token));
+
+        opt
+    }
+
+    /// Returns the configuration file read by [MessageHubOptions::get], if any.
+    pub fn config_file(toml_file: Option<&str>, store_directory: Option<&Path>) -> Option<PathBuf> {
+        match (toml_file, store_directory) {
+            (Some(toml_file), _) => Some(PathBuf::from(toml_file)),
+            (None, Some(store_directory)) => Some(store_directory.join(CONFIG_FILE_NAMES[0])),
+            (None, None) => CONFIG_FILE_NAMES
+                .iter()
+                .map(PathBuf::from)
+                .find(|file| file.exists()),
+        }
+    }
+
+    /// Check the permissions of the configuration file and of the stored credentials.
+    ///
+    /// Fails if a file is writable by the other users, and warns if a file containing secrets is
+    /// readable by them.
+    pub fn check_permissions(
+        &self,
+        config_file: Option<&Path>,
+    ) -> Result<(), AstarteMessageHubError> {
+        let credentials_file = self.store_directory.join(CREDENTIAL_FILE);
+        let files = config_file
+            .map(|file| (file, self.has_secrets()))
+            .into_iter()
+            .chain([(credentials_file.as_path(), true)]);
+
+        for (file, secret) in files {
+            match persist::exposure(file)? {
+                Some(Exposure::Writable) => {
+                    return Err(AstarteMessageHubError::InsecurePermissions(
+                        file.to_path_buf(),
+                    ));
+                }
+                Some(Exposure::Readable) if secret => {
+                    warn!(
+                        "{} contains secrets and is readable by other users, restrict its mode to {:o}",
+                        file.display(),
+                        persist::PRIVATE_MODE
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the options contain a secret.
+    fn has_secrets(&self) -> bool {
+        self.credentials_secret.is_some()
+            || self.pairing_token.is_some()
+            || !self.admin.tokens.is_empty()
+            || !self.auth.tokens.is_empty()
+    }
+
     /// Validates the configuration and return the reason if it is not valid.
     pub fn validate(&self) -> Result<(), ConfigValidationError> {
         ensure!(
@@ -142,6 +699,21 @@ impl MessageHubOptions {
             ConfigValidationError::MissingField("pairing_url")
         );
 
+        if let Some(device_id) = &self.device_id {
+            ensure!(
+                device_id::is_valid_device_id(device_id),
+                ConfigValidationError::InvalidDeviceId(device_id.clone())
+            );
+        }
+
+        ensure!(
+            self.device_id_providers.iter().all(|source| !matches!(
+                source,
+                DeviceIdSource::Static { device_id } if device_id.is_empty()
+            )),
+            ConfigValidationError::MissingField("device_id_providers.device_id")
+        );
+
         let valid_secret = self
             .credentials_secret
             .as_ref()
@@ -154,11 +726,24 @@ impl MessageHubOptions {
             .map(|e| !e.is_empty())
             .unwrap_or(false);
 
+        // The encrypted secret store can contain the secrets removed from the configuration
         ensure!(
-            valid_secret || valid_token,
+            valid_secret || valid_token || self.secrets.backend == SecretBackend::Encrypted,
             ConfigValidationError::MissingPairingAndCredentials
         );
 
+        ensure!(
+            self.secrets.backend == SecretBackend::Plaintext
+                || self.secrets.key_file.is_some()
+                || self.secrets.keyring_key.is_some(),
+            ConfigValidationError::MissingSecretKey
+        );
+
+        ensure!(
+            self.grpc_socket_port.is_some() || self.grpc_unix_socket_path.is_some(),
+            ConfigValidationError::MissingGrpcListener
+        );
+
         let valid_interface_dir = self
             .interfaces_directory
             .as_ref()
@@ -171,61 +756,322 @@ impl MessageHubOptions {
             ConfigValidationError::InvalidInterfaceDirectory(self.interfaces_directory.clone())
         );
 
+        if let Some(log_level) = &self.log_level {
+            ensure!(
+                LevelFilter::from_str(log_level).is_ok(),
+                ConfigValidationError::InvalidLogLevel(log_level.clone())
+            );
+        }
+
+        if self.admin.enabled {
+            ensure!(
+                self.admin.grpc_address.is_some() || self.admin.http_address.is_some(),
+                ConfigValidationError::MissingAdminListener
+            );
+
+            ensure!(
+                !self.admin.tokens.is_empty() && self.admin.tokens.iter().all(|t| !t.is_empty()),
+                ConfigValidationError::MissingAdminTokens
+            );
+        }
+
         Ok(())
     }
 
-    /// Obtains the credential secret from the stored path or by registering the device.
+    /// Obtains the credential secret from the options, from the secret store or by registering
+    /// the device.
+    ///
+    /// With the encrypted secrets backend the stored credentials secret is preferred over the one
+    /// of the options.
     pub async fn obtain_credential_secret<'a: 'b, 'b>(
         &'a mut self,
     ) -> Result<&'b str, AstarteMessageHubError> {
-        if let Some(ref cred_sec) = self.credentials_secret {
-            return Ok(cred_sec);
+        let store = self.secret_store()?;
+
+        let configured = self.credentials_secret.as_deref();
+        let credential =
+            match self.stored_secret(store.as_ref(), secrets::CREDENTIALS_SECRET, configured)? {
+                Some(cred) => {
+                    debug!("using configured or stored credentials");
+
+                    cred
+                }
+                None => {
+                    debug!("credentials not found, registering device");
+
+                    self.register_device(store.as_ref()).await?
+                }
+            };
+
+        self.credentials_secret = Some(credential);
+
+        Ok(self.credentials_secret.as_ref().unwrap())
+    }
+
+    /// Build the options of the Astarte device SDK.
+    ///
+    /// The device id and the credentials secret must be already obtained.
+    pub fn astarte_options(&self) -> Result<AstarteOptions, AstarteMessageHubError> {
+        let device_id = self.device_id.as_ref().ok_or_else(|| {
+            AstarteMessageHubError::FatalError("No device id provided".to_string())
+        })?;
+        let credentials_secret = self.credentials_secret.as_ref().ok_or_else(|| {
+            AstarteMessageHubError::FatalError("No credentials secret provided".to_string())
+        })?;
+
+        let mut options = AstarteOptions::new(
+            &self.realm,
+            device_id,
+            credentials_secret,
+            &self.pairing_url,
+        );
+
+        if self.astarte_ignore_ssl {
+            options = options.ignore_ssl_errors();
+        }
+
+        if let Some(int_dir) = &self.interfaces_directory {
+            options = options.interface_directory(&int_dir.to_string_lossy())?;
+        }
+
+        Ok(options)
+    }
+
+    /// Connect the device to Astarte.
+    ///
+    /// If Astarte rejects the credentials secret, the device is registered again with the pairing
+    /// token, at most [`MAX_REGISTRATION_ATTEMPTS`] times, and the new credentials secret replaces
+    /// the stored one.
+    ///
+    /// The device caches the server-owned properties in the given [ServerProperties], including
+    /// the ones of the interfaces directory.
+    pub async fn connect_device(
+        &mut self,
+        properties: &ServerProperties,
+    ) -> Result<AstarteDeviceSdk, AstarteMessageHubError> {
+        if let Some(interfaces_directory) = &self.interfaces_directory {
+            properties
+                .register_interfaces_directory(interfaces_directory)
+                .await?;
         }
 
-        let path = self.store_directory.join(CREDENTIAL_FILE);
+        self.connect_with(|options| {
+            let options = options.database(properties.clone());
 
-        let credential = match fs::read_to_string(&path) {
-            Ok(cred) => {
-                debug!("using stored credentials from {:?}", path);
+            async move { AstarteDeviceSdk::new(&options).await }
+        })
+        .await
+    }
 
-                Ok(cred)
+    async fn connect_with<F, Fut, T>(&mut self, mut connect: F) -> Result<T, AstarteMessageHubError>
+    where
+        F: FnMut(AstarteOptions) -> Fut,
+        Fut: Future<Output = Result<T, AstarteError>>,
+    {
+        self.obtain_device_id().await?;
+        // The encrypted store keeps the new credentials secret over the configured one
+        let configured_secret = self
+            .credentials_secret
+            .clone()
+            .filter(|secret| !secret.is_empty())
+            .filter(|_| self.secrets.backend != SecretBackend::Encrypted);
+        self.obtain_credential_secret().await?;
+
+        let mut attempts = 0;
+        loop {
+            let err = match connect(self.astarte_options()?).await {
+                Ok(connected) => return Ok(connected),
+                Err(err) => err,
+            };
+
+            if !is_authentication_error(&err)? {
+                return Err(err.into());
             }
-            Err(err) if err.kind() == io::ErrorKind::NotFound => {
-                debug!("credentials not found, registering device");
 
-                let cred = self.register_device(&path).await?;
+            if attempts == MAX_REGISTRATION_ATTEMPTS {
+                return Err(AstarteMessageHubError::RegistrationFailed {
+                    attempts,
+                    source: Box::new(err),
+                });
+            }
 
-                Ok(cred)
+            attempts += 1;
+            warn!(
+                "Astarte rejected the credentials secret, registering the device again ({}/{})",
+                attempts, MAX_REGISTRATION_ATTEMPTS
+            );
+
+            tokio::time::sleep(REGISTRATION_BACKOFF * attempts).await;
+            let rejected = self.credentials_secret.clone();
+            self.renew_credential_secret(err).await?;
+
+            // Remember the rejected configured secret, so the renewed one is used after a restart
+            if let Some(configured) = configured_secret
+                .as_ref()
+                .filter(|c| rejected == Some(c.to_string()))
+            {
+                self.secret_store()?.write(
+                    secrets::REJECTED_CREDENTIALS_SECRET,
+                    &secrets::secret_digest(configured),
+                )?;
+
+                warn!(
+                    "The configured credentials secret was rejected, the renewed one is used \
+                     instead: remove it from the configuration"
+                );
             }
-            Err(err) => Err(AstarteMessageHubError::FatalError(format!(
-                "failed to read {}: {}",
-                path.to_string_lossy(),
-                err
-            ))),
-        }?;
+        }
+    }
 
-        self.credentials_secret = Some(credential);
+    /// Registers the device again, replacing the credentials secret rejected by Astarte.
+    async fn renew_credential_secret(
+        &mut self,
+        rejected: AstarteError,
+    ) -> Result<(), AstarteMessageHubError> {
+        let store = self.secret_store()?;
 
-        Ok(self.credentials_secret.as_ref().unwrap())
+        let pairing_token = self.pairing_token.as_deref();
+        if self
+            .stored_secret(store.as_ref(), secrets::PAIRING_TOKEN, pairing_token)?
+            .is_none()
+        {
+            return Err(AstarteMessageHubError::CredentialsRejected(Box::new(
+                rejected,
+            )));
+        }
+
+        let credentials = self.register_device(store.as_ref()).await?;
+        self.credentials_secret = Some(credentials);
+
+        Ok(())
+    }
+
+    /// Open the store of the secrets of the device.
+    pub fn secret_store(&self) -> Result<Box<dyn SecretStore>, AstarteMessageHubError> {
+        secrets::open(&self.secrets, &self.store_directory)
+    }
+
+    /// Read a secret, choosing between the one configured in the options and the stored one.
+    ///
+    /// With the plaintext backend the configured secret is preferred, unless it is the credentials
+    /// secret Astarte rejected and the message hub renewed. With the encrypted backend the stored
+    /// secret is preferred, so the configured one is ignored once stored and a secret renewed by
+    /// the message hub is not replaced by a stale one of the configuration file.
+    fn stored_secret(
+        &self,
+        store: &dyn SecretStore,
+        name: &str,
+        configured: Option<&str>,
+    ) -> Result<Option<String>, AstarteMessageHubError> {
+        let configured = configured.filter(|secret| !secret.is_empty());
+
+        match (&self.secrets.backend, configured) {
+            (SecretBackend::Plaintext, Some(secret)) if name == secrets::CREDENTIALS_SECRET => {
+                let rejected = store.read(secrets::REJECTED_CREDENTIALS_SECRET)?;
+
+                match store.read(name)? {
+                    Some(renewed) if rejected == Some(secrets::secret_digest(secret)) => {
+                        warn!(
+                            "Using the renewed credentials secret instead of the rejected \
+                             configured one"
+                        );
+
+                        Ok(Some(renewed))
+                    }
+                    _ => Ok(Some(secret.to_string())),
+                }
+            }
+            (SecretBackend::Plaintext, Some(secret)) => Ok(Some(secret.to_string())),
+            (SecretBackend::Encrypted, configured) => {
+                Ok(store.read(name)?.or_else(|| configured.map(str::to_string)))
+            }
+            (SecretBackend::Plaintext, None) => store.read(name),
+        }
+    }
+
+    /// Copy the secrets of the options missing from the encrypted secret store, returning the
+    /// names of the configured secrets kept in the store.
+    ///
+    /// The stored secrets are not replaced, since they are preferred over the configured ones.
+    /// Nothing is stored with the plaintext backend.
+    pub fn migrate_secrets(&self) -> Result<Vec<&'static str>, AstarteMessageHubError> {
+        self.write_secrets(false)
+    }
+
+    /// Write the secrets of the options into the encrypted secret store, returning their names.
+    ///
+    /// The stored secrets are replaced, as done when provisioning new ones. Nothing is stored with
+    /// the plaintext backend.
+    pub fn store_secrets(&self) -> Result<Vec<&'static str>, AstarteMessageHubError> {
+        self.write_secrets(true)
+    }
+
+    fn write_secrets(&self, replace: bool) -> Result<Vec<&'static str>, AstarteMessageHubError> {
+        if self.secrets.backend != SecretBackend::Encrypted {
+            return Ok(Vec::new());
+        }
+
+        let store = self.secret_store()?;
+        let secrets = [
+            (secrets::CREDENTIALS_SECRET, &self.credentials_secret),
+            (secrets::PAIRING_TOKEN, &self.pairing_token),
+        ];
+
+        let mut stored = Vec::new();
+        for (name, secret) in secrets {
+            let secret = match secret {
+                Some(secret) if !secret.is_empty() => secret,
+                _ => continue,
+            };
+
+            match store.read(name)? {
+                Some(stored) if stored == *secret => {}
+                Some(_) if !replace => {
+                    warn!("Ignoring the configured {}, the stored one is used", name)
+                }
+                _ => store.write(name, secret)?,
+            }
+
+            stored.push(name);
+        }
+
+        Ok(stored)
+    }
+
+    /// Names of the secrets kept in the secret store that are still in plaintext in the
+    /// configuration file.
+    ///
+    /// The file belongs to the operator and is never rewritten, the secrets are only reported to
+    /// be removed from it.
+    pub fn plaintext_secrets<'a>(
+        config_file: &Path,
+        stored: &[&'a str],
+    ) -> Result<Vec<&'a str>, AstarteMessageHubError> {
+        let table: Table = toml::from_str(&fs::read_to_string(config_file)?)?;
+
+        Ok(stored
+            .iter()
+            .copied()
+            .filter(|name| table.contains_key(*name))
+            .collect())
     }
 
     /// Registers the device to the Astarte instance.
-    async fn register_device(&self, cred_file: &Path) -> Result<String, AstarteMessageHubError> {
-        let pairing_token = self.pairing_token.as_ref().ok_or_else(|| {
-            AstarteMessageHubError::FatalError("missing pairing token".to_string())
-        })?;
+    async fn register_device(
+        &self,
+        store: &dyn SecretStore,
+    ) -> Result<String, AstarteMessageHubError> {
+        let pairing_token = self
+            .stored_secret(store, secrets::PAIRING_TOKEN, self.pairing_token.as_deref())?
+            .ok_or_else(|| {
+                AstarteMessageHubError::FatalError("missing pairing token".to_string())
+            })?;
 
-        let credentials = self.register_with_astarte(pairing_token).await?;
+        let credentials = self.register_with_astarte(&pairing_token).await?;
 
-        debug!("device registered, storing credentials to {:?}", cred_file);
+        debug!("device registered, storing credentials");
 
-        fs::write(cred_file, &credentials).map_err(|err| {
-            AstarteMessageHubError::FatalError(format!(
-                "failed to write credentials to {}: {}",
-                cred_file.to_string_lossy(),
-                err
-            ))
-        })?;
+        store.write(secrets::CREDENTIALS_SECRET, &credentials)?;
 
         Ok(credentials)
     }
@@ -260,63 +1106,117 @@ impl MessageHubOptions {
         Ok(String::default())
     }
 
-    /// Obtains the device id from option or by zbus service.
+    /// Obtains the device id from the options or from the device id providers.
     pub async fn obtain_device_id<'a: 'b, 'b>(
         &'a mut self,
     ) -> Result<&'b str, AstarteMessageHubError> {
-        if self
-            .device_id
-            .as_ref()
-            .filter(|device_id| !device_id.is_empty())
-            .is_none()
-        {
-            let device_id = self.get_hardware_id_from_dbus().await?;
+        if self.device_id.is_none() {
+            let mut chain = DeviceIdChain::from_sources(&self.device_id_providers);
+            if let Some(namespace) = self.device_id_namespace {
+                chain = chain.with_namespace(namespace);
+            }
+
+            let device_id = chain.device_id().await?;
             self.device_id = Some(device_id);
         }
 
         Ok(self.device_id.as_ref().unwrap())
     }
+}
 
-    #[cfg(not(test))]
-    async fn get_hardware_id_from_dbus(&self) -> Result<String, AstarteMessageHubError> {
-        use crate::device::DeviceProxy;
+/// Whether the error means that Astarte rejected the credentials secret of the device.
+///
+/// Fails if the pairing error of the SDK cannot be recognized.
+pub fn is_authentication_error(err: &AstarteError) -> Result<bool, AstarteMessageHubError> {
+    Ok(matches!(pairing_status(err)?, Some(401 | 403)))
+}
 
-        let connection = zbus::Connection::system().await?;
-        let proxy = DeviceProxy::new(&connection).await?;
-        let device_id: String = proxy.get_hardware_id("").await?;
-        if device_id.is_empty() {
-            return Err(AstarteMessageHubError::FatalError(
-                "No hardware id provided".to_string(),
-            ));
-        }
-        Ok(device_id)
-    }
+/// Variants of the pairing errors of the SDK, the status code is in `ApiError`.
+const PAIRING_ERRORS: [&str; 7] = [
+    "InvalidCredentials",
+    "InvalidUrl",
+    "RequestError",
+    "UnexpectedResponse",
+    "ApiError",
+    "Crypto",
+    "ConfigError",
+];
+
+/// Status code of the response of the pairing API the SDK failed with.
+///
+/// The pairing errors of the SDK are in a private module and their message does not contain the
+/// status code, so it is read from the debug representation of the `ApiError(StatusCode, String)`
+/// variant. This is the only place depending on it, the SDK version is pinned and the tests check
+/// it with the errors returned by a pairing API. An unknown representation is an error, so a
+/// change of the SDK is not mistaken for an error other than a rejected secret.
+fn pairing_status(err: &AstarteError) -> Result<Option<u16>, AstarteMessageHubError> {
+    let err = match err {
+        AstarteError::OptionsError(AstarteOptionsError::PairingError(err)) => err,
+        _ => return Ok(None),
+    };
 
-    #[cfg(test)]
-    async fn get_hardware_id_from_dbus(&self) -> Result<String, AstarteMessageHubError> {
-        use log::info;
+    let debug = format!("{:?}", err);
+    let unknown =
+        || AstarteMessageHubError::FatalError(format!("unrecognized pairing error: {}", debug));
 
-        info!("retrieve mock-id");
-        Ok("mock-id".to_string())
+    let variant = debug.split('(').next().unwrap_or_default();
+    if !PAIRING_ERRORS.contains(&variant) {
+        return Err(unknown());
     }
+
+    let status = match debug.strip_prefix("ApiError(") {
+        Some(status) => status,
+        None => return Ok(None),
+    };
+
+    let end = status
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(unknown)?;
+
+    status[..end].parse().map(Some).map_err(|_| unknown())
 }
 
 #[cfg(test)]
 mod test {
+    use std::os::unix::fs::PermissionsExt;
+
     use super::*;
+    use crate::device_id::MOCK_HARDWARE_ID;
 
-    #[test]
-    fn test_is_valid_cred_sec_ok() {
-        let expected_msg_hub_opts = MessageHubOptions {
+    /// Options shared by the tests, which change the ones they check.
+    fn options() -> MessageHubOptions {
+        MessageHubOptions {
             realm: "1".to_string(),
-            device_id: Some("2".to_string()),
+            device_id: Some("2TBn-jNESuuHamE2Zo1anA".to_string()),
             pairing_url: "3".to_string(),
-            credentials_secret: Some("4".to_string()),
+            credentials_secret: None,
             pairing_token: None,
             interfaces_directory: None,
             astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
+            grpc_socket_port: Some(655),
+            grpc_unix_socket_path: None,
+            grpc_unix_socket_mode: None,
+            grpc_unix_socket_owner: None,
+            grpc_unix_socket_group: None,
             store_directory: MessageHubOptions::default_store_directory(),
+            log_level: None,
+            device_id_namespace: None,
+            device_id_providers: Vec::new(),
+            queue: QueueOptions::default(),
+            session: SessionOptions::default(),
+            metrics: MetricsOptions::default(),
+            admin: AdminOptions::default(),
+            provisioning: ProvisioningOptions::default(),
+            secrets: SecretsOptions::default(),
+            auth: AuthOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_is_valid_cred_sec_ok() {
+        let expected_msg_hub_opts = MessageHubOptions {
+            credentials_secret: Some("4".to_string()),
+            ..options()
         };
 
         let res = expected_msg_hub_opts.validate();
@@ -326,15 +1226,8 @@ mod test {
     #[test]
     fn test_is_valid_pairing_token_ok() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("4".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
 
         let res = expected_msg_hub_opts.validate();
@@ -345,14 +1238,8 @@ mod test {
     fn test_is_valid_empty_realm_err() {
         let expected_msg_hub_opts = MessageHubOptions {
             realm: "".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("4".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
         assert!(expected_msg_hub_opts.validate().is_err());
     }
@@ -360,31 +1247,31 @@ mod test {
     #[test]
     fn test_is_valid_empty_device_id() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
             device_id: Some("".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("4".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
+        };
+        assert!(matches!(
+            expected_msg_hub_opts.validate(),
+            Err(ConfigValidationError::InvalidDeviceId(device_id)) if device_id.is_empty()
+        ));
+
+        let invalid_msg_hub_opts = MessageHubOptions {
+            device_id: Some("2".to_string()),
+            ..expected_msg_hub_opts
         };
-        assert!(expected_msg_hub_opts.validate().is_ok());
+        assert!(matches!(
+            invalid_msg_hub_opts.validate(),
+            Err(ConfigValidationError::InvalidDeviceId(_))
+        ));
     }
 
     #[test]
     fn test_is_valid_empty_pairing_url_err() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
             pairing_url: "".to_string(),
-            credentials_secret: None,
             pairing_token: Some("4".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
         assert!(expected_msg_hub_opts.validate().is_err());
     }
@@ -392,15 +1279,8 @@ mod test {
     #[test]
     fn test_is_valid_empty_credentials_secred_err() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
             credentials_secret: Some("".to_string()),
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
         assert!(expected_msg_hub_opts.validate().is_err());
     }
@@ -408,15 +1288,8 @@ mod test {
     #[test]
     fn test_is_valid_empty_pairing_token_err() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
         assert!(expected_msg_hub_opts.validate().is_err());
     }
@@ -424,35 +1297,83 @@ mod test {
     #[test]
     fn test_is_valid_invalid_interf_dir_err() {
         let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("4".to_string()),
             interfaces_directory: Some(PathBuf::from("")),
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 5,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
         assert!(expected_msg_hub_opts.validate().is_err());
     }
 
     #[test]
     fn test_is_valid_missing_credentials_secret_and_pairing_token_err() {
-        let expected_msg_hub_opts = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
-            store_directory: MessageHubOptions::default_store_directory(),
-        };
+        let expected_msg_hub_opts = options();
         assert!(expected_msg_hub_opts.validate().is_err());
     }
 
+    #[test]
+    fn test_is_valid_grpc_listeners() {
+        let mut msg_hub_opts = MessageHubOptions {
+            credentials_secret: Some("4".to_string()),
+            grpc_socket_port: None,
+            ..options()
+        };
+        assert!(matches!(
+            msg_hub_opts.validate(),
+            Err(ConfigValidationError::MissingGrpcListener)
+        ));
+
+        msg_hub_opts.grpc_unix_socket_path = Some(PathBuf::from("/run/message-hub.sock"));
+        assert!(msg_hub_opts.validate().is_ok());
+
+        msg_hub_opts.grpc_socket_port = Some(50051);
+        assert!(msg_hub_opts.validate().is_ok());
+    }
+
+    #[test]
+    fn test_is_valid_admin() {
+        let mut msg_hub_opts = MessageHubOptions {
+            credentials_secret: Some("4".to_string()),
+            grpc_socket_port: Some(50051),
+            admin: AdminOptions {
+                enabled: true,
+                grpc_address: None,
+                http_address: None,
+                tokens: vec!["secret".to_string()],
+            },
+            ..options()
+        };
+        assert!(matches!(
+            msg_hub_opts.validate(),
+            Err(ConfigValidationError::MissingAdminListener)
+        ));
+
+        msg_hub_opts.admin.http_address = Some("127.0.0.1:50053".parse().unwrap());
+        assert!(msg_hub_opts.validate().is_ok());
+
+        msg_hub_opts.admin.tokens = vec![String::new()];
+        assert!(matches!(
+            msg_hub_opts.validate(),
+            Err(ConfigValidationError::MissingAdminTokens)
+        ));
+    }
+
+    #[test]
+    fn test_is_valid_log_level() {
+        let mut msg_hub_opts = file::get_options_from_toml(
+            "realm = \"1\"\npairing_url = \"2\"\ncredentials_secret = \"3\"\ngrpc_socket_port = 4",
+        )
+        .unwrap();
+
+        msg_hub_opts.log_level = Some("debug".to_string());
+        assert!(msg_hub_opts.validate().is_ok());
+
+        msg_hub_opts.log_level = Some("verbose".to_string());
+        assert!(matches!(
+            msg_hub_opts.validate(),
+            Err(ConfigValidationError::InvalidLogLevel(level)) if level == "verbose"
+        ));
+    }
+
     #[tokio::test]
     async fn obtain_stored_credential() {
         let expected = "32".to_string();
@@ -461,15 +1382,8 @@ mod test {
         fs::write(dir.path().join(CREDENTIAL_FILE), &expected).unwrap();
 
         let mut opt = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
             store_directory: dir.path().to_path_buf(),
+            ..options()
         };
 
         let secret = opt.obtain_credential_secret().await;
@@ -487,15 +1401,9 @@ mod test {
         let dir = tempfile::TempDir::new().unwrap();
 
         let mut opt = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("42".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
             store_directory: dir.path().to_path_buf(),
+            ..options()
         };
 
         let secret = opt.obtain_credential_secret().await;
@@ -511,20 +1419,440 @@ mod test {
         let res = fs::read_to_string(dir.path().join(CREDENTIAL_FILE)).unwrap();
 
         assert_eq!(secret, res);
+
+        let mode = fs::metadata(dir.path().join(CREDENTIAL_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, persist::PRIVATE_MODE);
+    }
+
+    #[test]
+    fn plaintext_secrets_in_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config_file = dir.path().join("config.toml");
+        let config = r#"
+            # Provisioned by the installer
+            realm = "1"
+            pairing_token = "42"
+            [secrets]
+            backend = "encrypted"
+            "#;
+        fs::write(&config_file, config).unwrap();
+
+        let stored = [secrets::CREDENTIALS_SECRET, secrets::PAIRING_TOKEN];
+        assert_eq!(
+            MessageHubOptions::plaintext_secrets(&config_file, &stored).unwrap(),
+            vec![secrets::PAIRING_TOKEN]
+        );
+        assert!(MessageHubOptions::plaintext_secrets(&config_file, &[])
+            .unwrap()
+            .is_empty());
+
+        // The configuration file is left untouched
+        assert_eq!(fs::read_to_string(&config_file).unwrap(), config);
+    }
+
+    #[tokio::test]
+    async fn obtain_credential_secret_encrypted() {
+        use std::fs::Permissions;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_file = dir.path().join("secrets.key");
+        fs::write(&key_file, "0123456789abcdef0123456789abcdef").unwrap();
+        fs::set_permissions(&key_file, Permissions::from_mode(0o600)).unwrap();
+
+        let mut opt = file::get_options_from_toml(&format!(
+            r#"
+            realm = "1"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
+            pairing_url = "3"
+            pairing_token = "42"
+            grpc_socket_port = 655
+            store_directory = "{}"
+            [secrets]
+            backend = "encrypted"
+            key_file = "{}"
+            "#,
+            dir.path().display(),
+            key_file.display()
+        ))
+        .unwrap();
+
+        assert_eq!(opt.store_secrets().unwrap(), vec![secrets::PAIRING_TOKEN]);
+
+        // The pairing token is read from the secret store
+        opt.pairing_token = None;
+        assert!(opt.validate().is_ok());
+
+        let secret = opt.obtain_credential_secret().await.unwrap().to_string();
+
+        assert!(!dir.path().join(CREDENTIAL_FILE).exists());
+        let store = opt.secret_store().unwrap();
+        assert_eq!(
+            store.read(secrets::CREDENTIALS_SECRET).unwrap(),
+            Some(secret)
+        );
+        assert_eq!(
+            store.read(secrets::PAIRING_TOKEN).unwrap(),
+            Some("42".to_string())
+        );
+
+        opt.secrets.key_file = None;
+        assert!(matches!(
+            opt.validate(),
+            Err(ConfigValidationError::MissingSecretKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn renewed_credential_secret_survives_restart() {
+        use std::fs::Permissions;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_file = dir.path().join("secrets.key");
+        fs::write(&key_file, "0123456789abcdef0123456789abcdef").unwrap();
+        fs::set_permissions(&key_file, Permissions::from_mode(0o600)).unwrap();
+
+        let config = format!(
+            r#"
+            realm = "1"
+            device_id = "2TBn-jNESuuHamE2Zo1anA"
+            pairing_url = "3"
+            credentials_secret = "stale"
+            pairing_token = "42"
+            grpc_socket_port = 655
+            store_directory = "{}"
+            [secrets]
+            backend = "encrypted"
+            key_file = "{}"
+            "#,
+            dir.path().display(),
+            key_file.display()
+        );
+
+        let opt = file::get_options_from_toml(&config).unwrap();
+        assert_eq!(
+            opt.migrate_secrets().unwrap(),
+            vec![secrets::CREDENTIALS_SECRET, secrets::PAIRING_TOKEN]
+        );
+
+        // The message hub registered the device again, renewing the credentials secret
+        let store = opt.secret_store().unwrap();
+        store.write(secrets::CREDENTIALS_SECRET, "renewed").unwrap();
+
+        // After a restart the stale secret of the configuration file is ignored
+        let mut opt = file::get_options_from_toml(&config).unwrap();
+        opt.migrate_secrets().unwrap();
+
+        assert_eq!(opt.obtain_credential_secret().await.unwrap(), "renewed");
+        assert_eq!(
+            store.read(secrets::CREDENTIALS_SECRET).unwrap().as_deref(),
+            Some("renewed")
+        );
+
+        // Provisioning replaces the stored secrets
+        opt.credentials_secret = Some("provisioned".to_string());
+        opt.store_secrets().unwrap();
+        assert_eq!(
+            store.read(secrets::CREDENTIALS_SECRET).unwrap().as_deref(),
+            Some("provisioned")
+        );
+    }
+
+    /// Pairing API rejecting every request, returns its URL.
+    fn spawn_rejecting_pairing_api() -> String {
+        spawn_failing_pairing_api(401)
+    }
+
+    /// Pairing API answering every request with the given status code, returns its URL.
+    fn spawn_failing_pairing_api(status: u16) -> String {
+        use std::convert::Infallible;
+
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Response, Server, StatusCode};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let status = StatusCode::from_u16(status).unwrap();
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(status.to_string()))
+                        .unwrap(),
+                )
+            }))
+        });
+
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+
+        format!("http://{}", address)
+    }
+
+    fn rejected_options(pairing_url: String, store_directory: &Path) -> MessageHubOptions {
+        MessageHubOptions {
+            realm: "realm".to_string(),
+            device_id: Some("device_id".to_string()),
+            pairing_url,
+            pairing_token: Some("42".to_string()),
+            store_directory: store_directory.to_path_buf(),
+            ..options()
+        }
+    }
+
+    #[tokio::test]
+    async fn register_again_when_rejected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join(CREDENTIAL_FILE), "rejected").unwrap();
+
+        let mut opt = rejected_options(spawn_rejecting_pairing_api(), dir.path());
+
+        let mut calls = 0;
+        let res = opt
+            .connect_with(|options| {
+                calls += 1;
+                let first = calls == 1;
+
+                async move {
+                    if first {
+                        AstarteDeviceSdk::new(&options).await.map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await;
+
+        assert!(res.is_ok(), "error connecting {:?}", res);
+        assert_eq!(calls, 2);
+
+        // The mocked registration returns an empty secret
+        assert_eq!(opt.credentials_secret.as_deref(), Some(""));
+        let stored = fs::read_to_string(dir.path().join(CREDENTIAL_FILE)).unwrap();
+        assert_eq!(stored, "");
+    }
+
+    #[tokio::test]
+    async fn renewed_credential_secret_replaces_the_configured_one() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pairing_url = spawn_rejecting_pairing_api();
+
+        let mut opt = rejected_options(pairing_url.clone(), dir.path());
+        opt.credentials_secret = Some("rejected".to_string());
+
+        let mut calls = 0;
+        let res = opt
+            .connect_with(|options| {
+                calls += 1;
+                let first = calls == 1;
+
+                async move {
+                    if first {
+                        AstarteDeviceSdk::new(&options).await.map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await;
+
+        assert!(res.is_ok(), "error connecting {:?}", res);
+        assert_eq!(calls, 2);
+
+        // The mocked registration returns an empty secret
+        fs::write(dir.path().join(CREDENTIAL_FILE), "renewed").unwrap();
+
+        // After a restart the renewed secret is preferred over the rejected configured one
+        let mut opt = rejected_options(pairing_url.clone(), dir.path());
+        opt.credentials_secret = Some("rejected".to_string());
+        assert_eq!(opt.obtain_credential_secret().await.unwrap(), "renewed");
+
+        // A new configured secret is preferred again
+        let mut opt = rejected_options(pairing_url, dir.path());
+        opt.credentials_secret = Some("configured".to_string());
+        assert_eq!(opt.obtain_credential_secret().await.unwrap(), "configured");
+    }
+
+    #[tokio::test]
+    async fn bounded_registration_attempts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pairing_url = spawn_rejecting_pairing_api();
+
+        let mut opt = rejected_options(pairing_url.clone(), dir.path());
+        opt.credentials_secret = Some("rejected".to_string());
+
+        let mut calls = 0;
+        let res = opt
+            .connect_with(|options| {
+                calls += 1;
+
+                async move { AstarteDeviceSdk::new(&options).await.map(|_| ()) }
+            })
+            .await;
+
+        assert!(matches!(
+            res,
+            Err(AstarteMessageHubError::RegistrationFailed { attempts, ref source })
+                if attempts == MAX_REGISTRATION_ATTEMPTS && is_authentication_error(source).unwrap()
+        ));
+        assert_eq!(calls, MAX_REGISTRATION_ATTEMPTS + 1);
+
+        // Without a pairing token the device cannot be registered again
+        let mut opt = rejected_options(pairing_url, dir.path());
+        opt.credentials_secret = Some("rejected".to_string());
+        opt.pairing_token = None;
+        fs::remove_file(dir.path().join(CREDENTIAL_FILE)).unwrap();
+
+        let res = opt
+            .connect_with(
+                |options| async move { AstarteDeviceSdk::new(&options).await.map(|_| ()) },
+            )
+            .await;
+
+        assert!(matches!(
+            res,
+            Err(AstarteMessageHubError::CredentialsRejected(_))
+        ));
+        assert!(!dir.path().join(CREDENTIAL_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn authentication_errors_of_the_pairing_api() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        for (status, expected) in [(401, true), (403, true), (404, false), (500, false)] {
+            let mut opt = rejected_options(spawn_failing_pairing_api(status), dir.path());
+            opt.credentials_secret = Some("rejected".to_string());
+
+            let err = match AstarteDeviceSdk::new(&opt.astarte_options().unwrap()).await {
+                Ok(_) => panic!("the pairing API accepted the credentials secret"),
+                Err(err) => err,
+            };
+
+            assert_eq!(pairing_status(&err).unwrap(), Some(status), "{:?}", err);
+            assert_eq!(
+                is_authentication_error(&err).unwrap(),
+                expected,
+                "{:?}",
+                err
+            );
+        }
+
+        // The other pairing errors have no status code
+        let mut opt = rejected_options("http://127.0.0.1:1".to_string(), dir.path());
+        opt.credentials_secret = Some("rejected".to_string());
+
+        let err = match AstarteDeviceSdk::new(&opt.astarte_options().unwrap()).await {
+            Ok(_) => panic!("the pairing API is not reachable"),
+            Err(err) => err,
+        };
+
+        assert!(
+            matches!(
+                err,
+                AstarteError::OptionsError(AstarteOptionsError::PairingError(_))
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(pairing_status(&err).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut opt = rejected_options("http://localhost".to_string(), dir.path());
+
+        let mut calls = 0;
+        let res: Result<(), _> = opt
+            .connect_with(|_| {
+                calls += 1;
+
+                async { Err(AstarteError::Unreported) }
+            })
+            .await;
+
+        assert!(matches!(
+            res,
+            Err(AstarteMessageHubError::AstarteError(err))
+                if matches!(*err, AstarteError::Unreported)
+        ));
+        assert_eq!(calls, 1);
+        assert!(!is_authentication_error(&AstarteError::Unreported).unwrap());
+    }
+
+    #[test]
+    fn check_files_permissions() {
+        use std::fs::Permissions;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let config_file = dir.path().join(CONFIG_FILE_NAMES[0]);
+        let credentials_file = dir.path().join(CREDENTIAL_FILE);
+
+        let mut options = file::get_options_from_toml(
+            r#"
+            realm = "1"
+            pairing_url = "2"
+            pairing_token = "3"
+            grpc_socket_port = 4
+            "#,
+        )
+        .unwrap();
+        options.store_directory = dir.path().to_path_buf();
+
+        // Missing files are not checked
+        assert!(options.check_permissions(Some(&config_file)).is_ok());
+
+        fs::write(&config_file, "").unwrap();
+        fs::set_permissions(&config_file, Permissions::from_mode(0o644)).unwrap();
+        assert!(options.check_permissions(Some(&config_file)).is_ok());
+
+        fs::set_permissions(&config_file, Permissions::from_mode(0o666)).unwrap();
+        assert!(matches!(
+            options.check_permissions(Some(&config_file)),
+            Err(AstarteMessageHubError::InsecurePermissions(path)) if path == config_file
+        ));
+        assert!(options.check_permissions(None).is_ok());
+
+        persist::write_private(&credentials_file, "secret").unwrap();
+        assert!(options.check_permissions(None).is_ok());
+
+        fs::set_permissions(&credentials_file, Permissions::from_mode(0o646)).unwrap();
+        assert!(matches!(
+            options.check_permissions(None),
+            Err(AstarteMessageHubError::InsecurePermissions(path)) if path == credentials_file
+        ));
+    }
+
+    #[tokio::test]
+    async fn bind_unix_socket_mode() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("message-hub.sock");
+
+        let _listener = bind_unix_socket(&path, Some(0o660), None, None).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // The socket left by the previous bind is replaced
+        let _listener = bind_unix_socket(&path, None, None, None).unwrap();
+        let umask = {
+            let _guard = UMASK_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+            let umask = stat::umask(Mode::from_bits_truncate(0o077));
+            stat::umask(umask);
+            umask
+        };
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o777 & !umask.bits());
     }
 
     #[tokio::test]
     async fn load_toml_config() {
         let expected = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("42".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
 
         let dir = tempfile::TempDir::new().unwrap();
@@ -535,7 +1863,7 @@ mod test {
 
         let path = Some(path.to_string_lossy().to_string());
 
-        let options = MessageHubOptions::get(path, None).await;
+        let options = MessageHubOptions::get(path, None, &ConfigOverrides::new()).await;
 
         assert!(options.is_ok(), "error loading config {:?}", options);
         assert_eq!(options.unwrap(), expected);
@@ -544,15 +1872,8 @@ mod test {
     #[tokio::test]
     async fn load_from_store_path() {
         let mut expected = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
             pairing_token: Some("42".to_string()),
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
-            store_directory: MessageHubOptions::default_store_directory(),
+            ..options()
         };
 
         let dir = tempfile::TempDir::new().unwrap();
@@ -561,7 +1882,7 @@ mod test {
 
         fs::write(&path, toml::to_string(&expected).unwrap()).unwrap();
 
-        let options = MessageHubOptions::get(None, Some(dir.path())).await;
+        let options = MessageHubOptions::get(None, Some(dir.path()), &ConfigOverrides::new()).await;
 
         // Set the store directory to the passed value
         expected.store_directory = dir.path().to_path_buf();
@@ -570,6 +1891,225 @@ mod test {
         assert_eq!(options.unwrap(), expected);
     }
 
+    #[test]
+    fn load_layered_options() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAMES[0]);
+
+        fs::write(
+            &path,
+            r#"
+            realm = "file"
+            pairing_url = "http://file"
+            credentials_secret = "secret"
+            grpc_socket_port = 1
+            [queue]
+            max_size = 1
+            "#,
+        )
+        .unwrap();
+
+        let mut overrides = ConfigOverrides::from_vars(vec![
+            ("MSGHUB_REALM".to_string(), "env".to_string()),
+            ("MSGHUB_GRPC_SOCKET_PORT".to_string(), "2".to_string()),
+        ])
+        .unwrap();
+        let mut cli = ConfigOverrides::new();
+        cli.set_assignment("grpc_socket_port=3").unwrap();
+        overrides.extend(cli);
+
+        let path = path.to_string_lossy().to_string();
+        let options = MessageHubOptions::load(Some(&path), Some(dir.path()), &overrides).unwrap();
+
+        assert_eq!(options.realm, "env");
+        assert_eq!(options.pairing_url, "http://file");
+        assert_eq!(options.grpc_socket_port, Some(3));
+        assert_eq!(options.queue.max_size, 1);
+        assert_eq!(
+            options.queue.retry_interval,
+            QueueOptions::default().retry_interval
+        );
+        assert_eq!(options.store_directory, dir.path());
+
+        // The options can be provided only by the overrides
+        let empty_dir = tempfile::TempDir::new().unwrap();
+        assert!(
+            MessageHubOptions::load(None, Some(empty_dir.path()), &ConfigOverrides::new()).is_err()
+        );
+
+        let mut overrides = ConfigOverrides::new();
+        // A string made only of digits is quoted, not to be parsed as an integer
+        overrides.set("realm", r#""1""#).unwrap();
+        overrides.set("pairing_url", "http://env").unwrap();
+        overrides.set("credentials_secret", "secret").unwrap();
+        overrides.set("grpc_socket_port", "4").unwrap();
+
+        let options = MessageHubOptions::load(None, Some(empty_dir.path()), &overrides).unwrap();
+        assert_eq!(options.realm, "1");
+        assert_eq!(options.grpc_socket_port, Some(4));
+
+        // A misspelled option is rejected
+        for key in ["grpc_socket_prot", "queue.maxsize"] {
+            let mut overrides = overrides.clone();
+            overrides.set(key, "1").unwrap();
+
+            let res = MessageHubOptions::load(None, Some(empty_dir.path()), &overrides);
+            assert!(
+                matches!(res, Err(AstarteMessageHubError::ConfigFileError(_))),
+                "{} accepted",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn provisioning_from_overrides() {
+        let overrides = ConfigOverrides::from_vars(vec![
+            (
+                "MSGHUB_PROVISIONING__HTTP__ENABLED".to_string(),
+                "false".to_string(),
+            ),
+            (
+                "MSGHUB_PROVISIONING__PROTOBUF__ADDRESS".to_string(),
+                "127.0.0.1:40043".to_string(),
+            ),
+        ])
+        .unwrap();
+
+        let provisioning = MessageHubOptions::provisioning_options(&overrides).unwrap();
+        assert!(!provisioning.http.enabled);
+        assert_eq!(
+            provisioning.http_address(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 40041))
+        );
+        assert!(provisioning.protobuf.enabled);
+        assert_eq!(
+            provisioning.protobuf_address(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 40043))
+        );
+
+        let provisioning =
+            MessageHubOptions::provisioning_options(&ConfigOverrides::new()).unwrap();
+        assert_eq!(provisioning, ProvisioningOptions::default());
+    }
+
+    #[tokio::test]
+    async fn provisioning_errors() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut overrides = ConfigOverrides::new();
+        overrides.set("provisioning.http.enabled", "false").unwrap();
+        overrides
+            .set("provisioning.protobuf.enabled", "false")
+            .unwrap();
+
+        let res = MessageHubOptions::get(None, Some(dir.path()), &overrides).await;
+        assert!(res.is_err());
+
+        // The address is already in use
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let mut overrides = ConfigOverrides::new();
+        overrides
+            .set("provisioning.http.address", &address)
+            .unwrap();
+        overrides
+            .set("provisioning.protobuf.enabled", "false")
+            .unwrap();
+
+        let res = MessageHubOptions::get(None, Some(dir.path()), &overrides).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn secret_options() {
+        for key in [
+            "credentials_secret",
+            "pairing_token",
+            " pairing_token ",
+            "admin",
+            "admin.tokens",
+            "auth",
+            "auth.tokens",
+            "auth.tokens.550e8400-e29b-41d4-a716-446655440000",
+        ] {
+            assert!(is_secret_option(key), "{}", key);
+        }
+
+        for key in [
+            "realm",
+            "admin.enabled",
+            "auth.enabled",
+            "pairing_url",
+            "auth.tokens_file",
+        ] {
+            assert!(!is_secret_option(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let mut options = file::get_options_from_toml(
+            r#"
+            realm = "1"
+            pairing_url = "2"
+            credentials_secret = "secret"
+            pairing_token = "token"
+            grpc_socket_port = 3
+            [admin]
+            tokens = ["admin_token"]
+            [auth.tokens]
+            "node" = "node_token"
+            "#,
+        )
+        .unwrap();
+
+        let printed = toml::to_string(&options.redacted()).unwrap();
+
+        for secret in [
+            "\"secret\"",
+            "\"token\"",
+            "\"admin_token\"",
+            "\"node_token\"",
+        ] {
+            assert!(!printed.contains(secret), "{} not redacted", secret);
+        }
+
+        // Every secret option is redacted
+        fn assert_redacted(value: &toml::Value, path: &str) {
+            match value {
+                toml::Value::String(value) => assert_eq!(value, REDACTED, "{}", path),
+                toml::Value::Array(values) => {
+                    values.iter().for_each(|value| assert_redacted(value, path))
+                }
+                toml::Value::Table(table) => table
+                    .values()
+                    .for_each(|value| assert_redacted(value, path)),
+                _ => panic!("{} is not a secret", path),
+            }
+        }
+
+        let table: toml::Value = toml::from_str(&printed).unwrap();
+        for path in SECRET_OPTIONS {
+            let value = path
+                .split('.')
+                .try_fold(&table, |value, name| value.get(name))
+                .unwrap_or_else(|| panic!("{} not printed", path));
+
+            assert_redacted(value, path);
+        }
+
+        let redacted = file::get_options_from_toml(&printed).unwrap();
+        assert_eq!(redacted.credentials_secret.as_deref(), Some(REDACTED));
+        assert_eq!(redacted.pairing_token.as_deref(), Some(REDACTED));
+        assert_eq!(redacted.admin.tokens, vec![REDACTED.to_string()]);
+        assert_eq!(redacted.auth.tokens["node"], REDACTED);
+
+        options.credentials_secret = None;
+        assert_eq!(options.redacted().credentials_secret, None);
+    }
+
     /// Make sure the example config is keep in sync with the code
     #[test]
     fn deserialize_example_config() {
@@ -583,13 +2123,12 @@ mod test {
         let expected = MessageHubOptions {
             realm: "example_realm".to_string(),
             device_id: Some("YOUR_UNIQUE_DEVICE_ID".to_string()),
-            credentials_secret: None,
             pairing_url: "https://api.astarte.EXAMPLE.COM".to_string(),
             pairing_token: Some("YOUR_PAIRING_TOKEN".to_string()),
             interfaces_directory: Some(PathBuf::from("/usr/share/message-hub/astarte-interfaces/")),
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 50051,
+            grpc_socket_port: Some(50051),
             store_directory: PathBuf::from("/var/lib/message-hub"),
+            ..options()
         };
 
         assert_ne!(opts, expected);
@@ -597,21 +2136,14 @@ mod test {
 
     #[tokio::test]
     async fn obtain_configured_device_id() {
-        let expected = "2".to_string();
+        let expected = "2TBn-jNESuuHamE2Zo1anA".to_string();
 
         let dir = tempfile::TempDir::new().unwrap();
         fs::write(dir.path().join(CREDENTIAL_FILE), &expected).unwrap();
 
         let mut opt = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("2".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
             store_directory: dir.path().to_path_buf(),
+            ..options()
         };
 
         let device_id = opt.obtain_device_id().await;
@@ -626,21 +2158,15 @@ mod test {
 
     #[tokio::test]
     async fn obtain_device_id_configured_none() {
-        let expected = "mock-id".to_string();
+        let expected = MOCK_HARDWARE_ID.to_string();
 
         let dir = tempfile::TempDir::new().unwrap();
         fs::write(dir.path().join(CREDENTIAL_FILE), &expected).unwrap();
 
         let mut opt = MessageHubOptions {
-            realm: "1".to_string(),
             device_id: None,
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
             store_directory: dir.path().to_path_buf(),
+            ..options()
         };
 
         let device_id = opt.obtain_device_id().await;
@@ -654,31 +2180,92 @@ mod test {
     }
 
     #[tokio::test]
-    async fn obtain_device_id_configured_some_but_empty() {
-        let expected = "mock-id".to_string();
+    async fn obtain_device_id_from_providers() {
+        let toml_str = r#"
+            realm = "1"
+            pairing_url = "3"
+            credentials_secret = "secret"
+            grpc_socket_port = 655
+            [[device_id_providers]]
+            type = "dmi"
+            [[device_id_providers]]
+            type = "static"
+            device_id = "iGMT4TuKU3KbkAya7hmeXQ"
+            [[device_id_providers]]
+            type = "edgehog"
+            namespace = "namespace"
+            [queue]
+            max_size = 1
+        "#;
+
+        let mut opt = file::get_options_from_toml(toml_str).unwrap();
+        assert_eq!(
+            opt.device_id_providers,
+            vec![
+                DeviceIdSource::Dmi,
+                DeviceIdSource::Static {
+                    device_id: "iGMT4TuKU3KbkAya7hmeXQ".to_string()
+                },
+                DeviceIdSource::Edgehog {
+                    namespace: "namespace".to_string()
+                },
+            ]
+        );
 
-        let dir = tempfile::TempDir::new().unwrap();
-        fs::write(dir.path().join(CREDENTIAL_FILE), &expected).unwrap();
+        // The providers are written before the tables
+        let serialized = toml::to_string(&opt).unwrap();
+        assert_eq!(file::get_options_from_toml(&serialized).unwrap(), opt);
 
-        let mut opt = MessageHubOptions {
-            realm: "1".to_string(),
-            device_id: Some("".to_string()),
-            pairing_url: "3".to_string(),
-            credentials_secret: None,
-            pairing_token: None,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: 655,
-            store_directory: dir.path().to_path_buf(),
-        };
+        // The DMI table is not readable without root privileges
+        if DeviceIdChain::from_sources(&[DeviceIdSource::Dmi])
+            .device_id()
+            .await
+            .is_err()
+        {
+            assert_eq!(
+                opt.obtain_device_id().await.unwrap(),
+                "iGMT4TuKU3KbkAya7hmeXQ"
+            );
+        }
 
-        let device_id = opt.obtain_device_id().await;
+        let mut overrides = ConfigOverrides::new();
+        overrides
+            .set(
+                "device_id_providers",
+                r#"[{ type = "static", device_id = "" }]"#,
+            )
+            .unwrap();
+        assert!(file::get_options_with_overrides(toml_str, &overrides).is_err());
+
+        opt.device_id_providers = vec![DeviceIdSource::Static {
+            device_id: String::new(),
+        }];
+        assert!(matches!(
+            opt.validate(),
+            Err(ConfigValidationError::MissingField(
+                "device_id_providers.device_id"
+            ))
+        ));
+    }
 
-        assert!(
-            device_id.is_ok(),
-            "error obtaining stored credential {:?}",
-            device_id
-        );
-        assert_eq!(device_id.unwrap(), expected);
+    #[tokio::test]
+    async fn obtain_generated_device_id() {
+        let namespace = Uuid::parse_str("bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1").unwrap();
+
+        let mut opt = file::get_options_from_toml(
+            r#"
+            realm = "1"
+            pairing_url = "3"
+            credentials_secret = "secret"
+            grpc_socket_port = 655
+            device_id_namespace = "bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(opt.device_id_namespace, Some(namespace));
+
+        let expected = device_id::generate_device_id(&namespace, MOCK_HARDWARE_ID);
+        assert_eq!(opt.obtain_device_id().await.unwrap(), expected);
+        assert!(opt.validate().is_ok());
     }
 }
@@ -0,0 +1,279 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Override the options of the configuration file with the environment and the command line.
+//!
+//! The configuration is layered, each layer replacing the options set by the previous ones: the
+//! defaults, the configuration file, the `MSGHUB_*` environment variables and the command line
+//! flags. An option is identified by its dotted path in the configuration file, e.g.
+//! `queue.max_size`, which corresponds to the `MSGHUB_QUEUE__MAX_SIZE` environment variable.
+
+use std::path::PathBuf;
+
+use toml::value::Table;
+use toml::Value;
+
+use crate::error::AstarteMessageHubError;
+
+/// Prefix of the environment variables overriding the options.
+pub const ENV_PREFIX: &str = "MSGHUB_";
+
+/// Separator of the tables in the name of the environment variables.
+const ENV_TABLE_SEPARATOR: &str = "__";
+
+/// Options overriding the ones read from the configuration file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConfigOverrides {
+    values: Vec<(Vec<String>, Value)>,
+}
+
+impl ConfigOverrides {
+    /// Create an empty set of overrides.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the overrides from the `MSGHUB_*` environment variables.
+    pub fn from_env() -> Result<Self, AstarteMessageHubError> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Read the overrides from the variables with the `MSGHUB_` prefix.
+    ///
+    /// The tables are separated by a double underscore, e.g. `MSGHUB_SESSION__BUFFER_SIZE`.
+    pub fn from_vars<I>(vars: I) -> Result<Self, AstarteMessageHubError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides = Self::new();
+
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) if !key.is_empty() => key,
+                _ => continue,
+            };
+
+            let key = key
+                .to_lowercase()
+                .split(ENV_TABLE_SEPARATOR)
+                .collect::<Vec<_>>()
+                .join(".");
+
+            overrides.set(&key, &value)?;
+        }
+
+        Ok(overrides)
+    }
+
+    /// Override an option with a `KEY=VALUE` assignment, like the ones passed to `--set`.
+    pub fn set_assignment(&mut self, assignment: &str) -> Result<(), AstarteMessageHubError> {
+        let (key, value) = assignment.split_once('=').ok_or_else(|| {
+            AstarteMessageHubError::FatalError(format!(
+                "Invalid option override '{}', expected KEY=VALUE",
+                assignment
+            ))
+        })?;
+
+        self.set(key.trim(), value.trim())
+    }
+
+    /// Override the option with the dotted path `key`.
+    ///
+    /// The value is parsed as a TOML value, falling back to a string if it is not valid TOML, so
+    /// a string made only of digits must be quoted, e.g. `realm="42"`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), AstarteMessageHubError> {
+        let value = parse_value(value).unwrap_or_else(|| Value::String(value.to_string()));
+
+        self.set_value(key, value)
+    }
+
+    /// Override the option with the dotted path `key` with a TOML value.
+    pub fn set_value(&mut self, key: &str, value: Value) -> Result<(), AstarteMessageHubError> {
+        let path: Vec<String> = key.split('.').map(str::to_string).collect();
+
+        if path.iter().any(String::is_empty) {
+            return Err(AstarteMessageHubError::FatalError(format!(
+                "Invalid option name '{}'",
+                key
+            )));
+        }
+
+        self.values.retain(|(current, _)| *current != path);
+        self.values.push((path, value));
+
+        Ok(())
+    }
+
+    /// Add the overrides of a following layer, replacing the options set by both.
+    pub fn extend(&mut self, other: ConfigOverrides) {
+        for (path, value) in other.values {
+            self.values.retain(|(current, _)| *current != path);
+            self.values.push((path, value));
+        }
+    }
+
+    /// Whether no option is overridden.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The overridden store directory, if any.
+    pub fn store_directory(&self) -> Option<PathBuf> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(path, _)| path.len() == 1 && path[0] == "store_directory")
+            .and_then(|(_, value)| value.as_str())
+            .map(PathBuf::from)
+    }
+
+    /// Apply the overrides to the table of a configuration file.
+    pub fn apply(&self, table: &mut Table) -> Result<(), AstarteMessageHubError> {
+        for (path, value) in &self.values {
+            let (name, tables) = path
+                .split_last()
+                .expect("the path of an option is not empty");
+
+            let mut current = &mut *table;
+            for table_name in tables {
+                let entry = current
+                    .entry(table_name.clone())
+                    .or_insert_with(|| Value::Table(Table::new()));
+
+                current = entry.as_table_mut().ok_or_else(|| {
+                    AstarteMessageHubError::FatalError(format!(
+                        "Cannot override '{}', '{}' is not a table",
+                        path.join("."),
+                        table_name
+                    ))
+                })?;
+            }
+
+            current.insert(name.clone(), value.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the value of an option as a TOML value.
+fn parse_value(value: &str) -> Option<Value> {
+    toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn read_env_overrides() {
+        let overrides = ConfigOverrides::from_vars(vars(&[
+            ("MSGHUB_REALM", r#""42""#),
+            (
+                "MSGHUB_PAIRING_URL",
+                "https://api.astarte.example.com/pairing",
+            ),
+            ("MSGHUB_GRPC_SOCKET_PORT", "50051"),
+            ("MSGHUB_GRPC_UNIX_SOCKET_MODE", "0o660"),
+            ("MSGHUB_QUEUE__MAX_SIZE", "10"),
+            ("MSGHUB_ADMIN__TOKENS", r#"["token"]"#),
+            ("MSGHUB_STORE_DIRECTORY", "/var/lib/message-hub"),
+            ("OTHER_VARIABLE", "1"),
+        ]))
+        .unwrap();
+
+        let mut table = Table::new();
+        overrides.apply(&mut table).unwrap();
+
+        let expected = toml::from_str::<Table>(
+            r#"
+            realm = "42"
+            pairing_url = "https://api.astarte.example.com/pairing"
+            grpc_socket_port = 50051
+            grpc_unix_socket_mode = 0o660
+            store_directory = "/var/lib/message-hub"
+            [queue]
+            max_size = 10
+            [admin]
+            tokens = ["token"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(table, expected);
+        assert_eq!(
+            overrides.store_directory(),
+            Some(PathBuf::from("/var/lib/message-hub"))
+        );
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        let mut overrides = ConfigOverrides::new();
+
+        assert!(overrides.set_assignment("realm").is_err());
+        assert!(overrides.set_assignment("queue..max_size=1").is_err());
+
+        overrides.set("queue", "1").unwrap();
+        overrides.set("queue.max_size", "1").unwrap();
+
+        let mut table = Table::new();
+        assert!(overrides.apply(&mut table).is_err());
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let mut table = toml::from_str::<Table>(
+            r#"
+            realm = "file"
+            pairing_url = "http://file"
+            [session]
+            buffer_size = 1
+            "#,
+        )
+        .unwrap();
+
+        let mut overrides = ConfigOverrides::from_vars(vars(&[
+            ("MSGHUB_REALM", "env"),
+            ("MSGHUB_DEVICE_ID", "env"),
+        ]))
+        .unwrap();
+
+        let mut cli = ConfigOverrides::new();
+        cli.set_assignment("realm=cli").unwrap();
+        cli.set_assignment("session.buffer_size = 2").unwrap();
+        overrides.extend(cli);
+
+        overrides.apply(&mut table).unwrap();
+
+        assert_eq!(table["realm"].as_str(), Some("cli"));
+        assert_eq!(table["device_id"].as_str(), Some("env"));
+        assert_eq!(table["pairing_url"].as_str(), Some("http://file"));
+        assert_eq!(table["session"]["buffer_size"].as_integer(), Some(2));
+    }
+}
@@ -20,26 +20,67 @@
 
 //! Provides a Protobuf API to set The Message Hub configurations
 
-use std::io::Write;
-use std::num::TryFromIntError;
+use std::net::SocketAddr;
 use std::path::Path;
 
+use log::error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tokio_stream::Stream;
+use tonic::transport::server::Connected;
 use tonic::transport::Server;
 use tonic::{Code, Request, Response, Status};
 
-use crate::config::MessageHubOptions;
+use crate::config::provisioning::{options_from_json, ProvisionedFile, ProvisioningError};
+use crate::config::{bind_unix_socket, MessageHubOptions};
+use crate::error::AstarteMessageHubError;
+use crate::health::{health_reporter, HealthReporter, MessageHubService};
 use crate::proto_message_hub;
+use crate::proto_message_hub::message_hub_config_server::MessageHubConfigServer;
 
 #[derive(Debug)]
 struct AstarteMessageHubConfig {
     configuration_ready_channel: Sender<()>,
-    toml_file: String,
+    toml_file: ProvisionedFile,
 }
 
 /// Provides a Protobuf API to set The Message Hub configurations
 pub struct ProtobufConfigProvider {
     shutdown_channel: Sender<()>,
+    health: HealthReporter,
+}
+
+impl From<ProvisioningError> for Status {
+    fn from(err: ProvisioningError) -> Self {
+        let code = match err {
+            ProvisioningError::Invalid(_) => Code::InvalidArgument,
+            ProvisioningError::NotFound => Code::NotFound,
+            ProvisioningError::Storage(_) => Code::Internal,
+        };
+
+        Status::new(code, err.to_string())
+    }
+}
+
+/// Convert the configuration message in the options, through their common field names.
+fn options_from_message(
+    message: proto_message_hub::ConfigMessage,
+) -> Result<MessageHubOptions, ProvisioningError> {
+    let value =
+        serde_json::to_value(message).map_err(|err| ProvisioningError::Invalid(err.to_string()))?;
+
+    options_from_json(value)
+}
+
+/// Convert the options in a configuration message, through their common field names.
+fn message_from_options(
+    options: &MessageHubOptions,
+) -> Result<proto_message_hub::ConfigMessage, ProvisioningError> {
+    serde_json::to_value(options)
+        .and_then(serde_json::from_value)
+        .map_err(|err| ProvisioningError::Storage(err.to_string()))
 }
 
 #[tonic::async_trait]
@@ -49,46 +90,33 @@ impl proto_message_hub::message_hub_config_server::MessageHubConfig for AstarteM
         &self,
         request: Request<proto_message_hub::ConfigMessage>,
     ) -> Result<Response<pbjson_types::Empty>, Status> {
-        let req = request.into_inner();
-
-        // Protobuf version 3 only supports u32
-        let port: u16 = req
-            .grpc_socket_port
-            .try_into()
-            .map_err(|err: TryFromIntError| {
-                Status::new(Code::InvalidArgument, format!("Invalid grpc port: {}", err))
-            })?;
-
-        let message_hub_options = MessageHubOptions {
-            realm: req.realm,
-            device_id: req.device_id,
-            credentials_secret: req.credentials_secret,
-            pairing_url: req.pairing_url,
-            pairing_token: req.pairing_token,
-            interfaces_directory: None,
-            astarte_ignore_ssl: false,
-            grpc_socket_port: port,
-            store_directory: MessageHubOptions::default_store_directory(),
-        };
+        let message_hub_options = options_from_message(request.into_inner())?;
+        let message_hub_options = self.toml_file.prepare(message_hub_options)?;
 
-        if let Err(err) = message_hub_options.validate() {
-            return Err(Status::new(
-                Code::InvalidArgument,
-                format!("Invalid configuration: {}.", err),
-            ));
-        }
+        self.toml_file.write(&message_hub_options)?;
 
-        let mut file = std::fs::File::create(Path::new(&self.toml_file))?;
+        let _ = self.configuration_ready_channel.send(()).await;
 
-        let result = toml::to_string(&message_hub_options);
-        if let Err(e) = result {
-            return Err(Status::new(Code::InvalidArgument, e.to_string()));
-        }
+        Ok(Response::new(pbjson_types::Empty {}))
+    }
 
-        let cfg = result.unwrap();
+    /// Protobuf API returning the provided configuration, with the secrets redacted
+    async fn get_config(
+        &self,
+        _request: Request<pbjson_types::Empty>,
+    ) -> Result<Response<proto_message_hub::ConfigMessage>, Status> {
+        let message_hub_options = self.toml_file.read_redacted()?;
 
-        write!(file, "{cfg}")?;
-        let _ = self.configuration_ready_channel.send(()).await;
+        Ok(Response::new(message_from_options(&message_hub_options)?))
+    }
+
+    /// Protobuf API validating a configuration without setting it
+    async fn validate_config(
+        &self,
+        request: Request<proto_message_hub::ConfigMessage>,
+    ) -> Result<Response<pbjson_types::Empty>, Status> {
+        let message_hub_options = options_from_message(request.into_inner())?;
+        self.toml_file.prepare(message_hub_options)?;
 
         Ok(Response::new(pbjson_types::Empty {}))
     }
@@ -97,34 +125,96 @@ impl proto_message_hub::message_hub_config_server::MessageHubConfig for AstarteM
 impl ProtobufConfigProvider {
     /// Start a new Protobuf API Server to allow a third party to feed the Message Hub
     /// configurations
+    ///
+    /// Returns an error if the address is not valid or cannot be bound.
     pub async fn new(
         address: &str,
         configuration_ready_channel: Sender<()>,
         toml_file: &str,
-    ) -> ProtobufConfigProvider {
-        use crate::proto_message_hub::message_hub_config_server::MessageHubConfigServer;
+    ) -> Result<ProtobufConfigProvider, AstarteMessageHubError> {
+        let addr: SocketAddr = address.parse().map_err(|err| {
+            AstarteMessageHubError::FatalError(format!(
+                "Invalid address {} for the protobuf service: {}",
+                address, err
+            ))
+        })?;
+        let listener = TcpListener::bind(addr).await?;
 
-        let addr = address.parse().unwrap();
+        Ok(Self::serve(
+            TcpListenerStream::new(listener),
+            configuration_ready_channel,
+            toml_file,
+        )
+        .await)
+    }
+
+    /// Start a new Protobuf API Server listening on a Unix domain socket
+    pub async fn with_unix_socket(
+        path: &Path,
+        configuration_ready_channel: Sender<()>,
+        toml_file: &str,
+    ) -> Result<ProtobufConfigProvider, AstarteMessageHubError> {
+        let listener = bind_unix_socket(path, None, None, None)?;
+
+        Ok(Self::serve(
+            UnixListenerStream::new(listener),
+            configuration_ready_channel,
+            toml_file,
+        )
+        .await)
+    }
+
+    async fn serve<I, IO>(
+        incoming: I,
+        configuration_ready_channel: Sender<()>,
+        toml_file: &str,
+    ) -> ProtobufConfigProvider
+    where
+        I: Stream<Item = Result<IO, std::io::Error>> + Send + 'static,
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+        IO::ConnectInfo: Clone + Send + Sync + 'static,
+    {
         let service = AstarteMessageHubConfig {
             configuration_ready_channel,
-            toml_file: toml_file.to_string(),
+            toml_file: ProvisionedFile::new(toml_file),
         };
+        // The device is not connected to Astarte while waiting for the configuration
+        let (mut health, health_service) = health_reporter();
+        health
+            .set_service_status("", tonic_health::ServingStatus::NotServing)
+            .await;
+        health.set_not_serving::<MessageHubService>().await;
+        health
+            .set_serving::<MessageHubConfigServer<AstarteMessageHubConfig>>()
+            .await;
+
         let (tx, mut rx) = channel::<()>(1);
         tokio::spawn(async move {
-            Server::builder()
+            let result = Server::builder()
                 .add_service(MessageHubConfigServer::new(service))
-                .serve_with_shutdown(addr, async { rx.recv().await.unwrap() })
-                .await
-                .unwrap_or_else(|e| panic!("Unable to start protobuf service: {}", e));
+                .add_service(health_service)
+                .serve_with_incoming_shutdown(incoming, async {
+                    rx.recv().await;
+                })
+                .await;
+
+            if let Err(err) = result {
+                error!("Unable to run the protobuf service: {}", err);
+            }
         });
         ProtobufConfigProvider {
             shutdown_channel: tx,
+            health,
         }
     }
 
     /// Stop the Protobuf API Server
     pub async fn stop(&self) {
-        self.shutdown_channel.send(()).await.unwrap();
+        self.health
+            .clone()
+            .set_not_serving::<MessageHubConfigServer<AstarteMessageHubConfig>>()
+            .await;
+        let _ = self.shutdown_channel.send(()).await;
     }
 }
 
@@ -155,15 +245,16 @@ mod test {
         let (tx, _) = mpsc::channel(1);
         let config_server = AstarteMessageHubConfig {
             configuration_ready_channel: tx,
-            toml_file,
+            toml_file: ProvisionedFile::new(toml_file),
         };
         let msg = ConfigMessage {
             realm: "rpc_realm".to_string(),
-            device_id: Some("rpc_device_id".to_string()),
+            device_id: Some("JZqj2XGDSbCNFbB7dM4UxA".to_string()),
             credentials_secret: None,
             pairing_url: "rpc_pairing_url".to_string(),
             pairing_token: Some("rpc_pairing_token".to_string()),
-            grpc_socket_port: 42,
+            grpc_socket_port: Some(42),
+            ..Default::default()
         };
         let result = config_server.set_config(Request::new(msg)).await;
         assert!(result.is_ok());
@@ -185,15 +276,16 @@ mod test {
         let (tx, _) = mpsc::channel(1);
         let config_server = AstarteMessageHubConfig {
             configuration_ready_channel: tx,
-            toml_file,
+            toml_file: ProvisionedFile::new(toml_file),
         };
         let msg = ConfigMessage {
             realm: "".to_string(),
-            device_id: Some("rpc_device_id".to_string()),
+            device_id: Some("JZqj2XGDSbCNFbB7dM4UxA".to_string()),
             credentials_secret: None,
             pairing_url: "rpc_pairing_url".to_string(),
             pairing_token: Some("rpc_pairing_token".to_string()),
-            grpc_socket_port: 42,
+            grpc_socket_port: Some(42),
+            ..Default::default()
         };
         let result = config_server.set_config(Request::new(msg)).await;
         assert!(result.is_err());
@@ -213,7 +305,9 @@ mod test {
             .to_string();
 
         let (tx, mut rx) = mpsc::channel(1);
-        let server = ProtobufConfigProvider::new("127.0.0.1:1400", tx, &toml_file).await;
+        let server = ProtobufConfigProvider::new("127.0.0.1:1400", tx, &toml_file)
+            .await
+            .unwrap();
         let channel = Endpoint::from_static("http://localhost:1400")
             .connect()
             .await
@@ -221,11 +315,12 @@ mod test {
         let mut client = MessageHubConfigClient::new(channel);
         let msg = ConfigMessage {
             realm: "rpc_realm".to_string(),
-            device_id: Some("rpc_device_id".to_string()),
+            device_id: Some("JZqj2XGDSbCNFbB7dM4UxA".to_string()),
             credentials_secret: None,
             pairing_url: "rpc_pairing_url".to_string(),
             pairing_token: Some("rpc_pairing_token".to_string()),
-            grpc_socket_port: 42,
+            grpc_socket_port: Some(42),
+            ..Default::default()
         };
         let response = client.set_config(msg).await;
         assert!(response.is_ok());
@@ -235,4 +330,183 @@ mod test {
             .await
             .is_err());
     }
+
+    #[tokio::test]
+    async fn unix_socket_test() {
+        use crate::proto_message_hub::message_hub_config_client::MessageHubConfigClient;
+        use crate::proto_message_hub::ConfigMessage;
+
+        let dir = TempDir::new().unwrap();
+        let toml_file = dir
+            .path()
+            .join(CONFIG_FILE_NAMES[0])
+            .to_string_lossy()
+            .to_string();
+        let socket = dir.path().join("provisioning.sock");
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let server = ProtobufConfigProvider::with_unix_socket(&socket, tx, &toml_file)
+            .await
+            .unwrap();
+
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_| {
+                tokio::net::UnixStream::connect(socket.clone())
+            }))
+            .await
+            .unwrap();
+        let mut client = MessageHubConfigClient::new(channel);
+        let msg = ConfigMessage {
+            realm: "rpc_realm".to_string(),
+            device_id: Some("JZqj2XGDSbCNFbB7dM4UxA".to_string()),
+            credentials_secret: None,
+            pairing_url: "rpc_pairing_url".to_string(),
+            pairing_token: Some("rpc_pairing_token".to_string()),
+            grpc_socket_port: Some(42),
+            ..Default::default()
+        };
+        let response = client.set_config(msg).await;
+        assert!(response.is_ok());
+        assert!(rx.recv().await.is_some());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn bind_error_test() {
+        let (tx, _) = mpsc::channel(1);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        assert!(
+            ProtobufConfigProvider::new(&address, tx.clone(), "config.toml")
+                .await
+                .is_err()
+        );
+        assert!(ProtobufConfigProvider::new("invalid", tx, "config.toml")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn full_config_test() {
+        use crate::config::{DeviceIdSource, SecretBackend};
+        use crate::proto_message_hub::message_hub_config_server::MessageHubConfig;
+        use crate::proto_message_hub::{
+            AuthConfig, ConfigMessage, DeviceIdProviderConfig, QueueConfig, SecretsConfig,
+        };
+
+        let dir = TempDir::new().unwrap();
+        let toml_file = dir.path().join(CONFIG_FILE_NAMES[0]);
+
+        let (tx, _) = mpsc::channel(1);
+        let config_server = AstarteMessageHubConfig {
+            configuration_ready_channel: tx,
+            toml_file: ProvisionedFile::new(&toml_file),
+        };
+
+        let result = config_server
+            .get_config(Request::new(pbjson_types::Empty {}))
+            .await;
+        assert_eq!(result.unwrap_err().code(), Code::NotFound);
+
+        let msg = ConfigMessage {
+            realm: "rpc_realm".to_string(),
+            credentials_secret: Some("rpc_secret".to_string()),
+            pairing_url: "rpc_pairing_url".to_string(),
+            interfaces_directory: Some(dir.path().to_string_lossy().to_string()),
+            astarte_ignore_ssl: true,
+            grpc_unix_socket_path: Some("/run/message-hub.sock".to_string()),
+            grpc_unix_socket_mode: Some(0o660),
+            queue: Some(QueueConfig {
+                max_size: Some(10),
+                eviction_policy: Some("drop_newest".to_string()),
+                retry_interval: None,
+            }),
+            auth: Some(AuthConfig {
+                enabled: true,
+                tokens_directory: None,
+                tokens: [("node".to_string(), "node_token".to_string())].into(),
+            }),
+            device_id_providers: vec![
+                DeviceIdProviderConfig {
+                    r#type: "machine_id".to_string(),
+                    ..Default::default()
+                },
+                DeviceIdProviderConfig {
+                    r#type: "edgehog".to_string(),
+                    ..Default::default()
+                },
+            ],
+            device_id_namespace: Some("bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1".to_string()),
+            secrets: Some(SecretsConfig {
+                backend: Some("plaintext".to_string()),
+                key_file: None,
+                keyring_key: Some("message-hub".to_string()),
+            }),
+            ..Default::default()
+        };
+
+        // The dry-run does not write the configuration
+        let invalid = ConfigMessage {
+            store_directory: Some("/other".to_string()),
+            ..msg.clone()
+        };
+        let result = config_server.validate_config(Request::new(invalid)).await;
+        assert_eq!(result.unwrap_err().code(), Code::InvalidArgument);
+
+        let result = config_server
+            .validate_config(Request::new(msg.clone()))
+            .await;
+        assert!(result.is_ok());
+        assert!(!toml_file.exists());
+
+        let result = config_server.set_config(Request::new(msg.clone())).await;
+        assert!(result.is_ok());
+
+        let options = crate::config::file::get_options_from_toml(
+            &std::fs::read_to_string(&toml_file).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(options.store_directory, dir.path());
+        assert!(options.astarte_ignore_ssl);
+        assert_eq!(options.queue.max_size, 10);
+        assert_eq!(options.auth.tokens["node"], "node_token");
+        assert_eq!(options.secrets.backend, SecretBackend::Plaintext);
+        assert_eq!(options.secrets.keyring_key.as_deref(), Some("message-hub"));
+        assert_eq!(
+            options.device_id_namespace.unwrap().to_string(),
+            "bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1"
+        );
+        assert_eq!(
+            options.device_id_providers,
+            vec![
+                DeviceIdSource::MachineId,
+                DeviceIdSource::Edgehog {
+                    namespace: String::new()
+                }
+            ]
+        );
+
+        let config = config_server
+            .get_config(Request::new(pbjson_types::Empty {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(config.realm, "rpc_realm");
+        assert_eq!(config.credentials_secret.as_deref(), Some("<redacted>"));
+        assert_eq!(config.grpc_unix_socket_mode, Some(0o660));
+        assert_eq!(
+            config.store_directory,
+            Some(dir.path().to_string_lossy().to_string())
+        );
+        assert_eq!(config.queue.unwrap().retry_interval, Some(5));
+        assert_eq!(config.auth.unwrap().tokens["node"], "<redacted>");
+        assert_eq!(config.device_id_providers[0].r#type, "machine_id");
+        assert_eq!(config.device_id_providers[1].namespace.as_deref(), Some(""));
+        assert_eq!(
+            config.secrets.unwrap().backend.as_deref(),
+            Some("plaintext")
+        );
+    }
 }
@@ -0,0 +1,218 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Handle the configurations received by the HTTP and Protobuf provisioning APIs.
+
+use std::fs;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::config::{file, MessageHubOptions};
+use crate::persist;
+use crate::secrets;
+
+/// Errors returned by the provisioning APIs.
+#[derive(Debug, Error)]
+pub(crate) enum ProvisioningError {
+    /// The configuration is not valid.
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+    /// No configuration was provided yet.
+    #[error("No configuration provided")]
+    NotFound,
+    /// The configuration file could not be read or written.
+    #[error("{0}")]
+    Storage(String),
+}
+
+/// Configuration file written by the provisioning APIs, in the store directory of the message
+/// hub.
+#[derive(Debug, Clone)]
+pub(crate) struct ProvisionedFile {
+    path: PathBuf,
+}
+
+impl ProvisionedFile {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Store directory the message hub was started with, containing the configuration file.
+    fn store_directory(&self) -> PathBuf {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => MessageHubOptions::default_store_directory(),
+        }
+    }
+
+    /// Validate the options, persisting them into the store directory of the message hub.
+    ///
+    /// The store directory can be omitted, otherwise it must be the one of the message hub.
+    pub(crate) fn prepare(
+        &self,
+        mut options: MessageHubOptions,
+    ) -> Result<MessageHubOptions, ProvisioningError> {
+        let store_directory = self.store_directory();
+
+        if options.store_directory != MessageHubOptions::default_store_directory()
+            && options.store_directory != store_directory
+        {
+            return Err(ProvisioningError::Invalid(format!(
+                "the store directory must be {}",
+                store_directory.display()
+            )));
+        }
+
+        options.store_directory = store_directory;
+        options
+            .validate()
+            .map_err(|err| ProvisioningError::Invalid(err.to_string()))?;
+
+        Ok(options)
+    }
+
+    /// Write the options in the configuration file.
+    ///
+    /// With the encrypted secrets backend, the secrets are written in the secret store instead.
+    pub(crate) fn write(&self, options: &MessageHubOptions) -> Result<(), ProvisioningError> {
+        let stored = options.store_secrets().map_err(|err| {
+            ProvisioningError::Storage(format!("Unable to store the secrets: {}", err))
+        })?;
+
+        let mut options = options.clone();
+        if stored.contains(&secrets::CREDENTIALS_SECRET) {
+            options.credentials_secret = None;
+        }
+        if stored.contains(&secrets::PAIRING_TOKEN) {
+            options.pairing_token = None;
+        }
+
+        let cfg = toml::to_string(&options).map_err(|err| {
+            ProvisioningError::Storage(format!("Error in config serialization: {}", err))
+        })?;
+
+        persist::write_private(&self.path, cfg).map_err(|err| {
+            ProvisioningError::Storage(format!(
+                "Unable to write in file {}: {}",
+                self.path.display(),
+                err
+            ))
+        })
+    }
+
+    /// Read the options in the configuration file, with the secrets redacted.
+    pub(crate) fn read_redacted(&self) -> Result<MessageHubOptions, ProvisioningError> {
+        if !self.path.exists() {
+            return Err(ProvisioningError::NotFound);
+        }
+
+        let toml_str = fs::read_to_string(&self.path).map_err(|err| {
+            ProvisioningError::Storage(format!(
+                "Unable to read file {}: {}",
+                self.path.display(),
+                err
+            ))
+        })?;
+
+        file::get_options_from_toml(&toml_str)
+            .map(|options| options.redacted())
+            .map_err(|err| ProvisioningError::Storage(err.to_string()))
+    }
+}
+
+/// Deserialize the options from a JSON value, the `null` fields are considered missing.
+pub(crate) fn options_from_json(
+    mut value: serde_json::Value,
+) -> Result<MessageHubOptions, ProvisioningError> {
+    remove_nulls(&mut value);
+
+    serde_json::from_value(value).map_err(|err| ProvisioningError::Invalid(err.to_string()))
+}
+
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::file::CONFIG_FILE_NAMES;
+
+    #[test]
+    fn persist_into_store_directory() {
+        let dir = TempDir::new().unwrap();
+        let file = ProvisionedFile::new(dir.path().join(CONFIG_FILE_NAMES[0]));
+
+        assert!(matches!(
+            file.read_redacted(),
+            Err(ProvisioningError::NotFound)
+        ));
+
+        let options = options_from_json(json!({
+            "realm": "realm",
+            "device_id": null,
+            "pairing_url": "pairing_url",
+            "credentials_secret": "secret",
+            "grpc_socket_port": 22,
+            "queue": {"max_size": 10, "eviction_policy": null},
+        }))
+        .unwrap();
+
+        let options = file.prepare(options).unwrap();
+        assert_eq!(options.store_directory, dir.path());
+        assert_eq!(options.queue.max_size, 10);
+
+        file.write(&options).unwrap();
+
+        let read = file.read_redacted().unwrap();
+        assert_eq!(read.store_directory, dir.path());
+        assert_eq!(read.credentials_secret.as_deref(), Some("<redacted>"));
+        assert_eq!(read.redacted(), options.redacted());
+
+        let mut other = options;
+        other.store_directory = PathBuf::from("/other");
+        assert!(matches!(
+            file.prepare(other),
+            Err(ProvisioningError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_json_options() {
+        assert!(options_from_json(json!({"realm": "realm"})).is_err());
+        assert!(options_from_json(json!({
+            "realm": "realm",
+            "pairing_url": "pairing_url",
+            "grpc_socket_port": 100000,
+        }))
+        .is_err());
+    }
+}
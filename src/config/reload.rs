@@ -0,0 +1,271 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Reload the configuration file while the message hub is running.
+//!
+//! The file is read again when its modification time changes or when the process receives a
+//! `SIGHUP`. The new options are validated and compared with the previous ones, to apply the
+//! changes that do not require a restart.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::MissedTickBehavior;
+
+use crate::config::overrides::ConfigOverrides;
+use crate::config::{file, MessageHubOptions, SessionOptions};
+use crate::error::AstarteMessageHubError;
+
+/// Interval between the checks of the configuration file for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Changes between two configurations, grouped by how they are applied.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// The Astarte device must be created again, because the realm, the device id or its
+    /// providers, the credentials, the pairing URL or the SSL options changed.
+    pub reconnect: bool,
+    /// The interfaces directory changed.
+    pub interfaces_directory: bool,
+    /// The log level changed.
+    pub log_level: bool,
+    /// The options of the outbound queue changed.
+    pub queue: bool,
+    /// The options of the sessions of the nodes changed.
+    pub session: bool,
+    /// Options changed that are applied only after restarting the message hub.
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    /// Compare the previous configuration with the current one.
+    pub fn between(previous: &MessageHubOptions, current: &MessageHubOptions) -> Self {
+        let reconnect = previous.realm != current.realm
+            || previous.device_id != current.device_id
+            || previous.device_id_namespace != current.device_id_namespace
+            || previous.device_id_providers != current.device_id_providers
+            || previous.credentials_secret != current.credentials_secret
+            || previous.pairing_url != current.pairing_url
+            || previous.pairing_token != current.pairing_token
+            || previous.astarte_ignore_ssl != current.astarte_ignore_ssl;
+
+        let mut restart_required = Vec::new();
+
+        let mut require_restart = |name, changed| {
+            if changed {
+                restart_required.push(name);
+            }
+        };
+
+        require_restart(
+            "grpc_socket_port",
+            previous.grpc_socket_port != current.grpc_socket_port,
+        );
+        require_restart(
+            "grpc_unix_socket",
+            previous.grpc_unix_socket_path != current.grpc_unix_socket_path
+                || previous.grpc_unix_socket_mode != current.grpc_unix_socket_mode
+                || previous.grpc_unix_socket_owner != current.grpc_unix_socket_owner
+                || previous.grpc_unix_socket_group != current.grpc_unix_socket_group,
+        );
+        require_restart(
+            "store_directory",
+            previous.store_directory != current.store_directory,
+        );
+        require_restart(
+            "session.detach_grace_period",
+            previous.session.detach_grace_period != current.session.detach_grace_period,
+        );
+        require_restart("metrics", previous.metrics != current.metrics);
+        require_restart("admin", previous.admin != current.admin);
+        require_restart("auth", previous.auth != current.auth);
+        require_restart("secrets", previous.secrets != current.secrets);
+
+        // The grace period is applied by the message hub, the other options by the handler
+        let session_options = |options: &SessionOptions| SessionOptions {
+            detach_grace_period: 0,
+            ..options.clone()
+        };
+
+        Self {
+            reconnect,
+            interfaces_directory: previous.interfaces_directory != current.interfaces_directory,
+            log_level: previous.log_level != current.log_level,
+            queue: previous.queue != current.queue,
+            session: session_options(&previous.session) != session_options(&current.session),
+            restart_required,
+        }
+    }
+
+    /// Whether the configurations are the same.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Watch the configuration file, sending the new options when the file changes or when the
+/// process receives a `SIGHUP`.
+///
+/// The overrides from the environment and the command line are applied to each new
+/// configuration. Invalid configurations are logged and ignored, the watch stops when the
+/// receiver is dropped.
+pub fn watch(
+    path: PathBuf,
+    overrides: ConfigOverrides,
+    poll_interval: Duration,
+) -> Result<Receiver<MessageHubOptions>, AstarteMessageHubError> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let (tx, rx) = channel(1);
+
+    tokio::spawn(async move {
+        let mut modified = modification(&path);
+        let mut interval = tokio::time::interval(poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let current = modification(&path);
+                    if current == modified {
+                        continue;
+                    }
+
+                    modified = current;
+                    info!("Configuration file {:?} changed, reloading it", path);
+                }
+                Some(()) = hangup.recv() => {
+                    info!("Received SIGHUP, reloading the configuration file {:?}", path);
+                }
+            }
+
+            match read_options(&path, &overrides) {
+                Ok(options) => {
+                    if tx.send(options).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => warn!("Ignoring the configuration file {:?}: {}", path, err),
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+/// Modification time and size of the file, [None] if it cannot be read.
+fn modification(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Read and validate the options in the configuration file.
+fn read_options(
+    path: &Path,
+    overrides: &ConfigOverrides,
+) -> Result<MessageHubOptions, AstarteMessageHubError> {
+    let toml_str = fs::read_to_string(path)?;
+
+    file::get_options_with_overrides(&toml_str, overrides)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const TOML_FILE: &str = r#"
+        realm = "1"
+        device_id = "2TBn-jNESuuHamE2Zo1anA"
+        pairing_url = "3"
+        credentials_secret = "4"
+        grpc_socket_port = 5
+    "#;
+
+    #[test]
+    fn classify_changes() {
+        let previous = file::get_options_from_toml(TOML_FILE).unwrap();
+
+        assert!(ConfigChanges::between(&previous, &previous.clone()).is_empty());
+
+        let mut current = previous.clone();
+        current.log_level = Some("debug".to_string());
+        current.queue.max_size = 10;
+        current.session.buffer_size = 10;
+        current.interfaces_directory = Some(PathBuf::from("/tmp"));
+
+        let changes = ConfigChanges::between(&previous, &current);
+        assert_eq!(
+            changes,
+            ConfigChanges {
+                reconnect: false,
+                interfaces_directory: true,
+                log_level: true,
+                queue: true,
+                session: true,
+                restart_required: Vec::new(),
+            }
+        );
+
+        let mut current = previous.clone();
+        current.credentials_secret = Some("5".to_string());
+        current.grpc_socket_port = Some(6);
+        current.session.detach_grace_period = 10;
+
+        let changes = ConfigChanges::between(&previous, &current);
+        assert!(changes.reconnect);
+        assert!(!changes.session);
+        assert_eq!(
+            changes.restart_required,
+            vec!["grpc_socket_port", "session.detach_grace_period"]
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_file_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(file::CONFIG_FILE_NAMES[0]);
+        fs::write(&path, TOML_FILE).unwrap();
+
+        let mut overrides = ConfigOverrides::new();
+        overrides.set("queue.max_size", "10").unwrap();
+
+        let mut rx = watch(path.clone(), overrides, Duration::from_millis(10)).unwrap();
+
+        // Invalid configurations are ignored
+        fs::write(&path, "realm = \"\"").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        fs::write(&path, format!("{}\nlog_level = \"debug\"\n", TOML_FILE)).unwrap();
+
+        let options = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.log_level, Some("debug".to_string()));
+        assert_eq!(options.queue.max_size, 10);
+        assert!(rx.try_recv().is_err());
+    }
+}
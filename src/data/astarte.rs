@@ -27,6 +27,7 @@
 
 use async_trait::async_trait;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use tonic::Status;
 
 use crate::astarte_message_hub::AstarteNode;
@@ -36,6 +37,9 @@ use crate::proto_message_hub;
 #[async_trait]
 pub trait AstarteRunner {
     async fn run(&mut self);
+
+    /// Watch the state of the connection to Astarte, updated while running the handler.
+    fn watch_connection(&self) -> watch::Receiver<proto_message_hub::ConnectionEvent>;
 }
 
 /// A **trait** required for all Astarte handlers that want to publish data on Astarte.
@@ -21,41 +21,65 @@
 //! Contains an implementation of an Astarte handler.
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use astarte_device_sdk::AstarteError;
 use async_trait::async_trait;
-use log::warn;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::RwLock;
+use chrono::Utc;
+use log::{debug, info, warn};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
 use tonic::Status;
 use uuid::Uuid;
 
 use crate::astarte_message_hub::AstarteNode;
+use crate::config::{QueueOptions, SessionOptions};
 use crate::data::astarte::{AstartePublisher, AstarteRunner, AstarteSubscriber};
+use crate::data::properties::ServerProperties;
+use crate::data::queue::OutboundQueue;
+use crate::data::subscribers::Subscribers;
+use crate::data::validation::validate_message;
 use crate::error::AstarteMessageHubError;
+use crate::interface::{InterfaceDefinition, InterfaceType, Reliability};
+use crate::metrics::Metrics;
 use crate::proto_message_hub;
+use crate::proto_message_hub::{ConnectionEvent, ConnectionState};
 
 #[cfg(test)]
 use crate::data::mock_astarte_sdk::MockAstarteDeviceSdk as AstarteDeviceSdk;
 #[cfg(not(test))]
 use astarte_device_sdk::AstarteDeviceSdk;
 
+/// Number of queued messages sent before writing the queue to the store directory.
+const REPLAY_PERSIST_BATCH: usize = 16;
+
 /// An Astarte Device SDK based implementation of an Astarte handler.
 /// Uses the Astarte Device SDK to provide subscribe and publish functionality.
 #[derive(Clone)]
 pub struct AstarteHandler {
-    device_sdk: AstarteDeviceSdk,
-    subscribers: Arc<RwLock<HashMap<Uuid, Subscriber>>>,
-}
-
-/// A subscriber for the Astarte handler.
-struct Subscriber {
-    introspection: Vec<astarte_device_sdk::Interface>,
-    sender: Sender<Result<proto_message_hub::AstarteMessage, Status>>,
+    device_sdk: Arc<watch::Sender<AstarteDeviceSdk>>,
+    subscribers: Arc<RwLock<Subscribers>>,
+    properties: ServerProperties,
+    queue: Arc<RwLock<OutboundQueue>>,
+    connected: Arc<AtomicBool>,
+    connection: Arc<watch::Sender<ConnectionEvent>>,
+    metrics: Metrics,
 }
 
 #[async_trait]
 impl AstarteSubscriber for AstarteHandler {
+    /// Subscribe a new Node and its introspection to the Astarte Message Hub.
+    ///
+    /// The interfaces are added to the device only if no other node declared them, or if they
+    /// upgrade the minor version of the registered ones. An interface conflicting with the one
+    /// declared by another node is rejected.
+    ///
+    /// A node already subscribed is replaced: its unchanged interfaces stay registered and the
+    /// events buffered while it was disconnected are sent to the new receiver.
     async fn subscribe(
         &self,
         astarte_node: &AstarteNode,
@@ -63,26 +87,71 @@ impl AstarteSubscriber for AstarteHandler {
     {
         use astarte_device_sdk::Interface;
 
-        let (tx, rx) = channel(32);
-
-        let mut astarte_interfaces: Vec<Interface> = vec![];
+        let mut introspection = Vec::with_capacity(astarte_node.introspection.len());
 
         for interface in astarte_node.introspection.iter() {
             let astarte_interface: Interface = interface.clone().try_into()?;
-            self.device_sdk
-                .add_interface(astarte_interface.clone())
-                .await?;
+            let definition = InterfaceDefinition::try_from(&astarte_interface)?;
 
-            astarte_interfaces.push(astarte_interface);
+            introspection.push((astarte_interface, definition));
         }
 
-        self.subscribers.write().await.insert(
-            astarte_node.id,
-            Subscriber {
-                introspection: astarte_interfaces,
-                sender: tx,
-            },
-        );
+        let mut subscribers = self.subscribers.write().await;
+
+        let to_add = subscribers.plan(&astarte_node.id, &introspection)?;
+        let stale = subscribers.stale_interfaces(&astarte_node.id, &introspection);
+
+        // The device caches the received properties from now on, whatever nodes declare them
+        self.properties
+            .register(introspection.iter().map(|(_, definition)| definition))
+            .await;
+
+        // On failure the device is brought back to the introspection tracked by the subscribers
+        let mut added = Vec::with_capacity(to_add.len());
+        for interface in to_add {
+            let interface_name = interface.get_name();
+
+            if let Err(err) = self.device_sdk().add_interface(interface).await {
+                self.restore_interfaces(&subscribers, &added, &[]).await;
+                return Err(err.into());
+            }
+
+            added.push(interface_name);
+        }
+
+        let mut removed = Vec::with_capacity(stale.len());
+        for interface_name in stale {
+            if let Err(err) = self.device_sdk().remove_interface(&interface_name).await {
+                self.restore_interfaces(&subscribers, &added, &removed)
+                    .await;
+                return Err(err.into());
+            }
+
+            removed.push(interface_name);
+        }
+
+        // Replay the events received while the node was disconnected, before any new event
+        let replay = subscribers.take_replay(&astarte_node.id).await;
+        let (tx, rx) = channel(32 + replay.len());
+
+        for message in replay {
+            let _ = tx.try_send(Ok(message));
+        }
+
+        // Synchronize the node with the cached values of its server-owned properties, of the same
+        // major version. They are queued before the subscriber is visible, so a newer live value
+        // is delivered after them
+        let mut cached_properties = Vec::new();
+        for (_, definition) in introspection.iter() {
+            cached_properties.extend(
+                self.properties
+                    .interface_properties(&definition.interface_name, definition.version_major)
+                    .await,
+            );
+        }
+
+        subscribers.insert(astarte_node.id, introspection, tx, cached_properties);
+
         Ok(rx)
     }
 
@@ -90,90 +159,92 @@ impl AstarteSubscriber for AstarteHandler {
     ///
     /// All the interfaces in this node introspection that are not in the introspection of any other node will be removed.
     async fn unsubscribe(&self, astarte_node: &AstarteNode) -> Result<(), AstarteMessageHubError> {
-        let interfaces_to_remove = {
-            let subscribers_guard = self.subscribers.read().await;
-            astarte_node
-                .introspection
-                .iter()
-                .filter_map(|interface| interface.clone().try_into().ok())
-                .filter(|interface| {
-                    subscribers_guard
-                        .iter()
-                        .filter(|(id, _)| astarte_node.id.ne(id))
-                        .find_map(|(_, subscriber)| {
-                            subscriber.introspection.contains(interface).then(|| ())
-                        })
-                        .is_none()
-                })
-                .collect::<Vec<astarte_device_sdk::Interface>>()
-        };
+        let mut subscribers = self.subscribers.write().await;
 
-        for interface in interfaces_to_remove.iter() {
-            self.device_sdk
-                .remove_interface(&interface.get_name())
-                .await?;
+        if !subscribers.contains(&astarte_node.id) {
+            return Err(AstarteMessageHubError::AstarteInvalidData(
+                "Unable to find AstarteNode".to_string(),
+            ));
         }
 
-        if self
-            .subscribers
-            .write()
-            .await
-            .remove(&astarte_node.id)
-            .is_some()
-        {
-            Ok(())
-        } else {
-            Err(AstarteMessageHubError::AstarteInvalidData(
-                "Unable to find AstarteNode".to_string(),
-            ))
+        let mut removed = Vec::new();
+        for interface_name in subscribers.exclusive_interfaces(&astarte_node.id) {
+            if let Err(err) = self.device_sdk().remove_interface(&interface_name).await {
+                self.restore_interfaces(&subscribers, &[], &removed).await;
+                return Err(err.into());
+            }
+
+            removed.push(interface_name);
         }
+
+        subscribers.remove(&astarte_node.id);
+
+        Ok(())
     }
 }
 
 #[async_trait]
 impl AstartePublisher for AstarteHandler {
+    /// Publish a message on Astarte.
+    ///
+    /// The messages on the interfaces declared by the subscribers are validated against the
+    /// interface mappings before being sent.
+    ///
+    /// While Astarte is unreachable the messages on properties interfaces and on guaranteed or
+    /// unique datastream mappings are retained in the outbound queue, to be sent once the
+    /// connection is restored. The messages are queued also while the queue is not empty, to
+    /// preserve their ordering.
     async fn publish(
         &self,
         astarte_message: &proto_message_hub::AstarteMessage,
     ) -> Result<(), AstarteMessageHubError> {
-        use crate::proto_message_hub::astarte_data_type::Data;
-        use crate::proto_message_hub::astarte_message::Payload;
+        let definition = self
+            .interface_definition(&astarte_message.interface_name)
+            .await;
+
+        if let Some(definition) = &definition {
+            if let Err(err) = validate_message(definition, astarte_message) {
+                self.metrics
+                    .published(&astarte_message.interface_name, false);
+                return Err(err.into());
+            }
+        }
 
-        match astarte_message.payload.clone().ok_or_else(|| {
-            AstarteMessageHubError::AstarteInvalidData("Invalid payload".to_string())
-        })? {
-            Payload::AstarteData(astarte_data) => {
-                match astarte_data.data.ok_or_else(|| {
-                    AstarteMessageHubError::AstarteInvalidData(
-                        "Invalid Astarte data type".to_string(),
-                    )
-                })? {
-                    Data::AstarteIndividual(data) => {
-                        self.publish_astarte_individual(
-                            data,
-                            &astarte_message.interface_name,
-                            &astarte_message.path,
-                            astarte_message.timestamp.clone(),
-                        )
-                        .await
-                    }
-                    Data::AstarteObject(object_data) => {
-                        self.publish_astarte_object(
-                            object_data,
-                            &astarte_message.interface_name,
-                            &astarte_message.path,
-                            astarte_message.timestamp.clone(),
-                        )
-                        .await
-                    }
-                }
+        let queueable = definition
+            .map(|definition| Self::is_queueable(&definition, &astarte_message.path))
+            .unwrap_or(false);
+
+        if queueable {
+            let mut queue = self.queue.write().await;
+
+            if queue.is_enabled() && (!self.is_connected() || !queue.is_empty()) {
+                debug!(
+                    "queueing message for {}{}",
+                    astarte_message.interface_name, astarte_message.path
+                );
+
+                let res = queue.push(astarte_message.clone());
+                self.metrics.set_queued_messages(queue.len());
+
+                return res;
             }
-            Payload::AstarteUnset(_) => {
-                self.device_sdk
-                    .unset(&astarte_message.interface_name, &astarte_message.path)
-                    .await
+        }
+
+        match self.send_message(astarte_message).await {
+            Err(AstarteMessageHubError::AstarteError(err))
+                if matches!(*err, AstarteError::BsonClientError(_))
+                    && queueable
+                    && self.queue.read().await.is_enabled() =>
+            {
+                warn!("Unable to send the message, queueing it: {:?}", err);
+
+                let mut queue = self.queue.write().await;
+                let res = queue.push(astarte_message.clone());
+                self.metrics.set_queued_messages(queue.len());
+
+                res
             }
-            .map_err(AstarteMessageHubError::AstarteError),
+            res => res,
         }
     }
 }
@@ -184,7 +255,11 @@ impl AstarteRunner for AstarteHandler {
     ///
     /// Polls the Astarte Device SDK for received messages. When the received message interface
     /// matches with one or more of the subscribers interface it forwards the message to each
-    /// subscriber queue.
+    /// subscriber queue. The server-owned properties are cached by the device, in the
+    /// [ServerProperties] given as its database, also when no node declares them.
+    ///
+    /// A connection error disconnects the handler, the connection is restored when an event is
+    /// received or when a message is published successfully.
     ///
     /// This function should be run periodically.
     /// N.B. the Astarte SDK `poll()` function is blocking and as a consequence so will be this
@@ -193,24 +268,35 @@ impl AstarteRunner for AstarteHandler {
     async fn run(&mut self) {
         use crate::proto_message_hub::AstarteMessage;
 
-        if let Ok(astarte_data_event) = self.device_sdk.handle_events().await {
+        let mut device_sdk_rx = self.device_sdk.subscribe();
+        let mut device_sdk = device_sdk_rx.borrow_and_update().clone();
+
+        let events = device_sdk.handle_events();
+        tokio::pin!(events);
+
+        let event = tokio::select! {
+            event = &mut events => event,
+            // The device has been replaced, the next run polls the new one
+            _ = device_sdk_rx.changed() => return,
+        };
+
+        match &event {
+            Ok(_) => self.set_connected(),
+            Err(AstarteError::ConnectionError(err)) => self.set_disconnected(err.to_string()),
+            Err(_) => {}
+        }
+
+        if let Ok(astarte_data_event) = event {
             println!("incoming: {:?}", astarte_data_event);
 
             if let Ok(astarte_message) = AstarteMessage::try_from(astarte_data_event.clone()) {
                 let subscribers_guard = self.subscribers.read().await;
-                let subscribers = subscribers_guard
-                    .iter()
-                    .filter(|(_, subscriber)| {
-                        subscriber
-                            .introspection
-                            .iter()
-                            .map(|iface| iface.get_name())
-                            .collect::<String>()
-                            .contains(&astarte_data_event.interface)
-                    })
-                    .map(|(_, subscriber)| subscriber);
+
+                self.metrics.received_event(&astarte_data_event.interface);
+
+                let subscribers = subscribers_guard.subscribed_to(&astarte_data_event.interface);
                 for subscriber in subscribers {
-                    let _ = subscriber.sender.send(Ok(astarte_message.clone())).await;
+                    subscriber.send(astarte_message.clone()).await;
                 }
             } else {
                 warn!(
@@ -220,6 +306,10 @@ impl AstarteRunner for AstarteHandler {
             }
         }
     }
+
+    fn watch_connection(&self) -> watch::Receiver<ConnectionEvent> {
+        self.connection.subscribe()
+    }
 }
 
 impl AstarteHandler {
@@ -227,8 +317,418 @@ impl AstarteHandler {
     #[allow(dead_code)]
     pub fn new(device_sdk: AstarteDeviceSdk) -> Self {
         AstarteHandler {
-            device_sdk,
+            device_sdk: Arc::new(watch::channel(device_sdk).0),
             subscribers: Arc::new(Default::default()),
+            properties: ServerProperties::new(),
+            queue: Arc::new(RwLock::new(OutboundQueue::new(QueueOptions::default()))),
+            connected: Arc::new(AtomicBool::new(true)),
+            connection: Arc::new(Self::connection_channel()),
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Constructs a new handler from the [AstarteDeviceSdk], persisting the outbound queue in the
+    /// `store_directory`.
+    pub fn with_store_directory(
+        device_sdk: AstarteDeviceSdk,
+        store_directory: &Path,
+        queue_options: QueueOptions,
+    ) -> Result<Self, AstarteMessageHubError> {
+        let queue = OutboundQueue::open(store_directory, queue_options)?;
+
+        Ok(AstarteHandler {
+            device_sdk: Arc::new(watch::channel(device_sdk).0),
+            subscribers: Arc::new(Default::default()),
+            properties: ServerProperties::new(),
+            queue: Arc::new(RwLock::new(queue)),
+            connected: Arc::new(AtomicBool::new(true)),
+            connection: Arc::new(Self::connection_channel()),
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// Set the size of the buffers of the events for each node and the policy applied when they
+    /// are full, also buffering the events for the nodes disconnected without detaching to replay
+    /// them when the nodes attach again.
+    ///
+    /// Must be called before subscribing any node.
+    pub fn with_session_options(mut self, options: SessionOptions) -> Self {
+        self.subscribers = Arc::new(RwLock::new(Subscribers::new(options)));
+        self
+    }
+
+    /// Send to the nodes the server-owned properties cached in the given [ServerProperties], the
+    /// database of the device.
+    ///
+    /// Must be called before subscribing any node.
+    pub fn with_server_properties(mut self, properties: ServerProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Collect the metrics of the messages published and received in the given metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        metrics.set_connected(self.is_connected());
+
+        // The handler has just been created, the queue is not locked
+        if let Ok(queue) = self.queue.try_read() {
+            metrics.set_queued_messages(queue.len());
+        }
+
+        self.metrics = metrics;
+        self
+    }
+
+    /// Returns the cache of the server-owned properties, the database of the devices.
+    pub fn server_properties(&self) -> ServerProperties {
+        self.properties.clone()
+    }
+
+    /// Number of events dropped for each node because it was not reading its stream.
+    pub async fn dropped_messages(&self) -> HashMap<Uuid, u64> {
+        self.subscribers.read().await.dropped_messages()
+    }
+
+    /// Number of messages waiting in the outbound queue to be sent to Astarte.
+    pub async fn queued_messages(&self) -> usize {
+        self.queue.read().await.len()
+    }
+
+    /// Replace the Astarte device, e.g. after changing its credentials, keeping the subscribed
+    /// nodes.
+    ///
+    /// The interfaces of the nodes are added to the new device before replacing the current one,
+    /// the previous connection is closed once the handler polls the new device.
+    pub async fn reconnect(
+        &self,
+        device_sdk: AstarteDeviceSdk,
+    ) -> Result<(), AstarteMessageHubError> {
+        // Hold the subscribers to prevent nodes from (un)subscribing while switching device
+        let subscribers = self.subscribers.write().await;
+
+        for interface in subscribers.interfaces() {
+            device_sdk.add_interface(interface.clone()).await?;
+        }
+
+        self.device_sdk.send_replace(device_sdk);
+
+        info!(
+            "Astarte device replaced, {} interfaces restored",
+            subscribers.interfaces().count()
+        );
+
+        Ok(())
+    }
+
+    /// Change the options of the outbound queue, discarding the oldest messages exceeding the new
+    /// maximum size.
+    pub async fn set_queue_options(
+        &self,
+        options: QueueOptions,
+    ) -> Result<(), AstarteMessageHubError> {
+        let mut queue = self.queue.write().await;
+
+        queue.set_options(options)?;
+        self.metrics.set_queued_messages(queue.len());
+
+        Ok(())
+    }
+
+    /// Change the session options, applied to the nodes attaching from now on.
+    pub async fn set_session_options(&self, options: SessionOptions) {
+        self.subscribers.write().await.set_options(options);
+    }
+
+    /// Replace the interfaces loaded from the interfaces directory with the ones in the new
+    /// directory.
+    ///
+    /// The interfaces of the previous directory that are not in the new one are removed from the
+    /// device, unless declared by a node.
+    pub async fn reload_interfaces_directory(
+        &self,
+        previous: Option<&Path>,
+        current: Option<&Path>,
+    ) -> Result<(), AstarteMessageHubError> {
+        let previous = previous
+            .map(read_interfaces_directory)
+            .transpose()?
+            .unwrap_or_default();
+        let current = current
+            .map(read_interfaces_directory)
+            .transpose()?
+            .unwrap_or_default();
+
+        let subscribers = self.subscribers.read().await;
+        let device_sdk = self.device_sdk();
+
+        let definitions = current
+            .iter()
+            .map(InterfaceDefinition::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.properties.register(&definitions).await;
+
+        for interface in previous {
+            let name = interface.get_name();
+
+            let kept = current.iter().any(|current| current.get_name() == name);
+            if !kept && subscribers.interface(&name).is_none() {
+                device_sdk.remove_interface(&name).await?;
+            }
+        }
+
+        for interface in current {
+            if subscribers.interface(&interface.get_name()).is_none() {
+                device_sdk.add_interface(interface).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Spawn a task sending the queued messages to Astarte, retrying periodically until the
+    /// connection is restored.
+    pub fn start_queue_replay(&self) -> JoinHandle<()> {
+        let handler = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let retry_interval = handler.queue.read().await.retry_interval().max(1);
+                tokio::time::sleep(Duration::from_secs(retry_interval)).await;
+
+                handler.replay_queue().await;
+            }
+        })
+    }
+
+    /// Send the queued messages in order while connected, stopping at the first transport failure.
+    ///
+    /// The messages are kept in the queue until the connection to Astarte is restored. A message
+    /// is removed from the queue only after it has been sent and the queue is written every
+    /// [REPLAY_PERSIST_BATCH] messages, so at most a batch of messages could be delivered again if
+    /// the message hub stops in between.
+    async fn replay_queue(&self) {
+        let mut sent = 0;
+
+        while self.is_connected() {
+            let message = match self.queue.read().await.front() {
+                Some(message) => message.clone(),
+                None => break,
+            };
+
+            match self.send_message(&message).await {
+                Ok(()) => {}
+                Err(AstarteMessageHubError::AstarteError(err))
+                    if matches!(*err, AstarteError::BsonClientError(_)) =>
+                {
+                    debug!("Unable to send the queued messages: {:?}", err);
+                    break;
+                }
+                Err(err) => {
+                    warn!("Discarding queued message that cannot be sent: {:?}", err);
+                }
+            }
+
+            let mut queue = self.queue.write().await;
+            if queue.front() == Some(&message) {
+                queue.pop_front();
+                sent += 1;
+
+                if sent % REPLAY_PERSIST_BATCH == 0 {
+                    self.persist_queue(&queue);
+                }
+            }
+        }
+
+        if sent % REPLAY_PERSIST_BATCH != 0 {
+            self.persist_queue(&*self.queue.read().await);
+        }
+    }
+
+    /// Write the queue after sending some of its messages.
+    fn persist_queue(&self, queue: &OutboundQueue) {
+        self.metrics.set_queued_messages(queue.len());
+
+        if let Err(err) = queue.persist() {
+            warn!("Unable to persist the outbound queue: {:?}", err);
+        }
+    }
+
+    /// Returns the current Astarte device, replaced when reconnecting.
+    fn device_sdk(&self) -> AstarteDeviceSdk {
+        self.device_sdk.borrow().clone()
+    }
+
+    /// Undo the changes to the device introspection, restoring the interfaces registered in the
+    /// subscribers.
+    async fn restore_interfaces(
+        &self,
+        subscribers: &Subscribers,
+        added: &[String],
+        removed: &[String],
+    ) {
+        let device_sdk = self.device_sdk();
+
+        for interface_name in added {
+            let res = match subscribers.interface(interface_name) {
+                Some(interface) => device_sdk.add_interface(interface.clone()).await,
+                None => device_sdk.remove_interface(interface_name).await,
+            };
+
+            if let Err(err) = res {
+                warn!(
+                    "Unable to restore the interface {}: {:?}",
+                    interface_name, err
+                );
+            }
+        }
+
+        for interface_name in removed {
+            if let Some(interface) = subscribers.interface(interface_name) {
+                if let Err(err) = device_sdk.add_interface(interface.clone()).await {
+                    warn!(
+                        "Unable to restore the interface {}: {:?}",
+                        interface_name, err
+                    );
+                }
+            }
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Channel of the connection state, the handler is created after connecting to Astarte.
+    fn connection_channel() -> watch::Sender<ConnectionEvent> {
+        let (tx, _) = watch::channel(ConnectionEvent {
+            state: ConnectionState::Connected.into(),
+            reason: String::new(),
+            timestamp: Some(Utc::now().into()),
+        });
+
+        tx
+    }
+
+    fn set_connected(&self) {
+        self.set_connection_state(ConnectionState::Connected, String::new());
+    }
+
+    /// Report a connection failure, the first one disconnects the handler while the following
+    /// ones are retries to connect again.
+    fn set_disconnected(&self, reason: String) {
+        let state = if self.is_connected() {
+            ConnectionState::Disconnected
+        } else {
+            ConnectionState::Reconnecting
+        };
+
+        self.set_connection_state(state, reason);
+    }
+
+    /// Update the connection state, notifying its changes to the nodes watching the connection.
+    fn set_connection_state(&self, state: ConnectionState, reason: String) {
+        let connected = state == ConnectionState::Connected;
+
+        if self.connected.swap(connected, Ordering::SeqCst) != connected {
+            self.metrics.set_connected(connected);
+        }
+
+        self.connection.send_if_modified(|event| {
+            if event.state() == state {
+                return false;
+            }
+
+            debug!("connection state changed to {:?}: {}", state, reason);
+
+            *event = ConnectionEvent {
+                state: state.into(),
+                reason,
+                timestamp: Some(Utc::now().into()),
+            };
+
+            true
+        });
+    }
+
+    /// Returns the definition of an interface declared by the subscribers.
+    async fn interface_definition(&self, interface_name: &str) -> Option<InterfaceDefinition> {
+        self.subscribers
+            .read()
+            .await
+            .definition(interface_name)
+            .cloned()
+    }
+
+    /// Check if a message should be retained in the queue when it cannot be sent.
+    fn is_queueable(definition: &InterfaceDefinition, path: &str) -> bool {
+        match definition.interface_type {
+            InterfaceType::Properties => true,
+            InterfaceType::Datastream => matches!(
+                definition.reliability(path),
+                Some(Reliability::Guaranteed | Reliability::Unique)
+            ),
+        }
+    }
+
+    /// Send a message to Astarte through the device SDK, a message sent restores the connection.
+    async fn send_message(
+        &self,
+        astarte_message: &proto_message_hub::AstarteMessage,
+    ) -> Result<(), AstarteMessageHubError> {
+        let res = self.send_payload(astarte_message).await;
+
+        self.metrics
+            .published(&astarte_message.interface_name, res.is_ok());
+
+        if res.is_ok() {
+            self.set_connected();
+        }
+
+        res
+    }
+
+    async fn send_payload(
+        &self,
+        astarte_message: &proto_message_hub::AstarteMessage,
+    ) -> Result<(), AstarteMessageHubError> {
+        use crate::proto_message_hub::astarte_data_type::Data;
+        use crate::proto_message_hub::astarte_message::Payload;
+
+        match astarte_message.payload.clone().ok_or_else(|| {
+            AstarteMessageHubError::AstarteInvalidData("Invalid payload".to_string())
+        })? {
+            Payload::AstarteData(astarte_data) => {
+                match astarte_data.data.ok_or_else(|| {
+                    AstarteMessageHubError::AstarteInvalidData(
+                        "Invalid Astarte data type".to_string(),
+                    )
+                })? {
+                    Data::AstarteIndividual(data) => {
+                        self.publish_astarte_individual(
+                            data,
+                            &astarte_message.interface_name,
+                            &astarte_message.path,
+                            astarte_message.timestamp.clone(),
+                        )
+                        .await
+                    }
+                    Data::AstarteObject(object_data) => {
+                        self.publish_astarte_object(
+                            object_data,
+                            &astarte_message.interface_name,
+                            &astarte_message.path,
+                            astarte_message.timestamp.clone(),
+                        )
+                        .await
+                    }
+                }
+            }
+            Payload::AstarteUnset(_) => {
+                self.device_sdk()
+                    .unset(&astarte_message.interface_name, &astarte_message.path)
+                    .await
+            }
+            .map_err(AstarteMessageHubError::from),
         }
     }
 
@@ -252,15 +752,15 @@ impl AstarteHandler {
             .try_into()?;
 
         if let Some(timestamp) = timestamp {
-            self.device_sdk
+            self.device_sdk()
                 .send_with_timestamp(interface_name, path, astarte_type, timestamp.try_into()?)
                 .await
         } else {
-            self.device_sdk
+            self.device_sdk()
                 .send(interface_name, path, astarte_type)
                 .await
         }
-        .map_err(AstarteMessageHubError::AstarteError)
+        .map_err(AstarteMessageHubError::from)
     }
 
     /// Publish an AstarteDataTypeObject on specific interface and path.
@@ -280,24 +780,47 @@ impl AstarteHandler {
 
         let aggr = crate::types::map_values_to_astarte_type(astarte_data_individual_map)?;
         if let Some(timestamp) = timestamp {
-            self.device_sdk
+            self.device_sdk()
                 .send_object_with_timestamp(interface_name, path, aggr, timestamp.try_into()?)
                 .await
         } else {
-            self.device_sdk
+            self.device_sdk()
                 .send_object(interface_name, path, aggr)
                 .await
         }
-        .map_err(AstarteMessageHubError::AstarteError)
+        .map_err(AstarteMessageHubError::from)
+    }
+}
+
+/// Read the interfaces in the `.json` files of a directory.
+fn read_interfaces_directory(
+    dir: &Path,
+) -> Result<Vec<astarte_device_sdk::Interface>, AstarteMessageHubError> {
+    let mut interfaces = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.extension().map_or(false, |ext| ext == "json") {
+            let interface = astarte_device_sdk::Interface::from_file(&path)
+                .map_err(AstarteError::InterfaceError)?;
+
+            interfaces.push(interface);
+        }
     }
+
+    Ok(interfaces)
 }
 
 #[cfg(test)]
+// The mocked device returns the unboxed errors of the SDK
+#[allow(clippy::result_large_err)]
 mod test {
-    use super::AstarteHandler;
+    use super::{AstarteHandler, REPLAY_PERSIST_BATCH};
 
     use std::collections::HashMap;
     use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
 
     use astarte_device_sdk::types::AstarteType;
     use astarte_device_sdk::{Aggregation, AstarteDeviceDataEvent, AstarteError};
@@ -306,8 +829,10 @@ mod test {
     use tonic::Status;
 
     use crate::astarte_message_hub::AstarteNode;
+    use crate::config::SessionOptions;
     use crate::data::astarte::{AstartePublisher, AstarteRunner, AstarteSubscriber};
     use crate::data::mock_astarte_sdk::MockAstarteDeviceSdk;
+    use crate::data::queue::OutboundQueue;
     use crate::error::AstarteMessageHubError;
 
     const SERV_PROPS_IFACE: &str = r#"
@@ -395,9 +920,8 @@ mod test {
 
         assert!(matches!(
             result.err().unwrap(),
-            AstarteMessageHubError::AstarteError(astarte_device_sdk::AstarteError::InterfaceError(
-                _
-            ))
+            AstarteMessageHubError::AstarteError(err)
+                if matches!(*err, astarte_device_sdk::AstarteError::InterfaceError(_))
         ))
     }
 
@@ -439,51 +963,562 @@ mod test {
         let mut rx: Receiver<Result<AstarteMessage, Status>> = subscribe_result.unwrap();
         astarte_handler.run().await;
 
-        let astarte_message_result = rx.recv().await.unwrap();
-        assert!(astarte_message_result.is_ok());
+        let astarte_message_result = rx.recv().await.unwrap();
+        assert!(astarte_message_result.is_ok());
+
+        let astarte_message = astarte_message_result.unwrap();
+
+        assert_eq!(expected_interface_name, astarte_message.interface_name);
+        assert_eq!(astarte_message.path, path);
+
+        let individual_data = astarte_message
+            .take_data()
+            .and_then(|data| data.take_individual())
+            .and_then(|data| data.individual_data)
+            .and_then(|data| data.into())
+            .unwrap();
+
+        assert_eq!(IndividualData::AstarteInteger(value), individual_data);
+    }
+
+    #[tokio::test]
+    async fn poll_failed_with_astarte_error() {
+        use crate::proto_message_hub::AstarteMessage;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let interfaces = vec![SERV_PROPS_IFACE.to_string().into_bytes()];
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            interfaces,
+        );
+
+        device_sdk
+            .expect_handle_events()
+            .returning(move || Err(AstarteError::DeserializationError));
+
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+
+        let subscribe_result = astarte_handler.subscribe(&astarte_node).await;
+        assert!(subscribe_result.is_ok());
+
+        let mut rx: Receiver<Result<AstarteMessage, Status>> = subscribe_result.unwrap();
+        astarte_handler.run().await;
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn poll_routes_only_exact_interface_name() {
+        use crate::proto_message_hub::AstarteMessage;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+
+        // Same interface name as SERV_PROPS_IFACE, with the last character stripped
+        device_sdk.expect_handle_events().returning(|| {
+            Ok(AstarteDeviceDataEvent {
+                interface: "org.astarte-platform.test.tes".to_string(),
+                path: "/button".to_string(),
+                data: Aggregation::Individual(true.into()),
+            })
+        });
+
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+
+        let subscribe_result = astarte_handler.subscribe(&astarte_node).await;
+        assert!(subscribe_result.is_ok());
+
+        let mut rx: Receiver<Result<AstarteMessage, Status>> = subscribe_result.unwrap();
+        astarte_handler.run().await;
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_keeps_subscribed_nodes() {
+        use crate::proto_message_hub::AstarteMessage;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .returning(|_| Ok(()));
+        device_sdk.expect_handle_events().never();
+
+        let mut new_device_sdk = MockAstarteDeviceSdk::new();
+        new_device_sdk
+            .expect_add_interface()
+            .withf(|interface| interface.get_name() == "org.astarte-platform.test.test")
+            .times(1)
+            .returning(|_| Ok(()));
+        new_device_sdk.expect_handle_events().returning(|| {
+            Ok(AstarteDeviceDataEvent {
+                interface: "org.astarte-platform.test.test".to_string(),
+                path: "/button".to_string(),
+                data: Aggregation::Individual(true.into()),
+            })
+        });
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+
+        let mut rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        astarte_handler.reconnect(new_device_sdk).await.unwrap();
+        astarte_handler.run().await;
+
+        let message = rx.recv().await.unwrap().unwrap();
+        assert_eq!(message.interface_name, "org.astarte-platform.test.test");
+    }
+
+    #[tokio::test]
+    async fn poll_routes_only_to_declaring_nodes() {
+        use crate::proto_message_hub::AstarteMessage;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+
+        device_sdk.expect_handle_events().returning(|| {
+            Ok(AstarteDeviceDataEvent {
+                interface: "com.test.object".to_string(),
+                path: "/obj".to_string(),
+                data: Aggregation::Object(HashMap::from([(
+                    "button".to_string(),
+                    AstarteType::Boolean(true),
+                )])),
+            })
+        });
+
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk.expect_remove_interface().returning(|_| Ok(()));
+
+        let props_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+        let obj_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440001".parse().unwrap(),
+            vec![
+                SERV_PROPS_IFACE.to_string().into_bytes(),
+                SERV_OBJ_IFACE.to_string().into_bytes(),
+            ],
+        );
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+
+        let mut props_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&props_node).await.unwrap();
+        let mut obj_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&obj_node).await.unwrap();
+
+        astarte_handler.run().await;
+
+        let astarte_message = obj_rx.recv().await.unwrap().unwrap();
+        assert!(props_rx.try_recv().is_err());
+        assert_eq!("com.test.object", astarte_message.interface_name);
+
+        // Once unsubscribed the node must not receive any more events
+        assert!(astarte_handler.unsubscribe(&obj_node).await.is_ok());
+        astarte_handler.run().await;
+
+        assert!(props_rx.try_recv().is_err());
+        assert!(astarte_handler
+            .subscribers
+            .read()
+            .await
+            .interface("com.test.object")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn shared_interface_added_and_removed_once() {
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .returning(|_| Ok(()));
+        device_sdk
+            .expect_remove_interface()
+            .withf(|name: &str| name == "com.test.object")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        let first = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_OBJ_IFACE.to_string().into_bytes()],
+        );
+        let second = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440001".parse().unwrap(),
+            vec![SERV_OBJ_IFACE.to_string().into_bytes()],
+        );
+
+        astarte_handler.subscribe(&first).await.unwrap();
+        astarte_handler.subscribe(&second).await.unwrap();
+
+        astarte_handler.unsubscribe(&first).await.unwrap();
+        astarte_handler.unsubscribe(&second).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reattach_replays_buffered_events() {
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+
+        device_sdk.expect_handle_events().returning(|| {
+            Ok(AstarteDeviceDataEvent {
+                interface: "com.test.object".to_string(),
+                path: "/obj".to_string(),
+                data: Aggregation::Object(HashMap::from([(
+                    "button".to_string(),
+                    AstarteType::Boolean(true),
+                )])),
+            })
+        });
+        // The interface is added only on the first attach
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .returning(|_| Ok(()));
+        device_sdk.expect_remove_interface().never();
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_OBJ_IFACE.to_string().into_bytes()],
+        );
+
+        let mut astarte_handler =
+            AstarteHandler::new(device_sdk).with_session_options(SessionOptions {
+                replay_buffer_size: 10,
+                ..Default::default()
+            });
+
+        let rx = astarte_handler.subscribe(&astarte_node).await.unwrap();
+        drop(rx);
+
+        astarte_handler.run().await;
+        astarte_handler.run().await;
+
+        let mut rx = astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        for _ in 0..2 {
+            let astarte_message = rx.try_recv().unwrap().unwrap();
+            assert_eq!("com.test.object", astarte_message.interface_name);
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reattach_replays_event_received_after_stream_dropped() {
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+
+        device_sdk.expect_handle_events().times(1).returning(|| {
+            Ok(AstarteDeviceDataEvent {
+                interface: "com.test.object".to_string(),
+                path: "/obj".to_string(),
+                data: Aggregation::Object(HashMap::from([(
+                    "button".to_string(),
+                    AstarteType::Boolean(true),
+                )])),
+            })
+        });
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_OBJ_IFACE.to_string().into_bytes()],
+        );
+
+        let mut astarte_handler =
+            AstarteHandler::new(device_sdk).with_session_options(SessionOptions {
+                replay_buffer_size: 10,
+                ..Default::default()
+            });
+
+        let rx = astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        // Let the delivery wait for the events before the node drops its stream
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        drop(rx);
+
+        astarte_handler.run().await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mut rx = astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        let astarte_message = rx.try_recv().unwrap().unwrap();
+        assert_eq!("com.test.object", astarte_message.interface_name);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribe_rejects_conflicting_interface() {
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        let first = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_OBJ_IFACE.to_string().into_bytes()],
+        );
+        let second = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440001".parse().unwrap(),
+            vec![SERV_OBJ_IFACE
+                .replace(r#""version_major": 0"#, r#""version_major": 1"#)
+                .into_bytes()],
+        );
+
+        astarte_handler.subscribe(&first).await.unwrap();
+
+        let result = astarte_handler.subscribe(&second).await;
+        assert!(matches!(
+            result,
+            Err(AstarteMessageHubError::InterfaceConflict { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn subscribe_failure_restores_the_introspection() {
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        device_sdk
+            .expect_add_interface()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Err(AstarteError::Unreported));
+        device_sdk
+            .expect_remove_interface()
+            .withf(|interface_name| interface_name == "org.astarte-platform.test.test")
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![
+                SERV_PROPS_IFACE.to_string().into_bytes(),
+                SERV_OBJ_IFACE.to_string().into_bytes(),
+            ],
+        );
+
+        let result = astarte_handler.subscribe(&astarte_node).await;
+        assert!(result.is_err());
+
+        assert!(!astarte_handler
+            .subscribers
+            .read()
+            .await
+            .contains(&astarte_node.id));
+    }
+
+    /// Store a property received by the device in its database, as the device does before
+    /// returning the event.
+    async fn receive_property(astarte_handler: &AstarteHandler, path: &str, value: i32) {
+        use crate::data::properties::encode_value;
+        use astarte_device_sdk::database::AstarteDatabase;
+
+        let value = encode_value(AstarteType::Integer(value)).unwrap();
+        astarte_handler
+            .server_properties()
+            .store_prop("org.astarte-platform.test.test", path, &value, 1)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribe_receives_cached_properties() {
+        use crate::data::properties::ServerProperties;
+        use crate::proto_message_hub::AstarteMessage;
+
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+
+        let first_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+
+        let astarte_handler = AstarteHandler::new(device_sdk)
+            .with_server_properties(ServerProperties::open(dir.path()).unwrap());
+
+        astarte_handler.subscribe(&first_node).await.unwrap();
+        receive_property(&astarte_handler, "/uptimeSeconds", 42).await;
+
+        // A new handler on the same store will sync a node with the stored value
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+
+        let astarte_handler = AstarteHandler::new(device_sdk)
+            .with_server_properties(ServerProperties::open(dir.path()).unwrap());
 
-        let astarte_message = astarte_message_result.unwrap();
+        let second_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440001".parse().unwrap(),
+            vec![
+                SERV_PROPS_IFACE.to_string().into_bytes(),
+                SERV_OBJ_IFACE.to_string().into_bytes(),
+            ],
+        );
 
-        assert_eq!(expected_interface_name, astarte_message.interface_name);
-        assert_eq!(astarte_message.path, path);
+        let mut second_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&second_node).await.unwrap();
 
-        let individual_data = astarte_message
+        let astarte_message = second_rx.recv().await.unwrap().unwrap();
+        assert_eq!("/uptimeSeconds", astarte_message.path);
+        let value: AstarteType = astarte_message
             .take_data()
             .and_then(|data| data.take_individual())
             .and_then(|data| data.individual_data)
-            .and_then(|data| data.into())
+            .unwrap()
+            .try_into()
             .unwrap();
+        assert_eq!(value, AstarteType::Integer(42));
+        assert!(second_rx.try_recv().is_err());
+    }
 
-        assert_eq!(IndividualData::AstarteInteger(value), individual_data);
+    #[tokio::test]
+    async fn cached_properties_of_the_declared_major_version() {
+        use crate::proto_message_hub::AstarteMessage;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk.expect_remove_interface().returning(|_| Ok(()));
+
+        let node_id = "550e8400-e29b-41d4-a716-446655440000".parse().unwrap();
+        let first_node = AstarteNode::new(node_id, vec![SERV_PROPS_IFACE.to_string().into_bytes()]);
+
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        astarte_handler.subscribe(&first_node).await.unwrap();
+        astarte_handler.unsubscribe(&first_node).await.unwrap();
+
+        let second_node = AstarteNode::new(
+            node_id,
+            vec![SERV_PROPS_IFACE
+                .replace(r#""version_major": 1"#, r#""version_major": 2"#)
+                .into_bytes()],
+        );
+
+        let mut second_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&second_node).await.unwrap();
+        assert!(second_rx.try_recv().is_err());
+
+        receive_property(&astarte_handler, "/uptimeSeconds", 42).await;
+        astarte_handler.unsubscribe(&second_node).await.unwrap();
+
+        let mut first_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&first_node).await.unwrap();
+        assert!(first_rx.try_recv().is_err());
     }
 
     #[tokio::test]
-    async fn poll_failed_with_astarte_error() {
+    async fn properties_cached_without_declaring_node() {
         use crate::proto_message_hub::AstarteMessage;
 
         let mut device_sdk = MockAstarteDeviceSdk::new();
-        let interfaces = vec![SERV_PROPS_IFACE.to_string().into_bytes()];
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk.expect_remove_interface().returning(|_| Ok(()));
 
         let astarte_node = AstarteNode::new(
             "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
-            interfaces,
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
         );
 
-        device_sdk
-            .expect_handle_events()
-            .returning(move || Err(AstarteError::DeserializationError));
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        astarte_handler.subscribe(&astarte_node).await.unwrap();
+        astarte_handler.unsubscribe(&astarte_node).await.unwrap();
+
+        // Received while no node declares the interface
+        receive_property(&astarte_handler, "/uptimeSeconds", 42).await;
+
+        let mut rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        let astarte_message = rx.recv().await.unwrap().unwrap();
+        assert_eq!("/uptimeSeconds", astarte_message.path);
+    }
+
+    #[tokio::test]
+    async fn cached_properties_precede_live_values() {
+        use crate::proto_message_hub::AstarteMessage;
 
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        for value in [1, 2] {
+            device_sdk
+                .expect_handle_events()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(move || {
+                    Ok(AstarteDeviceDataEvent {
+                        interface: "org.astarte-platform.test.test".to_string(),
+                        path: "/uptimeSeconds".to_string(),
+                        data: Aggregation::Individual(AstarteType::Integer(value)),
+                    })
+                });
+        }
         device_sdk.expect_add_interface().returning(|_| Ok(()));
 
+        let first_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+        let second_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440001".parse().unwrap(),
+            vec![SERV_PROPS_IFACE.to_string().into_bytes()],
+        );
+
         let mut astarte_handler = AstarteHandler::new(device_sdk);
 
-        let subscribe_result = astarte_handler.subscribe(&astarte_node).await;
-        assert!(subscribe_result.is_ok());
+        let _first_rx = astarte_handler.subscribe(&first_node).await.unwrap();
+        receive_property(&astarte_handler, "/uptimeSeconds", 1).await;
+        astarte_handler.run().await;
 
-        let mut rx: Receiver<Result<AstarteMessage, Status>> = subscribe_result.unwrap();
+        let mut second_rx: Receiver<Result<AstarteMessage, Status>> =
+            astarte_handler.subscribe(&second_node).await.unwrap();
+        receive_property(&astarte_handler, "/uptimeSeconds", 2).await;
         astarte_handler.run().await;
 
-        assert!(rx.try_recv().is_err());
+        for expected in [1, 2] {
+            let message = second_rx.recv().await.unwrap().unwrap();
+            let value: AstarteType = message
+                .take_data()
+                .and_then(|data| data.take_individual())
+                .and_then(|data| data.individual_data)
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+            assert_eq!(value, AstarteType::Integer(expected));
+        }
     }
 
     #[tokio::test]
@@ -921,6 +1956,397 @@ mod test {
         ));
     }
 
+    #[tokio::test]
+    async fn publish_rejects_invalid_payload() {
+        use crate::error::PayloadValidationError;
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::AstarteMessage;
+
+        const DEVICE_PROPS_IFACE: &str = r#"
+        {
+            "interface_name": "com.test.properties",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "properties",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/value",
+                    "type": "integer"
+                }
+            ]
+        }
+        "#;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk
+            .expect_send::<AstarteType>()
+            .never()
+            .returning(|_, _, _| Ok(()));
+
+        let astarte_handler = AstarteHandler::new(device_sdk);
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![DEVICE_PROPS_IFACE.to_string().into_bytes()],
+        );
+        astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        let astarte_message = AstarteMessage {
+            interface_name: "com.test.properties".to_string(),
+            path: "/value".to_string(),
+            payload: Some(Payload::AstarteData("5".into())),
+            timestamp: None,
+        };
+
+        let result = astarte_handler.publish(&astarte_message).await;
+        assert!(matches!(
+            result,
+            Err(AstarteMessageHubError::PayloadValidationError(
+                PayloadValidationError::TypeMismatch { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn replay_waits_for_connection() {
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::AstarteMessage;
+
+        const DEVICE_PROPS_IFACE: &str = r#"
+        {
+            "interface_name": "com.test.properties",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "properties",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/value",
+                    "type": "integer"
+                }
+            ]
+        }
+        "#;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk
+            .expect_handle_events()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Err(AstarteError::ConnectionError(
+                    rumqttc::ConnectionError::RequestsDone,
+                ))
+            });
+        device_sdk
+            .expect_handle_events()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Ok(AstarteDeviceDataEvent {
+                    interface: "com.test.object".to_string(),
+                    path: "/obj".to_string(),
+                    data: Aggregation::Individual(true.into()),
+                })
+            });
+        // The device accepts the messages also while disconnected
+        device_sdk
+            .expect_send()
+            .times(1)
+            .returning(|_: &str, _: &str, _: AstarteType| Ok(()));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut astarte_handler =
+            AstarteHandler::with_store_directory(device_sdk, dir.path(), Default::default())
+                .unwrap();
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![DEVICE_PROPS_IFACE.to_string().into_bytes()],
+        );
+        astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        astarte_handler.run().await;
+
+        let astarte_message = AstarteMessage {
+            interface_name: "com.test.properties".to_string(),
+            path: "/value".to_string(),
+            payload: Some(Payload::AstarteData(1.into())),
+            timestamp: None,
+        };
+        astarte_handler.publish(&astarte_message).await.unwrap();
+        assert_eq!(astarte_handler.queued_messages().await, 1);
+
+        // The queue is kept until the connection is restored
+        astarte_handler.replay_queue().await;
+        assert_eq!(astarte_handler.queued_messages().await, 1);
+
+        astarte_handler.run().await;
+        astarte_handler.replay_queue().await;
+        assert_eq!(astarte_handler.queued_messages().await, 0);
+    }
+
+    #[tokio::test]
+    async fn run_notifies_connection_events() {
+        use crate::proto_message_hub::ConnectionState;
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        device_sdk
+            .expect_handle_events()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Err(AstarteError::ConnectionError(
+                    rumqttc::ConnectionError::RequestsDone,
+                ))
+            });
+        device_sdk
+            .expect_handle_events()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Ok(AstarteDeviceDataEvent {
+                    interface: "com.test.object".to_string(),
+                    path: "/obj".to_string(),
+                    data: Aggregation::Individual(true.into()),
+                })
+            });
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+        let mut connection = astarte_handler.watch_connection();
+
+        assert_eq!(
+            connection.borrow_and_update().state(),
+            ConnectionState::Connected
+        );
+
+        for expected in [
+            ConnectionState::Disconnected,
+            ConnectionState::Reconnecting,
+            ConnectionState::Connected,
+        ] {
+            astarte_handler.run().await;
+
+            assert!(connection.has_changed().unwrap());
+            let event = connection.borrow_and_update().clone();
+            assert_eq!(event.state(), expected);
+            assert_eq!(
+                event.reason.is_empty(),
+                expected == ConnectionState::Connected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_persists_the_sent_messages() {
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::AstarteMessage;
+
+        const QUEUED: usize = REPLAY_PERSIST_BATCH + 4;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let store_directory = dir.path().to_path_buf();
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut calls = 0;
+        device_sdk.expect_send().times(QUEUED).returning(
+            move |_: &str, _: &str, _: AstarteType| {
+                // The messages sent in the first batch are no longer stored
+                if calls == REPLAY_PERSIST_BATCH {
+                    let stored = OutboundQueue::open(&store_directory, Default::default()).unwrap();
+                    assert_eq!(stored.len(), QUEUED - REPLAY_PERSIST_BATCH);
+                }
+                calls += 1;
+
+                Ok(())
+            },
+        );
+
+        let astarte_handler =
+            AstarteHandler::with_store_directory(device_sdk, dir.path(), Default::default())
+                .unwrap();
+
+        for value in 0..QUEUED {
+            let astarte_message = AstarteMessage {
+                interface_name: "com.test.properties".to_string(),
+                path: "/value".to_string(),
+                payload: Some(Payload::AstarteData((value as i32).into())),
+                timestamp: None,
+            };
+            astarte_handler
+                .queue
+                .write()
+                .await
+                .push(astarte_message)
+                .unwrap();
+        }
+
+        astarte_handler.replay_queue().await;
+
+        assert_eq!(astarte_handler.queued_messages().await, 0);
+        let stored = OutboundQueue::open(dir.path(), Default::default()).unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn publish_restores_connection_state() {
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::{AstarteMessage, ConnectionState};
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        device_sdk
+            .expect_handle_events()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| {
+                Err(AstarteError::ConnectionError(
+                    rumqttc::ConnectionError::RequestsDone,
+                ))
+            });
+        device_sdk
+            .expect_handle_events()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Err(AstarteError::Unreported));
+        device_sdk
+            .expect_send()
+            .times(1)
+            .returning(|_: &str, _: &str, _: AstarteType| Ok(()));
+
+        let mut astarte_handler = AstarteHandler::new(device_sdk);
+        let mut connection = astarte_handler.watch_connection();
+
+        astarte_handler.run().await;
+        assert_eq!(
+            connection.borrow_and_update().state(),
+            ConnectionState::Disconnected
+        );
+
+        // Polling the device or replaying an empty queue does not restore the connection
+        astarte_handler.run().await;
+        astarte_handler.replay_queue().await;
+        assert!(!connection.has_changed().unwrap());
+
+        let astarte_message = AstarteMessage {
+            interface_name: "io.demo.Unreliable".to_string(),
+            path: "/test".to_string(),
+            payload: Some(Payload::AstarteData(5.into())),
+            timestamp: None,
+        };
+        astarte_handler.publish(&astarte_message).await.unwrap();
+
+        assert_eq!(
+            connection.borrow_and_update().state(),
+            ConnectionState::Connected
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_queued_while_disconnected() {
+        use crate::proto_message_hub::astarte_message::Payload;
+        use crate::proto_message_hub::AstarteMessage;
+
+        const DEVICE_DATASTREAM_IFACE: &str = r#"
+        {
+            "interface_name": "com.test.guaranteed",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "datastream",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/value",
+                    "type": "integer",
+                    "reliability": "guaranteed"
+                },
+                {
+                    "endpoint": "/unreliable",
+                    "type": "integer"
+                }
+            ]
+        }
+        "#;
+
+        let message = |path: &str, value: i32| AstarteMessage {
+            interface_name: "com.test.guaranteed".to_string(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteData(value.into())),
+            timestamp: None,
+        };
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        let mut device_sdk = MockAstarteDeviceSdk::new();
+        let mut seq = mockall::Sequence::new();
+
+        device_sdk.expect_add_interface().returning(|_| Ok(()));
+        device_sdk
+            .expect_send()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|_: &str, _: &str, _: AstarteType| {
+                Err(AstarteError::BsonClientError(
+                    rumqttc::ClientError::Request(rumqttc::Request::Disconnect),
+                ))
+            });
+        let sent_cloned = sent.clone();
+        device_sdk
+            .expect_send()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(move |_: &str, _: &str, data: AstarteType| {
+                sent_cloned.lock().unwrap().push(data);
+                Ok(())
+            });
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let astarte_handler =
+            AstarteHandler::with_store_directory(device_sdk, dir.path(), Default::default())
+                .unwrap();
+
+        let astarte_node = AstarteNode::new(
+            "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            vec![DEVICE_DATASTREAM_IFACE.to_string().into_bytes()],
+        );
+        astarte_handler.subscribe(&astarte_node).await.unwrap();
+
+        // The unreliable mappings are not queued
+        let result = astarte_handler.publish(&message("/unreliable", 0)).await;
+        assert!(matches!(
+            result,
+            Err(AstarteMessageHubError::AstarteError(err))
+                if matches!(*err, AstarteError::BsonClientError(_))
+        ));
+        assert_eq!(astarte_handler.queued_messages().await, 0);
+
+        astarte_handler
+            .publish(&message("/value", 1))
+            .await
+            .unwrap();
+        astarte_handler
+            .publish(&message("/value", 2))
+            .await
+            .unwrap();
+        assert_eq!(astarte_handler.queued_messages().await, 2);
+
+        astarte_handler.replay_queue().await;
+
+        assert_eq!(astarte_handler.queued_messages().await, 0);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![AstarteType::Integer(1), AstarteType::Integer(2)]
+        );
+    }
+
     #[tokio::test]
     async fn detach_node_success() {
         let interfaces = vec![
@@ -18,25 +18,57 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+// The mock mirrors the SDK signatures, which return the unboxed errors
+#![allow(clippy::result_large_err)]
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
 use astarte_device_sdk::types::AstarteType;
 use astarte_device_sdk::{AstarteDeviceDataEvent, AstarteError, Interface};
 use mockall::mock;
 
+/// Mock of the Astarte device, its clones share the same expectations like the clones of the
+/// real device share the same connection.
 #[derive(Clone)]
-pub struct AstarteDeviceSdk {}
+pub struct MockAstarteDeviceSdk(Arc<MockDeviceSdk>);
+
+impl MockAstarteDeviceSdk {
+    pub fn new() -> Self {
+        Self(Arc::new(MockDeviceSdk::new()))
+    }
+
+    pub async fn handle_events(&mut self) -> Result<AstarteDeviceDataEvent, AstarteError> {
+        self.0.handle_events().await
+    }
+}
+
+impl Deref for MockAstarteDeviceSdk {
+    type Target = MockDeviceSdk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for MockAstarteDeviceSdk {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        Arc::get_mut(&mut self.0).expect("expectations must be set before cloning the mock")
+    }
+}
 
 mock! {
-    pub AstarteDeviceSdk {
-        pub async fn handle_events(&mut self) -> Result<AstarteDeviceDataEvent, AstarteError>;
-        pub async fn send<D: 'static>(
+    pub DeviceSdk {
+        pub async fn handle_events(&self) -> Result<AstarteDeviceDataEvent, AstarteError>;
+        pub async fn send<D>(
             &self,
             _interface_name: &str,
             _interface_path: &str,
             _data: D
         ) -> Result<(), AstarteError>
         where
-            D: Into<AstarteType>;
-        pub async fn send_with_timestamp<D: 'static>(
+            D: Into<AstarteType> + 'static;
+        pub async fn send_with_timestamp<D>(
             &self,
             _interface_name: &str,
             _interface_path: &str,
@@ -44,16 +76,16 @@ mock! {
             _timestamp: chrono::DateTime<chrono::Utc>
         ) -> Result<(), AstarteError>
         where
-            D: Into<AstarteType>;
-        pub async fn send_object<T: 'static>(
+            D: Into<AstarteType> + 'static;
+        pub async fn send_object<T>(
             &self,
             _interface_name: &str,
             _interface_path: &str,
             _data: T,
         ) -> Result<(), AstarteError>
         where
-            T: astarte_device_sdk::AstarteAggregate;
-        pub async fn send_object_with_timestamp<T: 'static>(
+            T: astarte_device_sdk::AstarteAggregate + 'static;
+        pub async fn send_object_with_timestamp<T>(
             &self,
             _interface_name: &str,
             _interface_path: &str,
@@ -61,7 +93,7 @@ mock! {
             _timestamp: chrono::DateTime<chrono::Utc>,
         ) -> Result<(), AstarteError>
         where
-            T: astarte_device_sdk::AstarteAggregate;
+            T: astarte_device_sdk::AstarteAggregate + 'static;
         pub async fn unset(
             &self,
             _interface_name: &str,
@@ -70,7 +102,4 @@ mock! {
         pub async fn add_interface(&self, _interface: Interface) -> Result<(), AstarteError>;
         pub async fn remove_interface(&self, _interface: &str) -> Result<(), AstarteError>;
     }
-    impl Clone for AstarteDeviceSdk {
-        fn clone(&self) -> Self;
-    }
 }
@@ -23,6 +23,11 @@
 
 pub(crate) mod astarte;
 pub mod astarte_handler;
+pub(crate) mod properties;
+mod queue;
+mod store;
+mod subscribers;
+mod validation;
 
 #[cfg(test)]
 mod mock_astarte_sdk;
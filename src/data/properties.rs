@@ -0,0 +1,637 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Cache of the server-owned properties received from Astarte.
+//!
+//! The last value of every server-owned property is kept so that a node attaching to the message
+//! hub can be synchronized with the current state of its properties interfaces.
+//!
+//! The cache is the database of the Astarte device: the device stores the properties received,
+//! deciding from its interface definitions, and deletes the ones purged by Astarte. The
+//! properties sent by the device are not stored, as for a device without a database.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use astarte_device_sdk::database::{AstarteDatabase, StoredProp};
+use astarte_device_sdk::types::AstarteType;
+use astarte_device_sdk::{Aggregation, AstarteDeviceDataEvent, AstarteError};
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use log::warn;
+use tokio::sync::RwLock;
+
+use crate::data::store;
+use crate::error::AstarteMessageHubError;
+use crate::interface::{InterfaceDefinition, InterfaceType, Ownership};
+use crate::proto_message_hub::AstarteMessage;
+
+/// Name of the file, inside the store directory, containing the cached properties.
+const PROPERTIES_FILE: &str = "cached_properties";
+
+/// Value of a property, stored with the major version of its interface.
+#[derive(Clone, PartialEq, prost::Message)]
+struct CachedProperty {
+    #[prost(int32, tag = "1")]
+    version_major: i32,
+    #[prost(message, optional, tag = "2")]
+    message: Option<AstarteMessage>,
+}
+
+/// Cache of the last received value for each server-owned property path.
+///
+/// Only the properties of the registered server-owned properties interfaces are cached. When
+/// created with [PropertyStore::open] the cache is persisted in the store directory, so that the
+/// values survive a restart of the message hub.
+#[derive(Debug, Default)]
+pub(crate) struct PropertyStore {
+    file: Option<PathBuf>,
+    interfaces: HashSet<String>,
+    properties: BTreeMap<(String, i32, String), AstarteMessage>,
+}
+
+impl PropertyStore {
+    /// Open the cache persisted in the given store directory, loading the stored values.
+    pub(crate) fn open(store_directory: &Path) -> Result<Self, AstarteMessageHubError> {
+        let file = store_directory.join(PROPERTIES_FILE);
+
+        let properties = store::read_messages::<CachedProperty>(&file)?
+            .into_iter()
+            .filter_map(|property| {
+                let message = property.message?;
+                let key = (
+                    message.interface_name.clone(),
+                    property.version_major,
+                    message.path.clone(),
+                );
+
+                Some((key, message))
+            })
+            .collect();
+
+        Ok(Self {
+            file: Some(file),
+            interfaces: HashSet::new(),
+            properties,
+        })
+    }
+
+    /// Cache the properties of an interface, if it is a server-owned properties interface.
+    pub(crate) fn register(&mut self, definition: &InterfaceDefinition) {
+        if definition.ownership == Ownership::Server
+            && definition.interface_type == InterfaceType::Properties
+        {
+            self.interfaces.insert(definition.interface_name.clone());
+        }
+    }
+
+    fn is_registered(&self, interface_name: &str) -> bool {
+        self.interfaces.contains(interface_name)
+    }
+
+    /// Store the value of a property, replacing the one of any other major version.
+    ///
+    /// An unset is kept until Astarte purges the property. Returns the snapshot to write on the
+    /// store directory, if the cache is persisted, so that the file is written without holding
+    /// the cache.
+    pub(crate) fn store(
+        &mut self,
+        version_major: i32,
+        message: AstarteMessage,
+    ) -> Option<PropertySnapshot> {
+        self.properties.retain(|(interface, _, path), _| {
+            interface != &message.interface_name || path != &message.path
+        });
+
+        let key = (
+            message.interface_name.clone(),
+            version_major,
+            message.path.clone(),
+        );
+        self.properties.insert(key, message);
+
+        self.snapshot()
+    }
+
+    /// Returns the cached value of a property with the given major version.
+    fn get(&self, interface_name: &str, version_major: i32, path: &str) -> Option<&AstarteMessage> {
+        self.properties
+            .get(&(interface_name.to_string(), version_major, path.to_string()))
+    }
+
+    /// Remove a property from the cache, whatever its major version.
+    pub(crate) fn remove(&mut self, interface_name: &str, path: &str) -> Option<PropertySnapshot> {
+        self.properties
+            .retain(|(interface, _, cached), _| interface != interface_name || cached != path);
+
+        self.snapshot()
+    }
+
+    /// Remove all the properties from the cache.
+    pub(crate) fn clear(&mut self) -> Option<PropertySnapshot> {
+        self.properties.clear();
+
+        self.snapshot()
+    }
+
+    /// Returns the cached values of all the properties of an interface with the given major
+    /// version, without the unset ones.
+    pub(crate) fn interface_properties(
+        &self,
+        interface_name: &str,
+        version_major: i32,
+    ) -> Vec<AstarteMessage> {
+        self.properties
+            .iter()
+            .filter(|((interface, major, _), message)| {
+                interface == interface_name && *major == version_major && message.unset().is_none()
+            })
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    fn snapshot(&self) -> Option<PropertySnapshot> {
+        self.file.as_ref().map(|file| PropertySnapshot {
+            file: file.clone(),
+            properties: self
+                .properties
+                .iter()
+                .map(|((_, version_major, _), message)| CachedProperty {
+                    version_major: *version_major,
+                    message: Some(message.clone()),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Content of the cache to write on the store directory.
+#[derive(Debug)]
+pub(crate) struct PropertySnapshot {
+    file: PathBuf,
+    properties: Vec<CachedProperty>,
+}
+
+impl PropertySnapshot {
+    /// Replace the persisted cache, blocking on the file system.
+    pub(crate) fn write(&self) -> Result<(), AstarteMessageHubError> {
+        store::write_messages(&self.file, &self.properties)
+    }
+}
+
+/// Server-owned properties of the device, shared with the Astarte device as its database.
+#[derive(Debug, Clone, Default)]
+pub struct ServerProperties {
+    store: Arc<RwLock<PropertyStore>>,
+}
+
+impl ServerProperties {
+    /// Create a cache of the properties that is kept only in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the cache of the properties persisted in the given store directory.
+    pub fn open(store_directory: &Path) -> Result<Self, AstarteMessageHubError> {
+        let store = PropertyStore::open(store_directory)?;
+
+        Ok(Self {
+            store: Arc::new(RwLock::new(store)),
+        })
+    }
+
+    /// Cache the properties of the server-owned properties interfaces among the given ones.
+    pub(crate) async fn register<'a, I>(&self, definitions: I)
+    where
+        I: IntoIterator<Item = &'a InterfaceDefinition>,
+    {
+        let mut store = self.store.write().await;
+
+        for definition in definitions {
+            store.register(definition);
+        }
+    }
+
+    /// Cache the properties of the server-owned properties interfaces in a directory.
+    pub(crate) async fn register_interfaces_directory(
+        &self,
+        dir: &Path,
+    ) -> Result<(), AstarteMessageHubError> {
+        let mut definitions = Vec::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension().map_or(false, |ext| ext == "json") {
+                definitions.push(InterfaceDefinition::from_json(&std::fs::read(&path)?)?);
+            }
+        }
+
+        self.register(&definitions).await;
+
+        Ok(())
+    }
+
+    /// Returns the cached values of the properties of an interface with the given major version.
+    pub(crate) async fn interface_properties(
+        &self,
+        interface_name: &str,
+        version_major: i32,
+    ) -> Vec<AstarteMessage> {
+        self.store
+            .read()
+            .await
+            .interface_properties(interface_name, version_major)
+    }
+
+    /// Write the cache without holding it or blocking the runtime.
+    async fn write(snapshot: Option<PropertySnapshot>) {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+
+        let res = tokio::task::spawn_blocking(move || snapshot.write())
+            .await
+            .unwrap_or_else(|err| Err(AstarteMessageHubError::FatalError(err.to_string())));
+
+        if let Err(err) = res {
+            warn!("Unable to store the properties cache: {:?}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl AstarteDatabase for ServerProperties {
+    /// Called by the device for the properties received and for the ones it sends, only the
+    /// first are cached.
+    async fn store_prop(
+        &self,
+        interface: &str,
+        path: &str,
+        value: &[u8],
+        interface_major: i32,
+    ) -> Result<(), AstarteError> {
+        let mut store = self.store.write().await;
+
+        if !store.is_registered(interface) {
+            return Ok(());
+        }
+
+        let message = AstarteMessage::try_from(AstarteDeviceDataEvent {
+            interface: interface.to_string(),
+            path: path.to_string(),
+            data: Aggregation::Individual(decode_value(value)?),
+        })
+        .map_err(|_| AstarteError::Conversion)?;
+
+        let snapshot = store.store(interface_major, message);
+        drop(store);
+
+        Self::write(snapshot).await;
+
+        Ok(())
+    }
+
+    async fn load_prop(
+        &self,
+        interface: &str,
+        path: &str,
+        interface_major: i32,
+    ) -> Result<Option<AstarteType>, AstarteError> {
+        let store = self.store.read().await;
+
+        let message = match store.get(interface, interface_major, path) {
+            Some(message) => message.clone(),
+            None => return Ok(None),
+        };
+
+        match AstarteDeviceDataEvent::try_from(message) {
+            Ok(AstarteDeviceDataEvent {
+                data: Aggregation::Individual(value),
+                ..
+            }) => Ok(Some(value)),
+            _ => Err(AstarteError::Conversion),
+        }
+    }
+
+    /// Called by the device for the properties purged by Astarte.
+    async fn delete_prop(&self, interface: &str, path: &str) -> Result<(), AstarteError> {
+        let snapshot = self.store.write().await.remove(interface, path);
+
+        Self::write(snapshot).await;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), AstarteError> {
+        let snapshot = self.store.write().await.clear();
+
+        Self::write(snapshot).await;
+
+        Ok(())
+    }
+
+    /// Returns the cached properties of the registered interfaces, compared by the device with
+    /// the ones set on Astarte to purge the others.
+    async fn load_all_props(&self) -> Result<Vec<StoredProp>, AstarteError> {
+        let store = self.store.read().await;
+
+        let mut props = Vec::new();
+        for ((interface, interface_major, path), message) in store.properties.iter() {
+            if !store.is_registered(interface) {
+                continue;
+            }
+
+            let value = match AstarteDeviceDataEvent::try_from(message.clone()) {
+                Ok(AstarteDeviceDataEvent {
+                    data: Aggregation::Individual(value),
+                    ..
+                }) => encode_value(value)?,
+                _ => return Err(AstarteError::Conversion),
+            };
+
+            props.push(StoredProp {
+                interface: interface.clone(),
+                path: path.clone(),
+                value,
+                interface_major: *interface_major,
+            });
+        }
+
+        Ok(props)
+    }
+}
+
+/// Decode the BSON payload of a property, empty for an unset.
+#[allow(clippy::result_large_err)]
+fn decode_value(value: &[u8]) -> Result<AstarteType, AstarteError> {
+    if value.is_empty() {
+        return Ok(AstarteType::Unset);
+    }
+
+    let document =
+        Document::from_reader(&mut &value[..]).map_err(|_| AstarteError::DeserializationError)?;
+
+    match document.get("v") {
+        Some(Bson::Document(_)) | None => Err(AstarteError::DeserializationError),
+        Some(value) => AstarteType::try_from(value.clone()),
+    }
+}
+
+/// Encode the value of a property as the BSON payload sent by Astarte.
+#[allow(clippy::result_large_err)]
+pub(crate) fn encode_value(value: AstarteType) -> Result<Vec<u8>, AstarteError> {
+    if let AstarteType::Unset = value {
+        return Ok(Vec::new());
+    }
+
+    let mut document = Document::new();
+    document.insert("v", Bson::from(value));
+
+    let mut buf = Vec::new();
+    document.to_writer(&mut buf)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::proto_message_hub::astarte_message::Payload;
+    use crate::proto_message_hub::AstarteUnset;
+
+    const SERVER_PROPERTIES: &str = r#"
+    {
+        "interface_name": "com.test.Props",
+        "version_major": 1,
+        "version_minor": 0,
+        "type": "properties",
+        "ownership": "server",
+        "mappings": [{ "endpoint": "/%{name}", "type": "integer", "allow_unset": true }]
+    }
+    "#;
+
+    fn property(interface_name: &str, path: &str, value: i32) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: interface_name.to_string(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteData(value.into())),
+            timestamp: None,
+        }
+    }
+
+    fn unset(interface_name: &str, path: &str) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: interface_name.to_string(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteUnset(AstarteUnset {})),
+            timestamp: None,
+        }
+    }
+
+    fn definition(json: &str) -> InterfaceDefinition {
+        InterfaceDefinition::from_json(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn store_and_unset_property() {
+        let mut store = PropertyStore::default();
+
+        store.store(0, property("com.test.Props", "/a", 1));
+        store.store(0, property("com.test.Props", "/a", 2));
+        store.store(0, property("com.test.Props", "/b", 3));
+        store.store(0, property("com.test.Other", "/a", 4));
+
+        let cached = store.interface_properties("com.test.Props", 0);
+        assert_eq!(
+            cached,
+            vec![
+                property("com.test.Props", "/a", 2),
+                property("com.test.Props", "/b", 3)
+            ]
+        );
+
+        store.store(0, unset("com.test.Props", "/a"));
+
+        let cached = store.interface_properties("com.test.Props", 0);
+        assert_eq!(cached, vec![property("com.test.Props", "/b", 3)]);
+        assert_eq!(
+            store.get("com.test.Props", 0, "/a"),
+            Some(&unset("com.test.Props", "/a"))
+        );
+
+        store.remove("com.test.Props", "/a");
+        assert_eq!(store.get("com.test.Props", 0, "/a"), None);
+    }
+
+    #[test]
+    fn properties_of_the_major_version() {
+        let mut store = PropertyStore::default();
+
+        store.store(0, property("com.test.Props", "/a", 1));
+        store.store(0, property("com.test.Props", "/b", 2));
+        store.store(1, property("com.test.Props", "/a", 3));
+
+        assert_eq!(
+            store.interface_properties("com.test.Props", 0),
+            vec![property("com.test.Props", "/b", 2)]
+        );
+        assert_eq!(
+            store.interface_properties("com.test.Props", 1),
+            vec![property("com.test.Props", "/a", 3)]
+        );
+    }
+
+    #[test]
+    fn reopen_persisted_properties() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut store = PropertyStore::open(dir.path()).unwrap();
+        assert!(store.interface_properties("com.test.Props", 0).is_empty());
+
+        store
+            .store(0, property("com.test.Props", "/a", 1))
+            .unwrap()
+            .write()
+            .unwrap();
+        store
+            .store(1, property("com.test.Props", "/b", 2))
+            .unwrap()
+            .write()
+            .unwrap();
+
+        let store = PropertyStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.interface_properties("com.test.Props", 0),
+            vec![property("com.test.Props", "/a", 1)]
+        );
+        assert_eq!(
+            store.interface_properties("com.test.Props", 1),
+            vec![property("com.test.Props", "/b", 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn database_of_the_server_properties() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let properties = ServerProperties::open(dir.path()).unwrap();
+
+        properties
+            .register(&[
+                definition(SERVER_PROPERTIES),
+                definition(
+                    &SERVER_PROPERTIES
+                        .replace("com.test.Props", "com.test.DeviceProps")
+                        .replace(r#""server""#, r#""device""#),
+                ),
+            ])
+            .await;
+
+        let value = encode_value(AstarteType::Integer(5)).unwrap();
+        properties
+            .store_prop("com.test.Props", "/a", &value, 1)
+            .await
+            .unwrap();
+        properties
+            .store_prop("com.test.Props", "/b", &[], 1)
+            .await
+            .unwrap();
+
+        // The properties sent by the device are not stored
+        properties
+            .store_prop("com.test.DeviceProps", "/a", &value, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            properties
+                .load_prop("com.test.DeviceProps", "/a", 1)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            properties
+                .load_prop("com.test.Props", "/a", 1)
+                .await
+                .unwrap(),
+            Some(AstarteType::Integer(5))
+        );
+        assert_eq!(
+            properties
+                .load_prop("com.test.Props", "/b", 1)
+                .await
+                .unwrap(),
+            Some(AstarteType::Unset)
+        );
+        assert_eq!(
+            properties
+                .load_prop("com.test.Props", "/a", 0)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            properties.interface_properties("com.test.Props", 1).await,
+            vec![property("com.test.Props", "/a", 5)]
+        );
+
+        let stored = properties.load_all_props().await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].value, value);
+        assert_eq!(stored[0].interface_major, 1);
+        assert!(stored[1].value.is_empty());
+
+        // Astarte purges the properties that are no longer set
+        properties
+            .delete_prop("com.test.Props", "/a")
+            .await
+            .unwrap();
+        assert!(properties
+            .interface_properties("com.test.Props", 1)
+            .await
+            .is_empty());
+
+        let reopened = PropertyStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.get("com.test.Props", 1, "/a"), None);
+        assert_eq!(
+            reopened.get("com.test.Props", 1, "/b"),
+            Some(&unset("com.test.Props", "/b"))
+        );
+    }
+
+    #[test]
+    fn encode_and_decode_values() {
+        for value in [
+            AstarteType::Integer(1),
+            AstarteType::String("value".to_string()),
+            AstarteType::DoubleArray(vec![1.0, 2.5]),
+            AstarteType::Unset,
+        ] {
+            let encoded = encode_value(value.clone()).unwrap();
+            assert_eq!(decode_value(&encoded).unwrap(), value);
+        }
+
+        assert!(decode_value(&[1, 2, 3]).is_err());
+    }
+}
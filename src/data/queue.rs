@@ -0,0 +1,277 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Persistent queue of the messages that could not be sent to Astarte.
+//!
+//! The messages published while Astarte is unreachable are retained in the queue, in the order
+//! they were received, and sent again once the connection is restored.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::config::{EvictionPolicy, QueueOptions};
+use crate::data::store;
+use crate::error::AstarteMessageHubError;
+use crate::proto_message_hub::AstarteMessage;
+
+/// Name of the file, inside the store directory, containing the queued messages.
+const QUEUE_FILE: &str = "outbound_queue";
+
+/// Bounded FIFO queue of messages, optionally persisted in the store directory.
+#[derive(Debug, Default)]
+pub(crate) struct OutboundQueue {
+    file: Option<PathBuf>,
+    options: QueueOptions,
+    messages: VecDeque<AstarteMessage>,
+}
+
+impl OutboundQueue {
+    /// Create a queue that is kept only in memory.
+    pub(crate) fn new(options: QueueOptions) -> Self {
+        Self {
+            file: None,
+            options,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Open the queue persisted in the given store directory, loading the stored messages.
+    pub(crate) fn open(
+        store_directory: &Path,
+        options: QueueOptions,
+    ) -> Result<Self, AstarteMessageHubError> {
+        let file = store_directory.join(QUEUE_FILE);
+        let messages = store::read_messages::<AstarteMessage>(&file)?.into();
+
+        let mut queue = Self {
+            file: Some(file),
+            options,
+            messages,
+        };
+
+        if queue.messages.len() > queue.options.max_size {
+            warn!(
+                "stored queue exceeds the maximum size, discarding {} messages",
+                queue.messages.len() - queue.options.max_size
+            );
+
+            queue.evict(queue.messages.len() - queue.options.max_size);
+            queue.persist()?;
+        }
+
+        Ok(queue)
+    }
+
+    /// Change the options of the queue, discarding the oldest messages exceeding the new maximum
+    /// size.
+    pub(crate) fn set_options(
+        &mut self,
+        options: QueueOptions,
+    ) -> Result<(), AstarteMessageHubError> {
+        self.options = options;
+
+        if self.messages.len() > self.options.max_size {
+            warn!(
+                "queue exceeds the new maximum size, discarding {} messages",
+                self.messages.len() - self.options.max_size
+            );
+
+            self.evict(self.messages.len() - self.options.max_size);
+            self.persist()?;
+        }
+
+        Ok(())
+    }
+
+    /// Interval in seconds between the attempts to send the queued messages.
+    pub(crate) fn retry_interval(&self) -> u64 {
+        self.options.retry_interval
+    }
+
+    /// Whether the queue can retain messages.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.options.max_size > 0
+    }
+
+    /// Append a message to the queue, applying the eviction policy when the queue is full.
+    pub(crate) fn push(&mut self, message: AstarteMessage) -> Result<(), AstarteMessageHubError> {
+        if !self.is_enabled() {
+            return Err(AstarteMessageHubError::QueueFull);
+        }
+
+        if self.messages.len() >= self.options.max_size {
+            match self.options.eviction_policy {
+                EvictionPolicy::DropNewest => return Err(AstarteMessageHubError::QueueFull),
+                EvictionPolicy::DropOldest => {
+                    warn!("outbound queue is full, discarding the oldest message");
+
+                    self.evict(self.messages.len() + 1 - self.options.max_size);
+                    self.messages.push_back(message);
+
+                    return self.persist();
+                }
+            }
+        }
+
+        if let Some(file) = &self.file {
+            store::append_message(file, &message)?;
+        }
+
+        self.messages.push_back(message);
+
+        Ok(())
+    }
+
+    /// Returns the oldest message in the queue.
+    pub(crate) fn front(&self) -> Option<&AstarteMessage> {
+        self.messages.front()
+    }
+
+    /// Remove the oldest message in the queue.
+    ///
+    /// The change is not written to the store directory until [OutboundQueue::persist] is called.
+    pub(crate) fn pop_front(&mut self) -> Option<AstarteMessage> {
+        self.messages.pop_front()
+    }
+
+    /// Number of messages in the queue.
+    pub(crate) fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Whether the queue contains no messages.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Write the queue on the store directory, if any.
+    pub(crate) fn persist(&self) -> Result<(), AstarteMessageHubError> {
+        match &self.file {
+            Some(file) => store::write_messages(file, &self.messages),
+            None => Ok(()),
+        }
+    }
+
+    fn evict(&mut self, count: usize) {
+        self.messages.drain(..count.min(self.messages.len()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::proto_message_hub::astarte_message::Payload;
+
+    fn message(value: i32) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: "com.test.Datastream".to_string(),
+            path: "/value".to_string(),
+            payload: Some(Payload::AstarteData(value.into())),
+            timestamp: None,
+        }
+    }
+
+    fn options(max_size: usize, eviction_policy: EvictionPolicy) -> QueueOptions {
+        QueueOptions {
+            max_size,
+            eviction_policy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn eviction_policies() {
+        let mut queue = OutboundQueue::new(options(2, EvictionPolicy::DropOldest));
+
+        for value in 0..3 {
+            queue.push(message(value)).unwrap();
+        }
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop_front(), Some(message(1)));
+        assert_eq!(queue.pop_front(), Some(message(2)));
+        assert!(queue.is_empty());
+
+        let mut queue = OutboundQueue::new(options(2, EvictionPolicy::DropNewest));
+
+        queue.push(message(0)).unwrap();
+        queue.push(message(1)).unwrap();
+        assert!(matches!(
+            queue.push(message(2)),
+            Err(AstarteMessageHubError::QueueFull)
+        ));
+        assert_eq!(queue.front(), Some(&message(0)));
+
+        let mut queue = OutboundQueue::new(options(0, EvictionPolicy::DropOldest));
+        assert!(queue.push(message(0)).is_err());
+    }
+
+    #[test]
+    fn shrink_queue() {
+        let mut queue = OutboundQueue::new(options(3, EvictionPolicy::DropOldest));
+
+        for value in 0..3 {
+            queue.push(message(value)).unwrap();
+        }
+
+        queue
+            .set_options(options(1, EvictionPolicy::DropNewest))
+            .unwrap();
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.front(), Some(&message(2)));
+        assert!(queue.push(message(3)).is_err());
+    }
+
+    #[test]
+    fn reopen_persisted_queue() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut queue =
+            OutboundQueue::open(dir.path(), options(10, EvictionPolicy::DropOldest)).unwrap();
+        assert!(queue.is_empty());
+
+        for value in 0..3 {
+            queue.push(message(value)).unwrap();
+        }
+        queue.pop_front();
+        queue.persist().unwrap();
+
+        let mut queue =
+            OutboundQueue::open(dir.path(), options(10, EvictionPolicy::DropOldest)).unwrap();
+        assert_eq!(queue.pop_front(), Some(message(1)));
+        assert_eq!(queue.pop_front(), Some(message(2)));
+        assert!(queue.is_empty());
+
+        queue.push(message(3)).unwrap();
+
+        let queue =
+            OutboundQueue::open(dir.path(), options(10, EvictionPolicy::DropOldest)).unwrap();
+        assert_eq!(queue.front(), Some(&message(1)));
+        assert_eq!(queue.len(), 3);
+
+        let queue =
+            OutboundQueue::open(dir.path(), options(1, EvictionPolicy::DropOldest)).unwrap();
+        assert_eq!(queue.front(), Some(&message(3)));
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Helpers to persist the protobuf messages, like the [AstarteMessage]s, in the store directory.
+//!
+//! The messages are stored in a file as a sequence of length delimited protobuf messages.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use log::warn;
+use prost::Message;
+
+use crate::error::AstarteMessageHubError;
+use crate::persist::{self, PRIVATE_MODE};
+#[cfg(doc)]
+use crate::proto_message_hub::AstarteMessage;
+
+/// Read all the messages stored in a file, a missing file contains no messages.
+pub(crate) fn read_messages<M>(file: &Path) -> Result<Vec<M>, AstarteMessageHubError>
+where
+    M: Message + Default,
+{
+    let content = match fs::read(file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut buf = content.as_slice();
+    let mut messages = Vec::new();
+
+    while !buf.is_empty() {
+        // The decode consumes the length even when the record is truncated
+        let valid_len = content.len() - buf.len();
+
+        match M::decode_length_delimited(&mut buf) {
+            Ok(message) => messages.push(message),
+            Err(err) => {
+                // A crash while appending leaves a partial record at the end of the file
+                warn!(
+                    "discarding the truncated record at the end of {}: {}",
+                    file.display(),
+                    err
+                );
+
+                truncate(file, valid_len)?;
+
+                break;
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Truncate a file to the given length.
+fn truncate(file: &Path, len: usize) -> Result<(), AstarteMessageHubError> {
+    let file = fs::OpenOptions::new().write(true).open(file)?;
+
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Replace the content of a file with the given messages.
+pub(crate) fn write_messages<'a, M, I>(
+    file: &Path,
+    messages: I,
+) -> Result<(), AstarteMessageHubError>
+where
+    M: Message + 'a,
+    I: IntoIterator<Item = &'a M>,
+{
+    let content = messages
+        .into_iter()
+        .flat_map(|message| message.encode_length_delimited_to_vec())
+        .collect::<Vec<u8>>();
+
+    persist::write_private(file, content)?;
+
+    Ok(())
+}
+
+/// Append a message at the end of a file.
+pub(crate) fn append_message<M>(file: &Path, message: &M) -> Result<(), AstarteMessageHubError>
+where
+    M: Message,
+{
+    use std::io::Write;
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(PRIVATE_MODE)
+        .open(file)?;
+
+    file.write_all(&message.encode_length_delimited_to_vec())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::proto_message_hub::AstarteMessage;
+
+    fn message(path: &str) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: "org.astarte-platform.test.Test".to_string(),
+            path: path.to_string(),
+            payload: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn truncated_record_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("messages.pb");
+
+        write_messages(&file, &[message("/first")]).unwrap();
+        let valid_len = fs::metadata(&file).unwrap().len();
+
+        // Partial record left by a crash while appending
+        let record = message("/second").encode_length_delimited_to_vec();
+        let mut content = fs::read(&file).unwrap();
+        content.extend_from_slice(&record[..record.len() - 3]);
+        fs::write(&file, content).unwrap();
+
+        let messages = read_messages::<AstarteMessage>(&file).unwrap();
+        assert_eq!(messages, vec![message("/first")]);
+        assert_eq!(fs::metadata(&file).unwrap().len(), valid_len);
+
+        append_message(&file, &message("/third")).unwrap();
+        assert_eq!(
+            read_messages::<AstarteMessage>(&file).unwrap(),
+            vec![message("/first"), message("/third")]
+        );
+    }
+}
@@ -0,0 +1,762 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Routing table and interface registry for the subscribers of the Astarte handler.
+//!
+//! The device introspection holds a single version of each interface, shared by all the nodes
+//! declaring it. Every registered interface keeps the set of nodes using it, so that it is removed
+//! from the device only when the last of them detaches.
+//!
+//! Two nodes can declare the same interface only if the declarations are compatible:
+//! - the major versions must be equal;
+//! - with the same minor version the definitions must be equal;
+//! - the newer minor version must contain all the mappings of the older one.
+//!
+//! When a node declares a newer compatible minor version the registered interface is upgraded,
+//! a node declaring an older compatible minor version uses the registered one.
+//!
+//! A node attaching again with the same id replaces its previous subscriber, the unchanged
+//! interfaces stay registered and the events received while the node was disconnected are
+//! replayed to the new subscriber.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+use astarte_device_sdk::Interface;
+use log::{debug, warn};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Mutex, Notify};
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::config::{OverflowPolicy, SessionOptions};
+use crate::error::AstarteMessageHubError;
+use crate::interface::InterfaceDefinition;
+use crate::proto_message_hub::AstarteMessage;
+
+/// A subscriber for the Astarte handler.
+///
+/// The events are buffered and delivered to the node by a dedicated task, so that a node not
+/// reading its stream does not delay the others. The task stops when the subscriber is dropped.
+pub(crate) struct Subscriber {
+    pub(crate) introspection: Vec<Interface>,
+    delivery: Arc<Delivery>,
+}
+
+impl Subscriber {
+    fn new(
+        id: Uuid,
+        introspection: Vec<Interface>,
+        sender: Sender<Result<AstarteMessage, Status>>,
+        pending: Vec<AstarteMessage>,
+        options: &SessionOptions,
+    ) -> Self {
+        let delivery = Arc::new(Delivery {
+            id,
+            state: Mutex::new(DeliveryState {
+                pending: pending.into(),
+                ..Default::default()
+            }),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            buffer_size: options.buffer_size.max(1),
+            overflow_policy: options.overflow_policy,
+            replay_buffer_size: options.replay_buffer_size,
+        });
+
+        tokio::spawn(Delivery::run(delivery.clone(), sender));
+
+        Self {
+            introspection,
+            delivery,
+        }
+    }
+
+    /// Queue an event for the subscriber.
+    ///
+    /// When the buffer is full the overflow policy is applied. If the node disconnected the event
+    /// is buffered to be replayed when the node attaches again.
+    pub(crate) async fn send(&self, message: AstarteMessage) {
+        let delivery = &self.delivery;
+        let mut state = delivery.state.lock().await;
+
+        if state.disconnected {
+            delivery.buffer_replay(&mut state, message);
+            return;
+        }
+
+        if state.pending.len() >= delivery.buffer_size {
+            match delivery.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    state.pending.pop_front();
+                    delivery.count_dropped(1);
+                }
+                OverflowPolicy::DropNewest => {
+                    delivery.count_dropped(1);
+                    return;
+                }
+                OverflowPolicy::Disconnect => {
+                    warn!(
+                        "Node {} is not reading its events, disconnecting it",
+                        delivery.id
+                    );
+
+                    delivery.count_dropped(state.pending.len() as u64);
+                    state.pending.clear();
+                    state.disconnected = true;
+                    delivery.buffer_replay(&mut state, message);
+                    delivery.notify.notify_one();
+                    return;
+                }
+            }
+        }
+
+        state.pending.push_back(message);
+        delivery.notify.notify_one();
+    }
+
+    /// Number of events dropped because the buffer of the node was full.
+    pub(crate) fn dropped_messages(&self) -> u64 {
+        self.delivery.dropped.load(AtomicOrdering::Relaxed)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.delivery.closed.store(true, AtomicOrdering::SeqCst);
+        self.delivery.notify.notify_one();
+    }
+}
+
+/// Buffer of the events waiting to be delivered to a node.
+struct Delivery {
+    id: Uuid,
+    state: Mutex<DeliveryState>,
+    /// Notified when an event is queued or the delivery stops.
+    notify: Notify,
+    /// Set when the subscriber is removed.
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    buffer_size: usize,
+    overflow_policy: OverflowPolicy,
+    replay_buffer_size: usize,
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    pending: VecDeque<AstarteMessage>,
+    /// Events received after the node disconnected, to replay when it attaches again.
+    replay: VecDeque<AstarteMessage>,
+    disconnected: bool,
+}
+
+impl Delivery {
+    /// Deliver the pending events to the node, until the subscriber is removed or the node
+    /// disconnects.
+    ///
+    /// An event is removed from the pending ones only when there is room for it in the node
+    /// stream, so that it is replayed if the node disconnects in the meantime.
+    async fn run(delivery: Arc<Delivery>, sender: Sender<Result<AstarteMessage, Status>>) {
+        loop {
+            // Wait for an event to deliver, noticing the node dropping its stream meanwhile
+            loop {
+                {
+                    let state = delivery.state.lock().await;
+
+                    if state.disconnected || delivery.closed.load(AtomicOrdering::SeqCst) {
+                        return;
+                    }
+
+                    if !state.pending.is_empty() {
+                        break;
+                    }
+                }
+
+                tokio::select! {
+                    _ = delivery.notify.notified() => {}
+                    // Reserving fails below, disconnecting the node
+                    _ = sender.closed() => break,
+                }
+            }
+
+            // Wait for room in the node stream, waking up if the delivery stops
+            let permit = tokio::select! {
+                permit = sender.reserve() => permit,
+                _ = delivery.notify.notified() => continue,
+            };
+
+            let permit = match permit {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!("Node {} disconnected", delivery.id);
+
+                    delivery.disconnect().await;
+                    break;
+                }
+            };
+
+            let mut state = delivery.state.lock().await;
+
+            if state.disconnected || delivery.closed.load(AtomicOrdering::SeqCst) {
+                break;
+            }
+
+            if let Some(message) = state.pending.pop_front() {
+                permit.send(Ok(message));
+            }
+        }
+    }
+
+    /// Mark the node as disconnected, moving the pending events to the replay buffer.
+    async fn disconnect(&self) {
+        let mut state = self.state.lock().await;
+
+        state.disconnected = true;
+
+        let pending = std::mem::take(&mut state.pending);
+        for message in pending {
+            self.buffer_replay(&mut state, message);
+        }
+    }
+
+    fn buffer_replay(&self, state: &mut DeliveryState, message: AstarteMessage) {
+        if self.replay_buffer_size == 0 {
+            return;
+        }
+
+        if state.replay.len() >= self.replay_buffer_size {
+            state.replay.pop_front();
+        }
+
+        state.replay.push_back(message);
+    }
+
+    fn count_dropped(&self, count: u64) {
+        let dropped = self.dropped.fetch_add(count, AtomicOrdering::Relaxed) + count;
+
+        debug!("Dropped {} events for node {}", dropped, self.id);
+    }
+}
+
+/// An interface registered on the device.
+struct RegisteredInterface {
+    interface: Interface,
+    definition: InterfaceDefinition,
+    nodes: HashSet<Uuid>,
+}
+
+/// Routing table for the subscribers of the Astarte handler.
+///
+/// Keeps an index from each interface name to the nodes that declared it in their introspection,
+/// so that an incoming event is delivered only to the nodes with an exact interface match.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    nodes: HashMap<Uuid, Subscriber>,
+    interfaces: HashMap<String, RegisteredInterface>,
+    options: SessionOptions,
+}
+
+impl Subscribers {
+    /// Create the routing table, buffering the events for each node as configured in the
+    /// session options.
+    pub(crate) fn new(options: SessionOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Check the introspection of a node against the registered interfaces.
+    ///
+    /// Returns the interfaces to add to the device, either because they are not registered or
+    /// because they upgrade the registered minor version.
+    pub(crate) fn plan(
+        &self,
+        id: &Uuid,
+        introspection: &[(Interface, InterfaceDefinition)],
+    ) -> Result<Vec<Interface>, AstarteMessageHubError> {
+        let mut to_add = Vec::new();
+
+        for (interface, definition) in introspection {
+            let registered = match self.interfaces.get(&definition.interface_name) {
+                // Interfaces used only by the same node can be replaced, if changed
+                Some(registered) if registered.nodes.iter().all(|node| node == id) => {
+                    if registered.definition != *definition {
+                        to_add.push(interface.clone());
+                    }
+                    continue;
+                }
+                Some(registered) => registered,
+                None => {
+                    to_add.push(interface.clone());
+                    continue;
+                }
+            };
+
+            if Self::check_compatible(&registered.definition, definition)? {
+                to_add.push(interface.clone());
+            }
+        }
+
+        Ok(to_add)
+    }
+
+    /// Check that a definition is compatible with the registered one, returns whether it
+    /// upgrades the registered interface.
+    fn check_compatible(
+        registered: &InterfaceDefinition,
+        definition: &InterfaceDefinition,
+    ) -> Result<bool, AstarteMessageHubError> {
+        let conflict = |reason: String| AstarteMessageHubError::InterfaceConflict {
+            name: definition.interface_name.clone(),
+            reason,
+        };
+
+        if registered.version_major != definition.version_major {
+            return Err(conflict(format!(
+                "major version {} differs from the registered {}",
+                definition.version_major, registered.version_major
+            )));
+        }
+
+        if registered.interface_type != definition.interface_type
+            || registered.ownership != definition.ownership
+            || registered.aggregation != definition.aggregation
+        {
+            return Err(conflict(
+                "type, ownership or aggregation differ from the registered interface".to_string(),
+            ));
+        }
+
+        let contains = |newer: &InterfaceDefinition, older: &InterfaceDefinition| {
+            older
+                .mappings
+                .iter()
+                .all(|mapping| newer.mappings.contains(mapping))
+        };
+
+        match definition.version_minor.cmp(&registered.version_minor) {
+            Ordering::Equal if definition == registered => Ok(false),
+            Ordering::Equal => Err(conflict(format!(
+                "mappings differ from the registered version {}.{}",
+                registered.version_major, registered.version_minor
+            ))),
+            Ordering::Greater if contains(definition, registered) => Ok(true),
+            Ordering::Less if contains(registered, definition) => Ok(false),
+            _ => Err(conflict(format!(
+                "mappings of version {}.{} are not compatible with the registered version {}.{}",
+                definition.version_major,
+                definition.version_minor,
+                registered.version_major,
+                registered.version_minor
+            ))),
+        }
+    }
+
+    /// Insert a subscriber, replacing any previous one with the same id.
+    ///
+    /// The pending messages are delivered before any event sent afterwards, regardless of the
+    /// size of the buffer. The introspection must have been checked with [Subscribers::plan].
+    pub(crate) fn insert(
+        &mut self,
+        id: Uuid,
+        introspection: Vec<(Interface, InterfaceDefinition)>,
+        sender: Sender<Result<AstarteMessage, Status>>,
+        pending: Vec<AstarteMessage>,
+    ) {
+        self.remove(&id);
+
+        let mut interfaces = Vec::with_capacity(introspection.len());
+
+        for (interface, definition) in introspection {
+            interfaces.push(interface.clone());
+
+            match self.interfaces.get_mut(&definition.interface_name) {
+                Some(registered) => {
+                    if definition.version_minor > registered.definition.version_minor {
+                        registered.interface = interface;
+                        registered.definition = definition;
+                    }
+
+                    registered.nodes.insert(id);
+                }
+                None => {
+                    self.interfaces.insert(
+                        definition.interface_name.clone(),
+                        RegisteredInterface {
+                            interface,
+                            definition,
+                            nodes: HashSet::from([id]),
+                        },
+                    );
+                }
+            }
+        }
+
+        self.nodes.insert(
+            id,
+            Subscriber::new(id, interfaces, sender, pending, &self.options),
+        );
+    }
+
+    /// Names of the interfaces used only by the given node and missing from its new
+    /// introspection, to remove from the device when the node attaches again.
+    pub(crate) fn stale_interfaces(
+        &self,
+        id: &Uuid,
+        introspection: &[(Interface, InterfaceDefinition)],
+    ) -> Vec<String> {
+        self.exclusive_interfaces(id)
+            .into_iter()
+            .filter(|name| {
+                !introspection
+                    .iter()
+                    .any(|(_, definition)| definition.interface_name == *name)
+            })
+            .collect()
+    }
+
+    /// Take the events buffered while the node was disconnected, followed by the events not yet
+    /// delivered.
+    pub(crate) async fn take_replay(&self, id: &Uuid) -> VecDeque<AstarteMessage> {
+        let subscriber = match self.nodes.get(id) {
+            Some(subscriber) => subscriber,
+            None => return VecDeque::new(),
+        };
+
+        let mut state = subscriber.delivery.state.lock().await;
+        let mut events = std::mem::take(&mut state.replay);
+        events.append(&mut state.pending);
+
+        events
+    }
+
+    /// Number of events dropped for each node because its buffer was full.
+    pub(crate) fn dropped_messages(&self) -> HashMap<Uuid, u64> {
+        self.nodes
+            .iter()
+            .map(|(id, subscriber)| (*id, subscriber.dropped_messages()))
+            .collect()
+    }
+
+    /// Remove a subscriber and release its interfaces.
+    pub(crate) fn remove(&mut self, id: &Uuid) -> Option<Subscriber> {
+        let subscriber = self.nodes.remove(id)?;
+
+        for interface in subscriber.introspection.iter() {
+            let name = interface.get_name();
+
+            if let Some(registered) = self.interfaces.get_mut(&name) {
+                registered.nodes.remove(id);
+
+                if registered.nodes.is_empty() {
+                    self.interfaces.remove(&name);
+                }
+            }
+        }
+
+        Some(subscriber)
+    }
+
+    /// Check if a node is subscribed.
+    pub(crate) fn contains(&self, id: &Uuid) -> bool {
+        self.nodes.contains_key(id)
+    }
+
+    /// Names of the interfaces used only by the given node, to remove from the device when the
+    /// node unsubscribes.
+    pub(crate) fn exclusive_interfaces(&self, id: &Uuid) -> Vec<String> {
+        self.interfaces
+            .iter()
+            .filter(|(_, registered)| registered.nodes.len() == 1 && registered.nodes.contains(id))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Iterate over the registered interfaces, to add them to a new device.
+    pub(crate) fn interfaces(&self) -> impl Iterator<Item = &Interface> {
+        self.interfaces
+            .values()
+            .map(|registered| &registered.interface)
+    }
+
+    /// Change the session options, applied to the nodes subscribing from now on.
+    pub(crate) fn set_options(&mut self, options: SessionOptions) {
+        self.options = options;
+    }
+
+    /// Returns the registered interface with the given name.
+    pub(crate) fn interface(&self, interface_name: &str) -> Option<&Interface> {
+        self.interfaces
+            .get(interface_name)
+            .map(|registered| &registered.interface)
+    }
+
+    /// Returns the definition of the registered interface with the given name.
+    pub(crate) fn definition(&self, interface_name: &str) -> Option<&InterfaceDefinition> {
+        self.interfaces
+            .get(interface_name)
+            .map(|registered| &registered.definition)
+    }
+
+    /// Iterate over the subscribers that declared the given interface.
+    pub(crate) fn subscribed_to<'a>(
+        &'a self,
+        interface_name: &str,
+    ) -> impl Iterator<Item = &'a Subscriber> {
+        self.interfaces
+            .get(interface_name)
+            .into_iter()
+            .flat_map(|registered| registered.nodes.iter())
+            .filter_map(|id| self.nodes.get(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    fn interface(major: i32, minor: i32, endpoints: &[&str]) -> (Interface, InterfaceDefinition) {
+        let mappings = endpoints
+            .iter()
+            .map(|endpoint| format!(r#"{{ "endpoint": "{}", "type": "integer" }}"#, endpoint))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let json = format!(
+            r#"{{
+                "interface_name": "com.test.Datastream",
+                "version_major": {},
+                "version_minor": {},
+                "type": "datastream",
+                "ownership": "device",
+                "mappings": [{}]
+            }}"#,
+            major, minor, mappings
+        );
+
+        (
+            Interface::from_str(&json).unwrap(),
+            InterfaceDefinition::from_json(json.as_bytes()).unwrap(),
+        )
+    }
+
+    fn subscribe(
+        subscribers: &mut Subscribers,
+        id: Uuid,
+        introspection: &[(Interface, InterfaceDefinition)],
+    ) -> Result<usize, AstarteMessageHubError> {
+        let to_add = subscribers.plan(&id, introspection)?;
+        let (tx, _) = channel(1);
+        subscribers.insert(id, introspection.to_vec(), tx, Vec::new());
+
+        Ok(to_add.len())
+    }
+
+    #[tokio::test]
+    async fn reference_counted_interfaces() {
+        let mut subscribers = Subscribers::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let introspection = [interface(1, 0, &["/a"])];
+
+        assert_eq!(
+            subscribe(&mut subscribers, first, &introspection).unwrap(),
+            1
+        );
+        assert_eq!(
+            subscribe(&mut subscribers, second, &introspection).unwrap(),
+            0
+        );
+        assert_eq!(subscribers.subscribed_to("com.test.Datastream").count(), 2);
+
+        assert!(subscribers.exclusive_interfaces(&first).is_empty());
+        subscribers.remove(&first);
+        assert_eq!(
+            subscribers.exclusive_interfaces(&second),
+            vec!["com.test.Datastream".to_string()]
+        );
+        subscribers.remove(&second);
+        assert!(subscribers.interface("com.test.Datastream").is_none());
+    }
+
+    #[tokio::test]
+    async fn conflicting_interfaces_are_rejected() {
+        let mut subscribers = Subscribers::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        subscribe(&mut subscribers, first, &[interface(1, 1, &["/a", "/b"])]).unwrap();
+
+        for conflicting in [
+            interface(2, 1, &["/a", "/b"]),
+            interface(1, 1, &["/a"]),
+            interface(1, 2, &["/a", "/c"]),
+            interface(1, 0, &["/c"]),
+        ] {
+            assert!(matches!(
+                subscribers.plan(&second, &[conflicting]),
+                Err(AstarteMessageHubError::InterfaceConflict { .. })
+            ));
+        }
+
+        // The same node can replace its own interfaces
+        assert_eq!(
+            subscribe(&mut subscribers, first, &[interface(2, 0, &["/c"])]).unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reattach_keeps_unchanged_interfaces() {
+        let mut subscribers = Subscribers::default();
+        let id = Uuid::new_v4();
+        let introspection = [interface(1, 0, &["/a"])];
+
+        assert_eq!(subscribe(&mut subscribers, id, &introspection).unwrap(), 1);
+        assert_eq!(subscribe(&mut subscribers, id, &introspection).unwrap(), 0);
+        assert!(subscribers.stale_interfaces(&id, &introspection).is_empty());
+        assert_eq!(
+            subscribers.stale_interfaces(&id, &[]),
+            vec!["com.test.Datastream".to_string()]
+        );
+    }
+
+    fn message(value: i32) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: "com.test.Datastream".to_string(),
+            path: format!("/{}", value),
+            timestamp: None,
+            payload: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let cases = [
+            (OverflowPolicy::DropOldest, vec!["/3", "/4"], 3),
+            (OverflowPolicy::DropNewest, vec!["/0", "/1"], 3),
+            (OverflowPolicy::Disconnect, vec![], 2),
+        ];
+
+        for (overflow_policy, expected, dropped) in cases {
+            let mut subscribers = Subscribers::new(SessionOptions {
+                buffer_size: 2,
+                overflow_policy,
+                ..Default::default()
+            });
+            let id = Uuid::new_v4();
+            let introspection = [interface(1, 0, &["/a"])];
+
+            let (tx, mut rx) = channel(1);
+            subscribers.insert(id, introspection.to_vec(), tx, Vec::new());
+
+            // Let the delivery task wait for the events
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            for value in 0..5 {
+                for subscriber in subscribers.subscribed_to("com.test.Datastream") {
+                    subscriber.send(message(value)).await;
+                }
+            }
+
+            let mut received = Vec::new();
+            while let Ok(Some(event)) =
+                tokio::time::timeout(Duration::from_millis(50), rx.recv()).await
+            {
+                received.push(event.unwrap().path);
+            }
+
+            assert_eq!(received, expected, "{:?}", overflow_policy);
+            assert_eq!(
+                subscribers.dropped_messages()[&id],
+                dropped,
+                "{:?}",
+                overflow_policy
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_events_of_disconnected_node() {
+        let mut subscribers = Subscribers::new(SessionOptions {
+            replay_buffer_size: 2,
+            ..Default::default()
+        });
+        let id = Uuid::new_v4();
+        let introspection = [interface(1, 0, &["/a"])];
+
+        subscribe(&mut subscribers, id, &introspection).unwrap();
+
+        // Let the delivery task detect the closed stream
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        for value in 0..3 {
+            for subscriber in subscribers.subscribed_to("com.test.Datastream") {
+                subscriber.send(message(value)).await;
+            }
+        }
+
+        let replay = subscribers.take_replay(&id).await;
+        let paths = replay.iter().map(|m| m.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/1", "/2"]);
+        assert!(subscribers.take_replay(&id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn minor_version_upgrade() {
+        let mut subscribers = Subscribers::default();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        subscribe(&mut subscribers, first, &[interface(1, 1, &["/a"])]).unwrap();
+
+        // A newer minor version upgrades the registered interface
+        assert_eq!(
+            subscribe(&mut subscribers, second, &[interface(1, 2, &["/a", "/b"])]).unwrap(),
+            1
+        );
+        assert_eq!(
+            subscribers
+                .definition("com.test.Datastream")
+                .unwrap()
+                .version_minor,
+            2
+        );
+
+        // An older minor version uses the registered interface
+        assert_eq!(
+            subscribe(&mut subscribers, third, &[interface(1, 0, &["/a"])]).unwrap(),
+            0
+        );
+        assert_eq!(
+            subscribers
+                .definition("com.test.Datastream")
+                .unwrap()
+                .version_minor,
+            2
+        );
+    }
+}
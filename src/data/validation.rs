@@ -0,0 +1,475 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Validation of the messages sent by the nodes against the mappings of their interface.
+//!
+//! The checks are done before publishing the message, so that an invalid message is rejected with
+//! the offending field instead of failing in the Astarte Device SDK.
+
+use crate::error::PayloadValidationError;
+use crate::interface::{Aggregation, InterfaceDefinition, InterfaceType, MappingType};
+use crate::proto_message_hub::astarte_data_type::Data;
+use crate::proto_message_hub::astarte_data_type_individual::IndividualData;
+use crate::proto_message_hub::astarte_message::Payload;
+use crate::proto_message_hub::{AstarteDataTypeObject, AstarteMessage};
+
+/// Check that a message matches the mappings of its interface.
+pub(crate) fn validate_message(
+    interface: &InterfaceDefinition,
+    message: &AstarteMessage,
+) -> Result<(), PayloadValidationError> {
+    let payload = message
+        .payload
+        .as_ref()
+        .ok_or(PayloadValidationError::MissingPayload)?;
+
+    match payload {
+        Payload::AstarteUnset(_) => validate_unset(interface, &message.path),
+        Payload::AstarteData(data) => match data
+            .data
+            .as_ref()
+            .ok_or(PayloadValidationError::MissingPayload)?
+        {
+            Data::AstarteIndividual(individual) => validate_individual(
+                interface,
+                &message.path,
+                individual
+                    .individual_data
+                    .as_ref()
+                    .ok_or(PayloadValidationError::MissingPayload)?,
+                message.timestamp.is_some(),
+            ),
+            Data::AstarteObject(object) => validate_object(
+                interface,
+                &message.path,
+                object,
+                message.timestamp.is_some(),
+            ),
+        },
+    }
+}
+
+fn validate_unset(
+    interface: &InterfaceDefinition,
+    path: &str,
+) -> Result<(), PayloadValidationError> {
+    let mapping = interface
+        .mapping(path)
+        .ok_or_else(|| unknown_path(interface, path))?;
+
+    if interface.interface_type == InterfaceType::Properties && mapping.allow_unset {
+        Ok(())
+    } else {
+        Err(PayloadValidationError::UnsetNotAllowed {
+            field: path.to_string(),
+        })
+    }
+}
+
+fn validate_individual(
+    interface: &InterfaceDefinition,
+    path: &str,
+    data: &IndividualData,
+    has_timestamp: bool,
+) -> Result<(), PayloadValidationError> {
+    if interface.aggregation != Aggregation::Individual {
+        return Err(PayloadValidationError::AggregationMismatch {
+            interface: interface.interface_name.clone(),
+            expected: "object",
+        });
+    }
+
+    let mapping = interface
+        .mapping(path)
+        .ok_or_else(|| unknown_path(interface, path))?;
+
+    validate_type(path, mapping.mapping_type, data)?;
+
+    if mapping.explicit_timestamp && !has_timestamp {
+        return Err(PayloadValidationError::MissingTimestamp {
+            field: path.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_object(
+    interface: &InterfaceDefinition,
+    path: &str,
+    object: &AstarteDataTypeObject,
+    has_timestamp: bool,
+) -> Result<(), PayloadValidationError> {
+    if interface.aggregation != Aggregation::Object {
+        return Err(PayloadValidationError::AggregationMismatch {
+            interface: interface.interface_name.clone(),
+            expected: "individual",
+        });
+    }
+
+    let mappings = interface.object_mappings(path).collect::<Vec<_>>();
+
+    if mappings.is_empty() {
+        return Err(unknown_path(interface, path));
+    }
+
+    let field_path = |field: &str| format!("{}/{}", path.trim_end_matches('/'), field);
+
+    // Report the fields in a stable order
+    let mut fields = object.object_data.keys().collect::<Vec<_>>();
+    fields.sort();
+
+    if let Some(field) = fields
+        .into_iter()
+        .find(|field| !mappings.iter().any(|m| m.object_field() == field.as_str()))
+    {
+        return Err(PayloadValidationError::UnknownField {
+            field: field_path(field),
+        });
+    }
+
+    for mapping in mappings.iter() {
+        let field = mapping.object_field();
+
+        let data = object
+            .object_data
+            .get(field)
+            .and_then(|value| value.individual_data.as_ref())
+            .ok_or_else(|| PayloadValidationError::MissingField {
+                field: field_path(field),
+            })?;
+
+        validate_type(&field_path(field), mapping.mapping_type, data)?;
+    }
+
+    if mappings.iter().any(|mapping| mapping.explicit_timestamp) && !has_timestamp {
+        return Err(PayloadValidationError::MissingTimestamp {
+            field: path.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_type(
+    field: &str,
+    mapping_type: MappingType,
+    data: &IndividualData,
+) -> Result<(), PayloadValidationError> {
+    let valid = match (mapping_type, data) {
+        (MappingType::Double, IndividualData::AstarteDouble(value)) => value.is_finite(),
+        (MappingType::Integer, IndividualData::AstarteInteger(_))
+        | (MappingType::Boolean, IndividualData::AstarteBoolean(_))
+        | (MappingType::LongInteger, IndividualData::AstarteLongInteger(_))
+        | (MappingType::LongInteger, IndividualData::AstarteInteger(_))
+        | (MappingType::String, IndividualData::AstarteString(_))
+        | (MappingType::BinaryBlob, IndividualData::AstarteBinaryBlob(_))
+        | (MappingType::DateTime, IndividualData::AstarteDateTime(_))
+        | (MappingType::IntegerArray, IndividualData::AstarteIntegerArray(_))
+        | (MappingType::BooleanArray, IndividualData::AstarteBooleanArray(_))
+        | (MappingType::LongIntegerArray, IndividualData::AstarteLongIntegerArray(_))
+        | (MappingType::LongIntegerArray, IndividualData::AstarteIntegerArray(_))
+        | (MappingType::StringArray, IndividualData::AstarteStringArray(_))
+        | (MappingType::BinaryBlobArray, IndividualData::AstarteBinaryBlobArray(_))
+        | (MappingType::DateTimeArray, IndividualData::AstarteDateTimeArray(_)) => true,
+        (MappingType::DoubleArray, IndividualData::AstarteDoubleArray(array)) => {
+            array.values.iter().all(|value| value.is_finite())
+        }
+        _ => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(PayloadValidationError::TypeMismatch {
+            field: field.to_string(),
+            expected: mapping_type.name(),
+            found: data_type_name(data),
+        })
+    }
+}
+
+/// Name of the Astarte type of a value.
+fn data_type_name(data: &IndividualData) -> &'static str {
+    match data {
+        IndividualData::AstarteDouble(value) if !value.is_finite() => "non finite double",
+        IndividualData::AstarteDouble(_) => "double",
+        IndividualData::AstarteInteger(_) => "integer",
+        IndividualData::AstarteBoolean(_) => "boolean",
+        IndividualData::AstarteLongInteger(_) => "longinteger",
+        IndividualData::AstarteString(_) => "string",
+        IndividualData::AstarteBinaryBlob(_) => "binaryblob",
+        IndividualData::AstarteDateTime(_) => "datetime",
+        IndividualData::AstarteDoubleArray(array)
+            if array.values.iter().any(|value| !value.is_finite()) =>
+        {
+            "doublearray with non finite values"
+        }
+        IndividualData::AstarteDoubleArray(_) => "doublearray",
+        IndividualData::AstarteIntegerArray(_) => "integerarray",
+        IndividualData::AstarteBooleanArray(_) => "booleanarray",
+        IndividualData::AstarteLongIntegerArray(_) => "longintegerarray",
+        IndividualData::AstarteStringArray(_) => "stringarray",
+        IndividualData::AstarteBinaryBlobArray(_) => "binaryblobarray",
+        IndividualData::AstarteDateTimeArray(_) => "datetimearray",
+    }
+}
+
+fn unknown_path(interface: &InterfaceDefinition, path: &str) -> PayloadValidationError {
+    PayloadValidationError::UnknownPath {
+        interface: interface.interface_name.clone(),
+        path: path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::proto_message_hub::{AstarteDataType, AstarteDataTypeIndividual, AstarteUnset};
+
+    const DEVICE_DATASTREAM: &str = r#"
+        {
+            "interface_name": "com.test.Datastream",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "datastream",
+            "ownership": "device",
+            "mappings": [
+                { "endpoint": "/%{sensor_id}/value", "type": "double", "explicit_timestamp": true },
+                { "endpoint": "/counter", "type": "longinteger" }
+            ]
+        }
+        "#;
+
+    const DEVICE_OBJECT: &str = r#"
+        {
+            "interface_name": "com.test.Object",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "datastream",
+            "ownership": "device",
+            "aggregation": "object",
+            "mappings": [
+                { "endpoint": "/%{sensor_id}/value", "type": "double" },
+                { "endpoint": "/%{sensor_id}/name", "type": "string" }
+            ]
+        }
+        "#;
+
+    const DEVICE_PROPERTIES: &str = r#"
+        {
+            "interface_name": "com.test.Properties",
+            "version_major": 0,
+            "version_minor": 1,
+            "type": "properties",
+            "ownership": "device",
+            "mappings": [
+                { "endpoint": "/unsettable", "type": "string", "allow_unset": true },
+                { "endpoint": "/fixed", "type": "string" }
+            ]
+        }
+        "#;
+
+    fn interface(json: &str) -> InterfaceDefinition {
+        InterfaceDefinition::from_json(json.as_bytes()).unwrap()
+    }
+
+    fn individual(path: &str, data: IndividualData, timestamp: bool) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: String::new(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteData(AstarteDataType {
+                data: Some(Data::AstarteIndividual(AstarteDataTypeIndividual {
+                    individual_data: Some(data),
+                })),
+            })),
+            timestamp: timestamp.then(|| Utc::now().into()),
+        }
+    }
+
+    fn object(path: &str, fields: Vec<(&str, IndividualData)>) -> AstarteMessage {
+        let object_data = fields
+            .into_iter()
+            .map(|(name, data)| {
+                (
+                    name.to_string(),
+                    AstarteDataTypeIndividual {
+                        individual_data: Some(data),
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        AstarteMessage {
+            interface_name: String::new(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteData(AstarteDataType {
+                data: Some(Data::AstarteObject(AstarteDataTypeObject { object_data })),
+            })),
+            timestamp: None,
+        }
+    }
+
+    fn unset(path: &str) -> AstarteMessage {
+        AstarteMessage {
+            interface_name: String::new(),
+            path: path.to_string(),
+            payload: Some(Payload::AstarteUnset(AstarteUnset {})),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn validate_individual_messages() {
+        let interface = interface(DEVICE_DATASTREAM);
+
+        let valid = individual("/1/value", IndividualData::AstarteDouble(4.2), true);
+        assert_eq!(validate_message(&interface, &valid), Ok(()));
+
+        let widened = individual("/counter", IndividualData::AstarteInteger(4), false);
+        assert_eq!(validate_message(&interface, &widened), Ok(()));
+
+        let wrong_type = individual(
+            "/1/value",
+            IndividualData::AstarteString("4.2".to_string()),
+            true,
+        );
+        assert_eq!(
+            validate_message(&interface, &wrong_type),
+            Err(PayloadValidationError::TypeMismatch {
+                field: "/1/value".to_string(),
+                expected: "double",
+                found: "string",
+            })
+        );
+
+        let not_finite = individual("/1/value", IndividualData::AstarteDouble(f64::NAN), true);
+        assert!(matches!(
+            validate_message(&interface, &not_finite),
+            Err(PayloadValidationError::TypeMismatch { .. })
+        ));
+
+        let no_timestamp = individual("/1/value", IndividualData::AstarteDouble(4.2), false);
+        assert_eq!(
+            validate_message(&interface, &no_timestamp),
+            Err(PayloadValidationError::MissingTimestamp {
+                field: "/1/value".to_string()
+            })
+        );
+
+        let unknown = individual("/1/other", IndividualData::AstarteDouble(4.2), true);
+        assert_eq!(
+            validate_message(&interface, &unknown),
+            Err(PayloadValidationError::UnknownPath {
+                interface: "com.test.Datastream".to_string(),
+                path: "/1/other".to_string()
+            })
+        );
+
+        assert_eq!(
+            validate_message(&interface, &unset("/counter")),
+            Err(PayloadValidationError::UnsetNotAllowed {
+                field: "/counter".to_string()
+            })
+        );
+
+        let aggregate = object("/1", vec![("value", IndividualData::AstarteDouble(4.2))]);
+        assert!(matches!(
+            validate_message(&interface, &aggregate),
+            Err(PayloadValidationError::AggregationMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn validate_object_messages() {
+        let interface = interface(DEVICE_OBJECT);
+
+        let valid = object(
+            "/1",
+            vec![
+                ("value", IndividualData::AstarteDouble(4.2)),
+                ("name", IndividualData::AstarteString("a".to_string())),
+            ],
+        );
+        assert_eq!(validate_message(&interface, &valid), Ok(()));
+
+        let missing = object("/1", vec![("value", IndividualData::AstarteDouble(4.2))]);
+        assert_eq!(
+            validate_message(&interface, &missing),
+            Err(PayloadValidationError::MissingField {
+                field: "/1/name".to_string()
+            })
+        );
+
+        let unknown = object(
+            "/1",
+            vec![
+                ("value", IndividualData::AstarteDouble(4.2)),
+                ("name", IndividualData::AstarteString("a".to_string())),
+                ("other", IndividualData::AstarteInteger(1)),
+            ],
+        );
+        assert_eq!(
+            validate_message(&interface, &unknown),
+            Err(PayloadValidationError::UnknownField {
+                field: "/1/other".to_string()
+            })
+        );
+
+        let wrong_type = object(
+            "/1",
+            vec![
+                ("value", IndividualData::AstarteBoolean(true)),
+                ("name", IndividualData::AstarteString("a".to_string())),
+            ],
+        );
+        assert_eq!(
+            validate_message(&interface, &wrong_type),
+            Err(PayloadValidationError::TypeMismatch {
+                field: "/1/value".to_string(),
+                expected: "double",
+                found: "boolean",
+            })
+        );
+
+        let single = individual("/1/value", IndividualData::AstarteDouble(4.2), false);
+        assert!(matches!(
+            validate_message(&interface, &single),
+            Err(PayloadValidationError::AggregationMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn validate_property_unset() {
+        let interface = interface(DEVICE_PROPERTIES);
+
+        assert_eq!(validate_message(&interface, &unset("/unsettable")), Ok(()));
+        assert_eq!(
+            validate_message(&interface, &unset("/fixed")),
+            Err(PayloadValidationError::UnsetNotAllowed {
+                field: "/fixed".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,495 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Obtain the device id when it is not configured.
+//!
+//! The providers configured in `device_id_providers` are tried in order, the first one returning a
+//! device id wins. The ids read from the system are converted to the Astarte format, a 128-bit
+//! value encoded in base64url without padding.
+//!
+//! With a namespace, the value read from the system by the providers is a hardware id instead, and
+//! the device id is the UUIDv5 generated from the namespace and the hardware id. The static device
+//! ids are used as they are.
+
+use std::fs;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, warn};
+use ring::hmac;
+use uuid::Uuid;
+
+use crate::config::DeviceIdSource;
+use crate::error::AstarteMessageHubError;
+
+/// Path of the machine id of the system.
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+/// Path of the product UUID of the DMI table.
+const DMI_PRODUCT_UUID_PATH: &str = "/sys/class/dmi/id/product_uuid";
+/// Application id the machine id is hashed with, so that the machine id itself is not exposed.
+const MACHINE_ID_APPLICATION: &[u8] = b"astarte-message-hub";
+/// Length of a device id, 128 bits encoded in base64url without padding.
+const DEVICE_ID_LEN: usize = 22;
+
+/// Check whether the device id is a 128-bit value encoded in base64url without padding.
+pub fn is_valid_device_id(device_id: &str) -> bool {
+    device_id.len() == DEVICE_ID_LEN
+        && URL_SAFE_NO_PAD
+            .decode(device_id)
+            .map(|bytes| bytes.len() == 16)
+            .unwrap_or(false)
+}
+
+/// Generate the device id of a hardware id, as the UUIDv5 of the hardware id in the namespace.
+///
+/// The same namespace and hardware id always generate the same device id.
+pub fn generate_device_id(namespace: &Uuid, hardware_id: &str) -> String {
+    let uuid = Uuid::new_v5(namespace, hardware_id.as_bytes());
+
+    URL_SAFE_NO_PAD.encode(uuid.as_bytes())
+}
+
+/// Source of the id of the device.
+#[async_trait]
+pub trait DeviceIdProvider: Send + Sync {
+    /// Name of the provider, used in the logs.
+    fn name(&self) -> &str;
+
+    /// Whether the provider returns a hardware id, from which the device id is generated when a
+    /// namespace is configured.
+    fn is_hardware_id(&self) -> bool {
+        true
+    }
+
+    /// Obtain the device id.
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError>;
+}
+
+/// Providers tried in order, returning the first valid device id obtained.
+pub struct DeviceIdChain {
+    providers: Vec<Box<dyn DeviceIdProvider>>,
+    namespace: Option<Uuid>,
+}
+
+impl DeviceIdChain {
+    /// Create a chain of providers.
+    pub fn new(providers: Vec<Box<dyn DeviceIdProvider>>) -> Self {
+        Self {
+            providers,
+            namespace: None,
+        }
+    }
+
+    /// Generate the device id in the namespace from the hardware id returned by the providers.
+    pub fn with_namespace(mut self, namespace: Uuid) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+
+    /// Create the chain of the configured sources, the Edgehog device service if there are none.
+    pub fn from_sources(sources: &[DeviceIdSource]) -> Self {
+        if sources.is_empty() {
+            return Self::new(vec![Box::new(EdgehogProvider::new(""))]);
+        }
+
+        let providers = sources
+            .iter()
+            .map(|source| -> Box<dyn DeviceIdProvider> {
+                match source {
+                    DeviceIdSource::Edgehog { namespace } => {
+                        Box::new(EdgehogProvider::new(namespace))
+                    }
+                    DeviceIdSource::MachineId => Box::new(MachineIdProvider::new()),
+                    DeviceIdSource::Dmi => Box::new(DmiProvider::new()),
+                    DeviceIdSource::Static { device_id } => {
+                        Box::new(StaticProvider::new(device_id))
+                    }
+                }
+            })
+            .collect();
+
+        Self::new(providers)
+    }
+}
+
+#[async_trait]
+impl DeviceIdProvider for DeviceIdChain {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError> {
+        for provider in &self.providers {
+            let namespace = self
+                .namespace
+                .as_ref()
+                .filter(|_| provider.is_hardware_id());
+
+            match (provider.device_id().await, namespace) {
+                (Ok(hardware_id), Some(namespace)) if !hardware_id.is_empty() => {
+                    debug!("hardware id obtained from the {} provider", provider.name());
+
+                    return Ok(generate_device_id(namespace, &hardware_id));
+                }
+                (Ok(device_id), _) if is_valid_device_id(&device_id) => {
+                    debug!("device id obtained from the {} provider", provider.name());
+
+                    return Ok(device_id);
+                }
+                (Ok(device_id), _) if !device_id.is_empty() => warn!(
+                    "the {} provider returned the invalid device id {}",
+                    provider.name(),
+                    device_id
+                ),
+                (Ok(_), _) => warn!(
+                    "the {} provider returned an empty device id",
+                    provider.name()
+                ),
+                (Err(err), _) => warn!(
+                    "unable to obtain the device id from the {} provider: {}",
+                    provider.name(),
+                    err
+                ),
+            }
+        }
+
+        Err(AstarteMessageHubError::DeviceIdUnavailable)
+    }
+}
+
+/// Hardware id returned by the `io.edgehog.Device` D-Bus service.
+pub struct EdgehogProvider {
+    namespace: String,
+}
+
+/// Hardware id returned by the mocked Edgehog device service.
+#[cfg(test)]
+pub(crate) const MOCK_HARDWARE_ID: &str = "jywsP7SkSl6aHD07LB4PAA";
+
+impl EdgehogProvider {
+    /// Create a provider requesting the hardware id in the namespace.
+    pub fn new(namespace: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+        }
+    }
+
+    #[cfg(not(test))]
+    async fn get_hardware_id_from_dbus(&self) -> Result<String, AstarteMessageHubError> {
+        use crate::device::DeviceProxy;
+
+        let connection = zbus::Connection::system().await?;
+        let proxy = DeviceProxy::new(&connection).await?;
+        let device_id: String = proxy.get_hardware_id(&self.namespace).await?;
+        if device_id.is_empty() {
+            return Err(AstarteMessageHubError::FatalError(
+                "No hardware id provided".to_string(),
+            ));
+        }
+        Ok(device_id)
+    }
+
+    #[cfg(test)]
+    async fn get_hardware_id_from_dbus(&self) -> Result<String, AstarteMessageHubError> {
+        use log::info;
+
+        info!("retrieve mock-id in namespace {:?}", self.namespace);
+        Ok(MOCK_HARDWARE_ID.to_string())
+    }
+}
+
+#[async_trait]
+impl DeviceIdProvider for EdgehogProvider {
+    fn name(&self) -> &str {
+        "edgehog"
+    }
+
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError> {
+        self.get_hardware_id_from_dbus().await
+    }
+}
+
+/// Id derived from the machine id of the system.
+///
+/// The machine id is confidential, so it is hashed with an application specific key, as
+/// recommended by `machine-id(5)`.
+pub struct MachineIdProvider {
+    path: PathBuf,
+}
+
+impl MachineIdProvider {
+    /// Create a provider reading `/etc/machine-id`.
+    pub fn new() -> Self {
+        Self::with_path(MACHINE_ID_PATH)
+    }
+
+    /// Create a provider reading the machine id from a file.
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Default for MachineIdProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl DeviceIdProvider for MachineIdProvider {
+    fn name(&self) -> &str {
+        "machine_id"
+    }
+
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError> {
+        let machine_id = fs::read_to_string(&self.path)?;
+        let machine_id = machine_id.trim();
+
+        let valid = machine_id.len() == 32
+            && machine_id.chars().all(|c| c.is_ascii_hexdigit())
+            && machine_id.chars().any(|c| c != '0');
+        if !valid {
+            return Err(AstarteMessageHubError::FatalError(format!(
+                "invalid machine id in {}",
+                self.path.display()
+            )));
+        }
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, machine_id.as_bytes());
+        let tag = hmac::sign(&key, MACHINE_ID_APPLICATION);
+
+        Ok(URL_SAFE_NO_PAD.encode(&tag.as_ref()[..16]))
+    }
+}
+
+/// Id derived from the product UUID of the DMI table, readable only by root.
+pub struct DmiProvider {
+    path: PathBuf,
+}
+
+impl DmiProvider {
+    /// Create a provider reading `/sys/class/dmi/id/product_uuid`.
+    pub fn new() -> Self {
+        Self::with_path(DMI_PRODUCT_UUID_PATH)
+    }
+
+    /// Create a provider reading the product UUID from a file.
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Default for DmiProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl DeviceIdProvider for DmiProvider {
+    fn name(&self) -> &str {
+        "dmi"
+    }
+
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError> {
+        let product_uuid = fs::read_to_string(&self.path)?;
+
+        // Some firmwares leave the UUID unset, as all zeros or all ones
+        let uuid = Uuid::parse_str(product_uuid.trim())
+            .ok()
+            .filter(|uuid| !uuid.is_nil() && uuid.as_bytes().iter().any(|b| *b != 0xff))
+            .ok_or_else(|| {
+                AstarteMessageHubError::FatalError(format!(
+                    "invalid product UUID in {}",
+                    self.path.display()
+                ))
+            })?;
+
+        Ok(URL_SAFE_NO_PAD.encode(uuid.as_bytes()))
+    }
+}
+
+/// Fixed device id.
+pub struct StaticProvider {
+    device_id: String,
+}
+
+impl StaticProvider {
+    /// Create a provider returning the device id.
+    pub fn new(device_id: impl Into<String>) -> Self {
+        Self {
+            device_id: device_id.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceIdProvider for StaticProvider {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn is_hardware_id(&self) -> bool {
+        false
+    }
+
+    async fn device_id(&self) -> Result<String, AstarteMessageHubError> {
+        Ok(self.device_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn machine_id_provider() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("machine-id");
+
+        let provider = MachineIdProvider::with_path(&path);
+        assert!(provider.device_id().await.is_err());
+
+        fs::write(&path, "0123456789abcdef0123456789abcdef\n").unwrap();
+        let device_id = provider.device_id().await.unwrap();
+
+        assert_eq!(device_id.len(), 22);
+        assert!(!device_id.contains("0123456789abcdef"));
+        assert_eq!(provider.device_id().await.unwrap(), device_id);
+
+        fs::write(&path, "00000000000000000000000000000000").unwrap();
+        assert!(provider.device_id().await.is_err());
+
+        fs::write(&path, "not-a-machine-id").unwrap();
+        assert!(provider.device_id().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dmi_provider() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("product_uuid");
+
+        fs::write(&path, "4c4c4544-0042-3510-8057-b5c04f4e3132\n").unwrap();
+        let provider = DmiProvider::with_path(&path);
+
+        let expected = URL_SAFE_NO_PAD.encode(
+            Uuid::parse_str("4c4c4544-0042-3510-8057-b5c04f4e3132")
+                .unwrap()
+                .as_bytes(),
+        );
+        assert_eq!(provider.device_id().await.unwrap(), expected);
+
+        fs::write(&path, "ffffffff-ffff-ffff-ffff-ffffffffffff").unwrap();
+        assert!(provider.device_id().await.is_err());
+
+        fs::write(&path, "00000000-0000-0000-0000-000000000000").unwrap();
+        assert!(provider.device_id().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chain_returns_first_device_id() {
+        let dir = TempDir::new().unwrap();
+
+        let chain = DeviceIdChain::new(vec![
+            Box::new(DmiProvider::with_path(dir.path().join("missing"))),
+            Box::new(StaticProvider::new("")),
+            Box::new(StaticProvider::new("not-a-device-id")),
+            Box::new(StaticProvider::new("iGMT4TuKU3KbkAya7hmeXQ")),
+            Box::new(EdgehogProvider::new("namespace")),
+        ]);
+        assert_eq!(chain.device_id().await.unwrap(), "iGMT4TuKU3KbkAya7hmeXQ");
+
+        let chain = DeviceIdChain::from_sources(&[]);
+        assert_eq!(chain.device_id().await.unwrap(), MOCK_HARDWARE_ID);
+
+        let chain = DeviceIdChain::new(vec![Box::new(MachineIdProvider::with_path(
+            dir.path().join("missing"),
+        ))]);
+        assert!(matches!(
+            chain.device_id().await,
+            Err(AstarteMessageHubError::DeviceIdUnavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn chain_generates_device_id_in_namespace() {
+        let namespace = Uuid::parse_str("bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1").unwrap();
+
+        let chain = DeviceIdChain::new(vec![
+            Box::new(StaticProvider::new("")),
+            Box::new(EdgehogProvider::new("namespace")),
+        ])
+        .with_namespace(namespace);
+
+        assert_eq!(
+            chain.device_id().await.unwrap(),
+            generate_device_id(&namespace, MOCK_HARDWARE_ID)
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_keeps_static_device_id_in_namespace() {
+        let namespace = Uuid::parse_str("bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1").unwrap();
+
+        let chain = DeviceIdChain::new(vec![
+            Box::new(StaticProvider::new("hardware-id")),
+            Box::new(StaticProvider::new("iGMT4TuKU3KbkAya7hmeXQ")),
+        ])
+        .with_namespace(namespace);
+
+        assert_eq!(chain.device_id().await.unwrap(), "iGMT4TuKU3KbkAya7hmeXQ");
+    }
+
+    #[test]
+    fn validate_device_id() {
+        assert!(is_valid_device_id("iGMT4TuKU3KbkAya7hmeXQ"));
+        assert!(is_valid_device_id("jywsP7SkSl6aHD07LB4PAA"));
+
+        assert!(!is_valid_device_id(""));
+        assert!(!is_valid_device_id("device_id"));
+        // Padded, standard alphabet and too long ids
+        assert!(!is_valid_device_id("iGMT4TuKU3KbkAya7hmeXQ=="));
+        assert!(!is_valid_device_id("iGMT4TuKU3KbkAya7hme+/"));
+        assert!(!is_valid_device_id("iGMT4TuKU3KbkAya7hmeXQA"));
+        // The last character has bits set past the 128 bits
+        assert!(!is_valid_device_id("iGMT4TuKU3KbkAya7hmeXR"));
+    }
+
+    #[test]
+    fn generate_uuid_v5_device_id() {
+        let device_id = generate_device_id(&Uuid::NAMESPACE_DNS, "python.org");
+        assert_eq!(device_id, "iGMT4TuKU3KbkAya7hmeXQ");
+        assert!(is_valid_device_id(&device_id));
+
+        let namespace = Uuid::parse_str("bd1a7e25-1e64-4b5b-a1f0-8c0e0b19c6c1").unwrap();
+        assert_eq!(
+            generate_device_id(&namespace, "hardware-id"),
+            "dtxicQVFVp-gO2wEwOICKw"
+        );
+        assert_ne!(
+            generate_device_id(&namespace, "hardware-id"),
+            generate_device_id(&Uuid::NAMESPACE_DNS, "hardware-id")
+        );
+    }
+}
@@ -42,12 +42,31 @@ pub enum AstarteMessageHubError {
 
     /// Error returned by the Astarte SDK
     #[error(transparent)]
-    AstarteError(#[from] astarte_device_sdk::AstarteError),
+    AstarteError(Box<astarte_device_sdk::AstarteError>),
 
     /// Error returned by the options
     #[error(transparent)]
     AstarteOptionsError(#[from] astarte_device_sdk::options::AstarteOptionsError),
 
+    /// None of the device id providers returned a device id
+    #[error("no device id provider returned a device id")]
+    DeviceIdUnavailable,
+
+    /// Astarte rejected the credentials secret and no pairing token is available to register the
+    /// device again
+    #[error("Astarte rejected the credentials secret and no pairing token is available")]
+    CredentialsRejected(#[source] Box<astarte_device_sdk::AstarteError>),
+
+    /// Astarte kept rejecting the credentials secret after registering the device again
+    #[error("Astarte rejected the credentials secret after {attempts} registrations")]
+    RegistrationFailed {
+        /// Number of times the device was registered again
+        attempts: u32,
+        /// Last error returned by Astarte
+        #[source]
+        source: Box<astarte_device_sdk::AstarteError>,
+    },
+
     /// Invalid date
     #[error("{0}")]
     AstarteInvalidData(String),
@@ -64,13 +83,122 @@ pub enum AstarteMessageHubError {
     #[error("configuration file error")]
     ConfigFileError(#[from] toml::de::Error),
 
+    /// A file containing the configuration or the secrets is writable by the other users
+    #[error("{0} is writable by other users, refusing to use it")]
+    InsecurePermissions(PathBuf),
+
+    /// Unable to serialize the configuration
+    #[error("configuration serialization error")]
+    ConfigSerializationError(#[from] toml::ser::Error),
+
     /// Fail while sending or receiving data
     #[error(transparent)]
     TransportError(#[from] tonic::transport::Error),
 
+    /// Error returned by a gRPC call
+    #[error(transparent)]
+    RpcError(Box<tonic::Status>),
+
+    /// Error returned by an HTTP server
+    #[error(transparent)]
+    HttpError(#[from] hyper::Error),
+
     /// Error returned by Zbus
     #[error(transparent)]
     ZbusError(#[from] zbus::Error),
+
+    /// Failed to decode a stored protobuf message
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
+
+    /// The queue of the messages to send to Astarte is full
+    #[error("outbound queue is full")]
+    QueueFull,
+
+    /// The interface conflicts with the one declared by another node
+    #[error("conflicting interface {name}: {reason}")]
+    InterfaceConflict {
+        /// Name of the interface
+        name: String,
+        /// Reason of the conflict
+        reason: String,
+    },
+
+    /// The message does not match the interface
+    #[error(transparent)]
+    PayloadValidationError(#[from] PayloadValidationError),
+}
+
+// The large errors are boxed, to keep the results small
+
+impl From<astarte_device_sdk::AstarteError> for AstarteMessageHubError {
+    fn from(err: astarte_device_sdk::AstarteError) -> Self {
+        Self::AstarteError(Box::new(err))
+    }
+}
+
+impl From<tonic::Status> for AstarteMessageHubError {
+    fn from(status: tonic::Status) -> Self {
+        Self::RpcError(Box::new(status))
+    }
+}
+
+/// Reason why a message does not match the mapping of its interface.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PayloadValidationError {
+    /// The message has no payload
+    #[error("missing payload")]
+    MissingPayload,
+    /// The path does not match any mapping of the interface
+    #[error("path {path} does not match any mapping of interface {interface}")]
+    UnknownPath {
+        /// Name of the interface
+        interface: String,
+        /// Path of the message
+        path: String,
+    },
+    /// Individual data sent on an object interface or vice versa
+    #[error("interface {interface} expects {expected} data")]
+    AggregationMismatch {
+        /// Name of the interface
+        interface: String,
+        /// Expected aggregation, either `individual` or `object`
+        expected: &'static str,
+    },
+    /// The type of a value does not match the type of its mapping
+    #[error("field {field} expects type {expected}, found {found}")]
+    TypeMismatch {
+        /// Path of the value
+        field: String,
+        /// Type of the mapping
+        expected: &'static str,
+        /// Type of the value
+        found: &'static str,
+    },
+    /// A field of an object is missing
+    #[error("missing object field {field}")]
+    MissingField {
+        /// Path of the missing field
+        field: String,
+    },
+    /// A field of an object does not match any mapping
+    #[error("unknown object field {field}")]
+    UnknownField {
+        /// Path of the unknown field
+        field: String,
+    },
+    /// The mapping requires an explicit timestamp
+    #[error("field {field} requires an explicit timestamp")]
+    MissingTimestamp {
+        /// Path of the value
+        field: String,
+    },
+    /// The mapping cannot be unset
+    #[error("field {field} cannot be unset")]
+    UnsetNotAllowed {
+        /// Path of the value
+        field: String,
+    },
 }
 
 /// Reason why a configuration is invalid.
@@ -79,10 +207,28 @@ pub enum ConfigValidationError {
     /// Missing required field in the configuration file
     #[error("{0} field is missing")]
     MissingField(&'static str),
+    /// The device id is not a 128-bit value encoded in base64url without padding
+    #[error("invalid device id {0}, expected 128 bits encoded in base64url without padding")]
+    InvalidDeviceId(String),
     /// Missing both the pairing token and the credentials secret
     #[error("either the pairing token or credential secret must be provided")]
     MissingPairingAndCredentials,
+    /// Neither the gRPC port nor the Unix domain socket path are provided
+    #[error("either the grpc socket port or the grpc unix socket path must be provided")]
+    MissingGrpcListener,
     /// The provided interface path is not a directory
     #[error("interface path {0:?} is not a directory")]
     InvalidInterfaceDirectory(Option<PathBuf>),
+    /// The log level is not one of off, error, warn, info, debug or trace
+    #[error("invalid log level {0}")]
+    InvalidLogLevel(String),
+    /// The administration API is enabled without a gRPC or HTTP address
+    #[error("either the admin grpc address or the admin http address must be provided")]
+    MissingAdminListener,
+    /// The administration API is enabled without tokens, or with an empty one
+    #[error("the admin api requires at least one non-empty token")]
+    MissingAdminTokens,
+    /// The encrypted secret store has neither a key file nor a keyring key
+    #[error("the encrypted secrets backend requires a key file or a keyring key")]
+    MissingSecretKey,
 }
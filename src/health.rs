@@ -0,0 +1,154 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Report the status of the message hub with the standard gRPC health checking protocol
+//! (`grpc.health.v1`), served by [tonic_health].
+//!
+//! The overall status of the server, requested with an empty service name, and the status of the
+//! `MessageHub` service reflect the connection of the device to Astarte. The `MessageHubConfig`
+//! service is reported as serving while the provisioning server is waiting for a configuration.
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tonic_health::ServingStatus;
+
+pub use tonic_health::server::{health_reporter, HealthReporter};
+
+use crate::data::astarte::AstarteRunner;
+use crate::proto_message_hub::message_hub_server::MessageHubServer;
+use crate::proto_message_hub::{ConnectionEvent, ConnectionState};
+use crate::{AstarteHandler, AstarteMessageHub};
+
+/// The message hub service, whose status follows the connection to Astarte.
+pub(crate) type MessageHubService = MessageHubServer<AstarteMessageHub<AstarteHandler>>;
+
+/// Report the connection of the device to Astarte, as observed by the handler, as the status of
+/// the server and of the message hub service.
+pub fn report_connection(reporter: HealthReporter, handler: &AstarteHandler) -> JoinHandle<()> {
+    tokio::spawn(watch_connection(reporter, handler.watch_connection()))
+}
+
+/// Update the statuses on every change of the connection state, until the handler is dropped.
+async fn watch_connection(
+    mut reporter: HealthReporter,
+    mut connection: watch::Receiver<ConnectionEvent>,
+) {
+    let mut reported = None;
+
+    loop {
+        let connected = connection.borrow_and_update().state() == ConnectionState::Connected;
+
+        // Disconnected and reconnecting are both not serving, the watchers see only the changes
+        if reported != Some(connected) {
+            let status = if connected {
+                reporter.set_serving::<MessageHubService>().await;
+                ServingStatus::Serving
+            } else {
+                reporter.set_not_serving::<MessageHubService>().await;
+                ServingStatus::NotServing
+            };
+
+            reporter.set_service_status("", status).await;
+            reported = Some(connected);
+        }
+
+        if connection.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Endpoint, NamedService};
+    use tonic_health::proto::health_check_response::ServingStatus;
+    use tonic_health::proto::health_client::HealthClient;
+    use tonic_health::proto::HealthCheckRequest;
+
+    use super::*;
+
+    fn event(state: ConnectionState) -> ConnectionEvent {
+        ConnectionEvent {
+            state: state.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn report_the_connection_state() {
+        let (reporter, health_service) = health_reporter();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let (tx, rx) = watch::channel(event(ConnectionState::Connected));
+        let task = tokio::spawn(watch_connection(reporter, rx));
+
+        let channel = Endpoint::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        let request = || HealthCheckRequest {
+            service: MessageHubService::NAME.to_string(),
+        };
+
+        // The service is registered by the first report
+        let mut statuses = loop {
+            match client.watch(request()).await {
+                Ok(response) => break response.into_inner(),
+                Err(status) => assert_eq!(status.code(), tonic::Code::NotFound),
+            }
+
+            tokio::task::yield_now().await;
+        };
+
+        let status = statuses.message().await.unwrap().unwrap().status();
+        assert_eq!(status, ServingStatus::Serving);
+
+        tx.send(event(ConnectionState::Disconnected)).unwrap();
+        tx.send(event(ConnectionState::Reconnecting)).unwrap();
+        let status = statuses.message().await.unwrap().unwrap().status();
+        assert_eq!(status, ServingStatus::NotServing);
+
+        let response = client
+            .check(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().status(), ServingStatus::NotServing);
+
+        tx.send(event(ConnectionState::Connected)).unwrap();
+        let status = statuses.message().await.unwrap().unwrap().status();
+        assert_eq!(status, ServingStatus::Serving);
+
+        drop(tx);
+        task.await.unwrap();
+    }
+}
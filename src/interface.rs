@@ -0,0 +1,346 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Definition of an Astarte interface as seen by the message hub.
+//!
+//! The [astarte_device_sdk::Interface] only exposes the name and the version of an interface, this
+//! module provides access to the type, the aggregation and the mappings of the interface.
+
+use serde::Deserialize;
+
+use crate::error::AstarteMessageHubError;
+
+/// Type of an Astarte interface.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InterfaceType {
+    Datastream,
+    Properties,
+}
+
+/// Ownership of an Astarte interface.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Ownership {
+    Device,
+    Server,
+}
+
+/// Aggregation of an Astarte interface.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Aggregation {
+    Individual,
+    Object,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Self::Individual
+    }
+}
+
+/// Reliability of a datastream mapping.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Reliability {
+    Unreliable,
+    Guaranteed,
+    Unique,
+}
+
+impl Default for Reliability {
+    fn default() -> Self {
+        Self::Unreliable
+    }
+}
+
+/// Type of the data of a mapping.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MappingType {
+    Double,
+    Integer,
+    Boolean,
+    LongInteger,
+    String,
+    BinaryBlob,
+    DateTime,
+    DoubleArray,
+    IntegerArray,
+    BooleanArray,
+    LongIntegerArray,
+    StringArray,
+    BinaryBlobArray,
+    DateTimeArray,
+}
+
+impl MappingType {
+    /// Name of the type in the interface definition.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            MappingType::Double => "double",
+            MappingType::Integer => "integer",
+            MappingType::Boolean => "boolean",
+            MappingType::LongInteger => "longinteger",
+            MappingType::String => "string",
+            MappingType::BinaryBlob => "binaryblob",
+            MappingType::DateTime => "datetime",
+            MappingType::DoubleArray => "doublearray",
+            MappingType::IntegerArray => "integerarray",
+            MappingType::BooleanArray => "booleanarray",
+            MappingType::LongIntegerArray => "longintegerarray",
+            MappingType::StringArray => "stringarray",
+            MappingType::BinaryBlobArray => "binaryblobarray",
+            MappingType::DateTimeArray => "datetimearray",
+        }
+    }
+}
+
+/// A mapping of an Astarte interface.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct MappingDefinition {
+    pub(crate) endpoint: String,
+    #[serde(rename = "type")]
+    pub(crate) mapping_type: MappingType,
+    #[serde(default)]
+    pub(crate) reliability: Reliability,
+    #[serde(default)]
+    pub(crate) explicit_timestamp: bool,
+    #[serde(default)]
+    pub(crate) allow_unset: bool,
+}
+
+impl MappingDefinition {
+    /// Check if the path matches the endpoint of the mapping, parameters match any level.
+    pub(crate) fn is_compatible(&self, path: &str) -> bool {
+        endpoint_matches(&self.endpoint, path)
+    }
+
+    /// Check if the path matches the endpoint of the mapping without its last level, as for the
+    /// path of an object.
+    fn is_parent_compatible(&self, path: &str) -> bool {
+        self.endpoint
+            .rsplit_once('/')
+            .map(|(parent, _)| endpoint_matches(parent, path))
+            .unwrap_or(false)
+    }
+
+    /// Last level of the endpoint, naming the field of an object.
+    pub(crate) fn object_field(&self) -> &str {
+        self.endpoint
+            .rsplit_once('/')
+            .map(|(_, field)| field)
+            .unwrap_or(&self.endpoint)
+    }
+}
+
+/// Check if the path matches an endpoint, parameters match any level.
+fn endpoint_matches(endpoint: &str, path: &str) -> bool {
+    if !path.starts_with('/') {
+        return false;
+    }
+
+    let mut endpoint_levels = endpoint.trim_start_matches('/').split('/');
+    let mut path_levels = path.trim_start_matches('/').split('/');
+
+    loop {
+        match (endpoint_levels.next(), path_levels.next()) {
+            (None, None) => return true,
+            (Some(endpoint), Some(path)) => {
+                let is_parameter = endpoint.starts_with("%{") && endpoint.ends_with('}');
+
+                if path.is_empty() || (!is_parameter && endpoint != path) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Definition of an Astarte interface.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct InterfaceDefinition {
+    pub(crate) interface_name: String,
+    pub(crate) version_major: i32,
+    pub(crate) version_minor: i32,
+    pub(crate) ownership: Ownership,
+    #[serde(rename = "type")]
+    pub(crate) interface_type: InterfaceType,
+    #[serde(default)]
+    pub(crate) aggregation: Aggregation,
+    pub(crate) mappings: Vec<MappingDefinition>,
+}
+
+impl InterfaceDefinition {
+    /// Parse the JSON definition of an interface.
+    pub(crate) fn from_json(json: &[u8]) -> Result<Self, AstarteMessageHubError> {
+        serde_json::from_slice(json).map_err(|_| AstarteMessageHubError::ConversionError)
+    }
+
+    /// Check if data can be sent on the path, for an object the path excludes the last level of
+    /// the endpoints.
+    pub(crate) fn has_path(&self, path: &str) -> bool {
+        match self.aggregation {
+            Aggregation::Individual => self.mapping(path).is_some(),
+            Aggregation::Object => self.object_mappings(path).next().is_some(),
+        }
+    }
+
+    /// Iterate over the mappings of an object interface sent on the path.
+    pub(crate) fn object_mappings<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> impl Iterator<Item = &'a MappingDefinition> {
+        self.mappings
+            .iter()
+            .filter(move |mapping| mapping.is_parent_compatible(path))
+    }
+
+    /// Returns the mapping of an individual interface matching the path.
+    pub(crate) fn mapping(&self, path: &str) -> Option<&MappingDefinition> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.is_compatible(path))
+    }
+
+    /// Reliability of the data sent on the path, the mappings of an object share the same one.
+    pub(crate) fn reliability(&self, path: &str) -> Option<Reliability> {
+        match self.aggregation {
+            Aggregation::Individual => self.mapping(path).map(|mapping| mapping.reliability),
+            Aggregation::Object => self.mappings.first().map(|mapping| mapping.reliability),
+        }
+    }
+}
+
+impl TryFrom<&astarte_device_sdk::Interface> for InterfaceDefinition {
+    type Error = AstarteMessageHubError;
+
+    fn try_from(interface: &astarte_device_sdk::Interface) -> Result<Self, Self::Error> {
+        serde_json::to_value(interface)
+            .and_then(serde_json::from_value)
+            .map_err(|_| AstarteMessageHubError::ConversionError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const DEVICE_DATASTREAM: &str = r#"
+        {
+            "interface_name": "com.test.Datastream",
+            "version_major": 1,
+            "version_minor": 2,
+            "type": "datastream",
+            "ownership": "device",
+            "mappings": [
+                {
+                    "endpoint": "/%{sensor_id}/value",
+                    "type": "double",
+                    "reliability": "guaranteed",
+                    "explicit_timestamp": true
+                },
+                {
+                    "endpoint": "/name",
+                    "type": "string"
+                }
+            ]
+        }
+        "#;
+
+    #[test]
+    fn definition_from_sdk_interface() {
+        let interface = astarte_device_sdk::Interface::from_str(DEVICE_DATASTREAM).unwrap();
+
+        let definition = InterfaceDefinition::try_from(&interface).unwrap();
+
+        assert_eq!(definition.interface_name, "com.test.Datastream");
+        assert_eq!(definition.version_major, 1);
+        assert_eq!(definition.version_minor, 2);
+        assert_eq!(definition.ownership, Ownership::Device);
+        assert_eq!(definition.interface_type, InterfaceType::Datastream);
+        assert_eq!(definition.aggregation, Aggregation::Individual);
+        assert_eq!(
+            InterfaceDefinition::from_json(DEVICE_DATASTREAM.as_bytes()).unwrap(),
+            definition
+        );
+
+        let mapping = definition.mapping("/42/value").unwrap();
+        assert_eq!(mapping.endpoint, "/%{sensor_id}/value");
+        assert_eq!(mapping.mapping_type, MappingType::Double);
+        assert!(mapping.explicit_timestamp);
+        assert_eq!(mapping.object_field(), "value");
+
+        assert_eq!(
+            definition.reliability("/42/value"),
+            Some(Reliability::Guaranteed)
+        );
+        assert_eq!(
+            definition.reliability("/name"),
+            Some(Reliability::Unreliable)
+        );
+        assert_eq!(definition.reliability("/42"), None);
+    }
+
+    #[test]
+    fn mapping_compatible_paths() {
+        let mapping = MappingDefinition {
+            endpoint: "/%{sensor_id}/value".to_string(),
+            mapping_type: MappingType::Double,
+            reliability: Reliability::Unreliable,
+            explicit_timestamp: false,
+            allow_unset: false,
+        };
+
+        assert!(mapping.is_compatible("/1/value"));
+        assert!(!mapping.is_compatible("1/value"));
+        assert!(!mapping.is_compatible("//value"));
+        assert!(!mapping.is_compatible("/1/value/other"));
+        assert!(!mapping.is_compatible("/1/other"));
+        assert!(!mapping.is_compatible("/1"));
+    }
+
+    #[test]
+    fn object_paths() {
+        let definition = InterfaceDefinition::from_json(
+            br#"{
+                "interface_name": "com.test.Object",
+                "version_major": 0,
+                "version_minor": 1,
+                "type": "datastream",
+                "ownership": "device",
+                "aggregation": "object",
+                "mappings": [
+                    { "endpoint": "/%{sensor_id}/value", "type": "double" },
+                    { "endpoint": "/%{sensor_id}/name", "type": "string" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(definition.has_path("/1"));
+        assert!(!definition.has_path("/1/value"));
+        assert!(!definition.has_path("/"));
+    }
+}
@@ -22,14 +22,26 @@
 
 pub use crate::astarte_message_hub::AstarteMessageHub;
 pub use crate::data::astarte_handler::AstarteHandler;
+pub use crate::data::properties::ServerProperties;
+pub use crate::proto_message_hub::message_hub_admin_server::MessageHubAdminServer;
 pub use crate::proto_message_hub::message_hub_server::MessageHubServer;
 
+pub mod admin;
 mod astarte_device_sdk_types;
 mod astarte_message_hub;
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
 mod data;
 mod device;
+pub mod device_id;
 pub mod error;
+pub mod health;
+mod interface;
+pub mod metrics;
+mod persist;
 #[allow(missing_docs)]
 pub mod proto_message_hub;
+pub mod secrets;
 mod types;
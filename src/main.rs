@@ -24,87 +24,504 @@
 
 #![warn(missing_docs)]
 
+use std::future::Future;
+use std::io;
 use std::net::Ipv6Addr;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::Parser;
-use log::info;
+use clap::{Parser, Subcommand};
+use env_logger::DEFAULT_FILTER_ENV;
+use log::{error, info, warn, LevelFilter};
+use nix::unistd::{Gid, Group, Uid, User};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::wrappers::UnixListenerStream;
+use toml::Value;
+use uuid::Uuid;
 
-use astarte_device_sdk::options::AstarteOptions;
-use astarte_device_sdk::AstarteDeviceSdk;
-
-use astarte_message_hub::config::MessageHubOptions;
+use astarte_message_hub::auth::{AdminAuthenticator, NodeAuthenticator};
+use astarte_message_hub::config::overrides::ConfigOverrides;
+use astarte_message_hub::config::reload::{self, ConfigChanges};
+use astarte_message_hub::config::{self, bind_unix_socket, MessageHubOptions};
+use astarte_message_hub::device_id;
 use astarte_message_hub::error::AstarteMessageHubError;
+use astarte_message_hub::health;
+use astarte_message_hub::metrics::Metrics;
 use astarte_message_hub::proto_message_hub::message_hub_server::MessageHubServer;
 use astarte_message_hub::AstarteHandler;
 use astarte_message_hub::AstarteMessageHub;
+use astarte_message_hub::MessageHubAdminServer;
+use astarte_message_hub::ServerProperties;
 
 /// A central service that runs on (Linux) devices for collecting and delivering messages from N
 /// apps using 1 MQTT connection to Astarte.
+///
+/// The secrets are not accepted as flags, since the command line is visible to the other users:
+/// they are read from the configuration file, the secret store or the `MSGHUB_CREDENTIALS_SECRET`
+/// and `MSGHUB_PAIRING_TOKEN` environment variables.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     /// Path to a valid .toml file containing the message hub configuration.
-    #[clap(short, long, conflicts_with = "store-directory")]
+    #[clap(short, long)]
     toml: Option<String>,
     /// Directory used by Astarte-Message-Hub to retain configuration and other persistent data.
-    #[clap(short, long, conflicts_with = "toml")]
+    #[clap(short, long)]
     store_directory: Option<PathBuf>,
+    /// The Astarte realm the device belongs to.
+    #[clap(long)]
+    realm: Option<String>,
+    /// A unique ID for the device.
+    #[clap(long)]
+    device_id: Option<String>,
+    /// The URL of the Astarte pairing API.
+    #[clap(long)]
+    pairing_url: Option<String>,
+    /// Directory containing the Astarte interfaces.
+    #[clap(long)]
+    interfaces_directory: Option<PathBuf>,
+    /// Ignore SSL errors when connecting to Astarte, `--astarte-ignore-ssl=false` to overwrite
+    /// the configuration file.
+    #[clap(
+        long,
+        value_name = "BOOL",
+        takes_value = true,
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    astarte_ignore_ssl: Option<bool>,
+    /// The gRPC port to use, listening on the loopback interface.
+    #[clap(long)]
+    grpc_socket_port: Option<u16>,
+    /// Path of the Unix domain socket the gRPC server listens on.
+    #[clap(long)]
+    grpc_unix_socket_path: Option<PathBuf>,
+    /// Maximum level of the logs, ignored when the `RUST_LOG` variable is set.
+    #[clap(long)]
+    log_level: Option<String>,
+    /// Override any option with its dotted path, e.g. `--set queue.max_size=10`.
+    #[clap(long = "set", value_name = "KEY=VALUE", multiple_occurrences = true)]
+    set: Vec<String>,
+    /// Print the effective configuration, with the secrets redacted, and exit.
+    #[clap(long)]
+    print_config: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate the device id of a hardware id in a namespace, as Astarte expects it, and exit.
+    GenerateDeviceId {
+        /// UUID of the namespace the device id is generated in.
+        #[clap(long)]
+        namespace: Uuid,
+        /// Hardware id of the device, e.g. its serial number.
+        hardware_id: String,
+    },
+}
+
+impl Cli {
+    /// Options overridden by the command line flags.
+    fn overrides(&self) -> Result<ConfigOverrides, AstarteMessageHubError> {
+        let mut overrides = ConfigOverrides::new();
+
+        let strings = [
+            ("realm", &self.realm),
+            ("device_id", &self.device_id),
+            ("pairing_url", &self.pairing_url),
+            ("log_level", &self.log_level),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                overrides.set_value(key, Value::String(value.clone()))?;
+            }
+        }
+
+        let paths = [
+            ("store_directory", &self.store_directory),
+            ("interfaces_directory", &self.interfaces_directory),
+            ("grpc_unix_socket_path", &self.grpc_unix_socket_path),
+        ];
+        for (key, value) in paths {
+            if let Some(value) = value {
+                let value = Value::String(value.to_string_lossy().to_string());
+                overrides.set_value(key, value)?;
+            }
+        }
+
+        if let Some(port) = self.grpc_socket_port {
+            overrides.set_value("grpc_socket_port", Value::Integer(port.into()))?;
+        }
+
+        if let Some(ignore_ssl) = self.astarte_ignore_ssl {
+            overrides.set_value("astarte_ignore_ssl", Value::Boolean(ignore_ssl))?;
+        }
+
+        for assignment in &self.set {
+            let key = assignment.split('=').next().unwrap_or_default().trim();
+            if config::is_secret_option(key) {
+                return Err(AstarteMessageHubError::FatalError(format!(
+                    "The {} cannot be set on the command line, use the MSGHUB_{} variable",
+                    key,
+                    key.to_uppercase().replace('.', "__")
+                )));
+            }
+
+            overrides.set_assignment(assignment)?;
+        }
+
+        Ok(overrides)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), AstarteMessageHubError> {
-    env_logger::init();
+    let log_level_configurable = init_logger();
     let args = Cli::parse();
 
-    let store_directory = args.store_directory.as_deref();
+    if let Some(Command::GenerateDeviceId {
+        namespace,
+        hardware_id,
+    }) = &args.command
+    {
+        println!("{}", device_id::generate_device_id(namespace, hardware_id));
+
+        return Ok(());
+    }
+
+    // Defaults < configuration file < environment variables < command line flags
+    let mut overrides = ConfigOverrides::from_env()?;
+    overrides.extend(args.overrides()?);
+
+    let store_directory = overrides.store_directory();
+    let store_directory = store_directory.as_deref();
+    if args.print_config {
+        let options = MessageHubOptions::load(args.toml.as_deref(), store_directory, &overrides)?;
+        print!("{}", toml::to_string(&options.redacted())?);
+
+        return Ok(());
+    }
 
-    let mut options = MessageHubOptions::get(args.toml, store_directory).await?;
+    let mut options =
+        MessageHubOptions::get(args.toml.clone(), store_directory, &overrides).await?;
 
-    // Initialize an Astarte device
-    let device_sdk = initialize_astarte_device_sdk(&mut options).await?;
+    // Without a configuration file the options only come from the overrides
+    let config_file = MessageHubOptions::config_file(args.toml.as_deref(), store_directory)
+        .filter(|file| file.exists());
+    options.check_permissions(config_file.as_deref())?;
+
+    // Keep the secrets in the encrypted store, the operator removes them from the configuration
+    let stored = options.migrate_secrets()?;
+    if let Some(config_file) = config_file.as_deref() {
+        match MessageHubOptions::plaintext_secrets(config_file, &stored) {
+            Ok(plaintext) if !plaintext.is_empty() => warn!(
+                "The {} in {} are ignored, as kept in the encrypted store, remove them from the file",
+                plaintext.join(" and "),
+                config_file.display()
+            ),
+            Ok(_) => {}
+            Err(err) => warn!(
+                "Unable to check the secrets in {}: {}",
+                config_file.display(),
+                err
+            ),
+        }
+    }
+    if log_level_configurable {
+        set_log_level(options.log_level.as_deref());
+    }
+
+    // Options as read from the configuration, compared with the reloaded ones
+    let loaded_options = options.clone();
+
+    // Initialize an Astarte device, caching the server-owned properties in the store directory
+    let properties = ServerProperties::open(&options.store_directory)?;
+    let device_sdk = options.connect_device(&properties).await?;
     info!("Connection to Astarte established.");
 
+    // Collect the metrics of the nodes and of the messages
+    let metrics = Metrics::new();
+
     // Create a new Astarte handler
-    let handler = AstarteHandler::new(device_sdk);
+    let handler = AstarteHandler::with_store_directory(
+        device_sdk,
+        &options.store_directory,
+        options.queue.clone(),
+    )?
+    .with_session_options(options.session.clone())
+    .with_server_properties(properties)
+    .with_metrics(metrics.clone());
+
+    // Send the messages queued while Astarte was unreachable
+    handler.start_queue_replay();
+
+    // Report the connection to Astarte through the gRPC health service
+    let (health_reporter, health_service) = health::health_reporter();
+    health::report_connection(health_reporter, &handler);
+
+    // Apply the changes to the configuration file without restarting
+    if let Some(config_file) = config_file {
+        let config_rx = reload::watch(config_file, overrides, reload::POLL_INTERVAL)?;
+
+        tokio::spawn(reload_config(
+            handler.clone(),
+            loaded_options,
+            log_level_configurable,
+            config_rx,
+        ));
+    }
 
     // Create a new message hub
-    let message_hub = AstarteMessageHub::new(handler.clone());
+    let authenticator = NodeAuthenticator::from_options(&options.auth, &options.store_directory)?;
+    let astarte_message_hub = AstarteMessageHub::new(handler.clone())
+        .with_detach_grace_period(Duration::from_secs(options.session.detach_grace_period))
+        .with_metrics(metrics.clone());
+    let admin = astarte_message_hub.admin();
+    let message_hub = MessageHubServer::with_interceptor(astarte_message_hub, authenticator);
+
+    // Run the protobuf server on the TCP and the Unix domain sockets
+    let tcp_server = options.grpc_socket_port.map(|port| {
+        let addrs = (Ipv6Addr::LOCALHOST, port).into();
+        info!("gRPC server listening on {}", addrs);
+
+        tonic::transport::Server::builder()
+            .add_service(message_hub.clone())
+            .add_service(health_service.clone())
+            .serve(addrs)
+    });
+
+    let unix_server = match &options.grpc_unix_socket_path {
+        Some(path) => {
+            let listener = bind_grpc_unix_socket(&options)?;
+            info!("gRPC server listening on {:?}", path);
+
+            Some(
+                tonic::transport::Server::builder()
+                    .add_service(message_hub.clone())
+                    .add_service(health_service.clone())
+                    .serve_with_incoming(UnixListenerStream::new(listener)),
+            )
+        }
+        None => None,
+    };
+
+    let metrics_server = options
+        .metrics
+        .enabled
+        .then(|| metrics.serve(options.metrics.address));
+
+    // Serve the administration API, protected by the admin tokens
+    let admin_authenticator = options
+        .admin
+        .enabled
+        .then(|| AdminAuthenticator::from_options(&options.admin))
+        .transpose()?;
+
+    let admin_grpc_server = admin_authenticator.as_ref().and_then(|authenticator| {
+        options.admin.grpc_address.map(|addrs| {
+            info!("Admin gRPC server listening on {}", addrs);
 
-    // Run the protobuf server
-    let addrs = (Ipv6Addr::LOCALHOST, options.grpc_socket_port).into();
-    tonic::transport::Server::builder()
-        .add_service(MessageHubServer::new(message_hub))
-        .serve(addrs)
-        .await?;
+            tonic::transport::Server::builder()
+                .add_service(MessageHubAdminServer::with_interceptor(
+                    admin.clone(),
+                    authenticator.clone(),
+                ))
+                .serve(addrs)
+        })
+    });
+
+    let admin_http_server = admin_authenticator.as_ref().and_then(|authenticator| {
+        options
+            .admin
+            .http_address
+            .map(|addrs| admin.clone().serve_http(addrs, authenticator.clone()))
+    });
+
+    tokio::try_join!(
+        serve(tcp_server),
+        serve(unix_server),
+        serve(metrics_server),
+        serve(admin_grpc_server),
+        serve(admin_http_server)
+    )?;
 
     Ok(())
 }
 
-async fn initialize_astarte_device_sdk(
-    msg_hub_opts: &mut MessageHubOptions,
-) -> Result<AstarteDeviceSdk, AstarteMessageHubError> {
-    msg_hub_opts.obtain_device_id().await?;
-    // Obtain the credentials secret, the store defaults to the current directory
-    msg_hub_opts.obtain_credential_secret().await?;
+/// Initialize the logger, returns whether the log level can be set from the configuration.
+///
+/// The `RUST_LOG` variable, when set, takes precedence over the configured log level.
+fn init_logger() -> bool {
+    if std::env::var_os(DEFAULT_FILTER_ENV).is_some() {
+        env_logger::init();
+
+        return false;
+    }
+
+    // Enable all the levels in the logger, the maximum level is then set from the configuration
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    set_log_level(None);
+
+    true
+}
+
+/// Set the maximum log level, defaulting to errors only as without `RUST_LOG`.
+fn set_log_level(log_level: Option<&str>) {
+    // The level is checked when validating the configuration
+    let level = log_level
+        .and_then(|level| LevelFilter::from_str(level).ok())
+        .unwrap_or(LevelFilter::Error);
+
+    log::set_max_level(level);
+}
 
-    // Create the configuration options for the device and then instantiate a new device
-    let mut device_sdk_opts = AstarteOptions::new(
-        &msg_hub_opts.realm,
-        msg_hub_opts.device_id.as_ref().unwrap(),
-        msg_hub_opts.credentials_secret.as_ref().unwrap(),
-        &msg_hub_opts.pairing_url,
-    );
+/// Apply the reloaded configurations, keeping the attached nodes.
+async fn reload_config(
+    handler: AstarteHandler,
+    mut previous: MessageHubOptions,
+    log_level_configurable: bool,
+    mut config_rx: Receiver<MessageHubOptions>,
+) {
+    while let Some(options) = config_rx.recv().await {
+        let changes = ConfigChanges::between(&previous, &options);
+        if changes.is_empty() {
+            info!("Configuration unchanged");
+            continue;
+        }
 
-    if msg_hub_opts.astarte_ignore_ssl {
-        device_sdk_opts = device_sdk_opts.ignore_ssl_errors();
+        match apply_config(
+            &handler,
+            &previous,
+            &options,
+            &changes,
+            log_level_configurable,
+        )
+        .await
+        {
+            Ok(()) => {
+                info!("Configuration reloaded");
+                previous = options;
+            }
+            Err(err) => error!("Unable to apply the reloaded configuration: {}", err),
+        }
+    }
+}
+
+/// Apply the changes that do not require a restart.
+async fn apply_config(
+    handler: &AstarteHandler,
+    previous: &MessageHubOptions,
+    options: &MessageHubOptions,
+    changes: &ConfigChanges,
+    log_level_configurable: bool,
+) -> Result<(), AstarteMessageHubError> {
+    if !changes.restart_required.is_empty() {
+        warn!(
+            "Changes to {} are applied only after a restart",
+            changes.restart_required.join(", ")
+        );
+    }
+
+    if changes.log_level && log_level_configurable {
+        set_log_level(options.log_level.as_deref());
     }
 
-    if let Some(int_dir) = &msg_hub_opts.interfaces_directory {
-        device_sdk_opts = device_sdk_opts.interface_directory(&int_dir.to_string_lossy())?;
+    if changes.queue {
+        handler.set_queue_options(options.queue.clone()).await?;
     }
 
-    let sdk = AstarteDeviceSdk::new(&device_sdk_opts).await?;
+    if changes.session {
+        handler.set_session_options(options.session.clone()).await;
+    }
+
+    if changes.reconnect {
+        info!("Astarte device options changed, reconnecting");
+
+        // The new device loads the interfaces directory
+        let mut options = options.clone();
+        let device_sdk = options.connect_device(&handler.server_properties()).await?;
+
+        handler.reconnect(device_sdk).await?;
+    } else if changes.interfaces_directory {
+        handler
+            .reload_interfaces_directory(
+                previous.interfaces_directory.as_deref(),
+                options.interfaces_directory.as_deref(),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Run a server, if configured.
+async fn serve<F, E>(server: Option<F>) -> Result<(), AstarteMessageHubError>
+where
+    F: Future<Output = Result<(), E>>,
+    E: Into<AstarteMessageHubError>,
+{
+    match server {
+        Some(server) => server.await.map_err(Into::into),
+        None => Ok(()),
+    }
+}
+
+/// Bind the Unix domain socket of the gRPC server, setting its mode and ownership.
+fn bind_grpc_unix_socket(
+    options: &MessageHubOptions,
+) -> Result<UnixListener, AstarteMessageHubError> {
+    let path = options
+        .grpc_unix_socket_path
+        .as_ref()
+        .ok_or_else(|| AstarteMessageHubError::FatalError("missing unix socket path".into()))?;
+
+    let owner = options
+        .grpc_unix_socket_owner
+        .as_deref()
+        .map(resolve_user)
+        .transpose()?;
+    let group = options
+        .grpc_unix_socket_group
+        .as_deref()
+        .map(resolve_group)
+        .transpose()?;
+
+    let listener = bind_unix_socket(path, options.grpc_unix_socket_mode, owner, group)?;
+
+    Ok(listener)
+}
+
+/// Resolve a user name or id.
+fn resolve_user(user: &str) -> Result<Uid, AstarteMessageHubError> {
+    if let Ok(uid) = user.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    User::from_name(user)
+        .map_err(io::Error::from)?
+        .map(|user| user.uid)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown user {}", user)).into()
+        })
+}
+
+/// Resolve a group name or id.
+fn resolve_group(group: &str) -> Result<Gid, AstarteMessageHubError> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
 
-    Ok(sdk)
+    Group::from_name(group)
+        .map_err(io::Error::from)?
+        .map(|group| group.gid)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown group {}", group)).into()
+        })
 }
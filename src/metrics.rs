@@ -0,0 +1,245 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Prometheus metrics of the Astarte message hub.
+//!
+//! The metrics are always collected, and exposed in the Prometheus text format on the `/metrics`
+//! route of an HTTP server only if enabled in the configuration.
+
+use std::net::SocketAddr;
+
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Router, Server};
+use log::info;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::error::AstarteMessageHubError;
+
+/// Metrics of the message hub, cloning it shares the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    attached_nodes: IntGauge,
+    attach_total: IntCounter,
+    detach_total: IntCounter,
+    node_messages_total: IntCounterVec,
+    published_messages_total: IntCounterVec,
+    publish_errors_total: IntCounterVec,
+    queued_messages: IntGauge,
+    received_events_total: IntCounterVec,
+    connected: IntGauge,
+}
+
+impl Metrics {
+    /// Create the metrics, registered in a new registry.
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("msghub".to_string()), None)
+            .expect("the prefix must be valid");
+
+        let metrics = Self {
+            attached_nodes: IntGauge::new("attached_nodes", "Number of attached nodes")
+                .expect("valid gauge"),
+            attach_total: IntCounter::new(
+                "attach_total",
+                "Number of nodes attached since the start",
+            )
+            .expect("valid counter"),
+            detach_total: IntCounter::new(
+                "detach_total",
+                "Number of nodes detached since the start",
+            )
+            .expect("valid counter"),
+            node_messages_total: counter_vec(
+                "node_messages_total",
+                "Number of messages sent by the nodes, per interface",
+            ),
+            published_messages_total: counter_vec(
+                "published_messages_total",
+                "Number of messages published on Astarte, per interface",
+            ),
+            publish_errors_total: counter_vec(
+                "publish_errors_total",
+                "Number of messages that could not be published, per interface",
+            ),
+            queued_messages: IntGauge::new(
+                "queued_messages",
+                "Number of messages waiting in the outbound queue",
+            )
+            .expect("valid gauge"),
+            received_events_total: counter_vec(
+                "received_events_total",
+                "Number of events received from Astarte, per interface",
+            ),
+            connected: IntGauge::new(
+                "connected",
+                "Whether the device is connected to Astarte, 1 if connected",
+            )
+            .expect("valid gauge"),
+            registry,
+        };
+
+        metrics.register_all().expect("metrics registered once");
+        metrics.connected.set(1);
+
+        metrics
+    }
+
+    fn register_all(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.attached_nodes.clone()))?;
+        self.registry
+            .register(Box::new(self.attach_total.clone()))?;
+        self.registry
+            .register(Box::new(self.detach_total.clone()))?;
+        self.registry
+            .register(Box::new(self.node_messages_total.clone()))?;
+        self.registry
+            .register(Box::new(self.published_messages_total.clone()))?;
+        self.registry
+            .register(Box::new(self.publish_errors_total.clone()))?;
+        self.registry
+            .register(Box::new(self.queued_messages.clone()))?;
+        self.registry
+            .register(Box::new(self.received_events_total.clone()))?;
+        self.registry.register(Box::new(self.connected.clone()))?;
+
+        Ok(())
+    }
+
+    /// A node attached, with the number of nodes now attached.
+    pub(crate) fn node_attached(&self, attached_nodes: usize) {
+        self.attach_total.inc();
+        self.attached_nodes.set(attached_nodes as i64);
+    }
+
+    /// A node detached, with the number of nodes still attached.
+    pub(crate) fn node_detached(&self, attached_nodes: usize) {
+        self.detach_total.inc();
+        self.attached_nodes.set(attached_nodes as i64);
+    }
+
+    /// A node sent a message on the interface.
+    pub(crate) fn node_message(&self, interface_name: &str) {
+        self.node_messages_total
+            .with_label_values(&[interface_name])
+            .inc();
+    }
+
+    /// A message on the interface was published, successfully or not.
+    pub(crate) fn published(&self, interface_name: &str, success: bool) {
+        let counter = if success {
+            &self.published_messages_total
+        } else {
+            &self.publish_errors_total
+        };
+
+        counter.with_label_values(&[interface_name]).inc();
+    }
+
+    /// Set the number of messages in the outbound queue.
+    pub(crate) fn set_queued_messages(&self, queued_messages: usize) {
+        self.queued_messages.set(queued_messages as i64);
+    }
+
+    /// An event on the interface was received from Astarte.
+    pub(crate) fn received_event(&self, interface_name: &str) {
+        self.received_events_total
+            .with_label_values(&[interface_name])
+            .inc();
+    }
+
+    /// Set the connection state of the device.
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.set(connected.into());
+    }
+
+    /// Encode the metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+
+    /// Serve the metrics on the `/metrics` route of an HTTP server listening on the address.
+    pub async fn serve(self, address: SocketAddr) -> Result<(), AstarteMessageHubError> {
+        let app = Router::new()
+            .route("/metrics", get(Self::metrics))
+            .layer(Extension(self));
+
+        let server = Server::try_bind(&address)?;
+        info!("Metrics available on http://{}/metrics", address);
+
+        server.serve(app.into_make_service()).await?;
+
+        Ok(())
+    }
+
+    /// HTTP endpoint returning the metrics.
+    async fn metrics(Extension(metrics): Extension<Metrics>) -> impl IntoResponse {
+        match metrics.encode() {
+            Ok(body) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                body,
+            ),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/plain")],
+                err.to_string(),
+            ),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn counter_vec(name: &str, help: &str) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), &["interface"]).expect("valid counter")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_metrics() {
+        let metrics = Metrics::new();
+
+        metrics.node_attached(1);
+        metrics.node_message("com.test.Datastream");
+        metrics.published("com.test.Datastream", true);
+        metrics.published("com.test.Datastream", false);
+        metrics.set_connected(false);
+
+        let text = metrics.encode().unwrap();
+
+        assert!(text.contains("msghub_attached_nodes 1"));
+        assert!(text.contains("msghub_attach_total 1"));
+        assert!(text.contains(r#"msghub_node_messages_total{interface="com.test.Datastream"} 1"#));
+        assert!(
+            text.contains(r#"msghub_published_messages_total{interface="com.test.Datastream"} 1"#)
+        );
+        assert!(text.contains(r#"msghub_publish_errors_total{interface="com.test.Datastream"} 1"#));
+        assert!(text.contains("msghub_connected 0"));
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Persist the files containing the configuration and the secrets.
+//!
+//! The files are written atomically, so a power loss leaves either the previous or the new
+//! content, and are only accessible by the owner.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Mode of the files containing the configuration or the secrets.
+pub(crate) const PRIVATE_MODE: u32 = 0o600;
+
+/// Permissions of a file containing secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exposure {
+    /// Only accessible by the owner and the group.
+    Private,
+    /// Readable by the other users.
+    Readable,
+    /// Writable by the other users.
+    Writable,
+}
+
+/// Replace the content of a file, only accessible by its owner.
+///
+/// The content is written in a temporary file in the same directory, synced to the disk and
+/// renamed over the destination.
+pub(crate) fn write_private(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let tmp_path = temporary_path(path)?;
+
+    let result = write_synced(&tmp_path, content.as_ref()).and_then(|()| {
+        fs::rename(&tmp_path, path)?;
+
+        // Sync the directory to persist the rename
+        File::open(directory)?.sync_all()
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Path of the temporary file used to write the destination.
+fn temporary_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file", path.display()),
+        )
+    })?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+
+    Ok(path.with_file_name(tmp_name))
+}
+
+fn write_synced(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(PRIVATE_MODE)
+        .open(path)?;
+
+    // The mode is only applied when the file is created
+    file.set_permissions(Permissions::from_mode(PRIVATE_MODE))?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Check who can access a file, [None] if the file does not exist.
+pub(crate) fn exposure(path: &Path) -> io::Result<Option<Exposure>> {
+    let mode = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().mode(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let exposure = if mode & 0o002 != 0 {
+        Exposure::Writable
+    } else if mode & 0o004 != 0 {
+        Exposure::Readable
+    } else {
+        Exposure::Private
+    };
+
+    Ok(Some(exposure))
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn write_private_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("credentials_secret");
+
+        fs::write(&path, "previous").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o666)).unwrap();
+        assert_eq!(exposure(&path).unwrap(), Some(Exposure::Writable));
+
+        write_private(&path, "secret").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, PRIVATE_MODE);
+        assert_eq!(exposure(&path).unwrap(), Some(Exposure::Private));
+
+        // Only the written file is left in the directory
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn check_exposure() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+
+        assert_eq!(exposure(&path).unwrap(), None);
+
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(exposure(&path).unwrap(), Some(Exposure::Readable));
+
+        fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(exposure(&path).unwrap(), Some(Exposure::Private));
+
+        assert!(write_private(Path::new("/"), "").is_err());
+    }
+}
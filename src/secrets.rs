@@ -0,0 +1,496 @@
+/*
+ * This file is part of Astarte.
+ *
+ * Copyright 2023 SECO Mind Srl
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Store the secrets of the device, like the credentials secret and the pairing token.
+//!
+//! The [PlaintextSecretStore] keeps each secret in a file of the store directory, the
+//! [EncryptedSecretStore] encrypts the files with AES-256-GCM, using a key read from a file or from
+//! the kernel keyring.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_long};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::{SecretBackend, SecretsOptions};
+use crate::error::AstarteMessageHubError;
+use crate::persist::{self, Exposure};
+
+/// Name of the secret with the credentials secret of the device.
+pub const CREDENTIALS_SECRET: &str = "credentials_secret";
+/// Name of the secret with the pairing token of the device.
+pub const PAIRING_TOKEN: &str = "pairing_token";
+
+/// Name of the digest of the configured credentials secret rejected by Astarte.
+pub const REJECTED_CREDENTIALS_SECRET: &str = "rejected_credentials_secret";
+
+/// Names of the secrets stored by the message hub.
+const SECRETS: [&str; 2] = [CREDENTIALS_SECRET, PAIRING_TOKEN];
+
+/// Extension of the encrypted secret files.
+const ENCRYPTED_EXTENSION: &str = "enc";
+/// Version of the format of the encrypted secret files.
+const ENCRYPTED_VERSION: u8 = 1;
+/// Minimum length of the material the encryption key is derived from.
+const MIN_KEY_LEN: usize = 32;
+
+/// Storage of the secrets of the device.
+pub trait SecretStore: Send + Sync {
+    /// Read a secret, [None] if it is not stored.
+    fn read(&self, name: &str) -> Result<Option<String>, AstarteMessageHubError>;
+
+    /// Write a secret, replacing the stored one.
+    fn write(&self, name: &str, secret: &str) -> Result<(), AstarteMessageHubError>;
+}
+
+/// Hex encoded SHA-256 digest of a secret, to recognize it without storing it.
+pub fn secret_digest(secret: &str) -> String {
+    digest(&SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Open the secret store configured in the options.
+///
+/// The encrypted store migrates the plaintext secrets left in the store directory.
+pub fn open(
+    options: &SecretsOptions,
+    store_directory: &Path,
+) -> Result<Box<dyn SecretStore>, AstarteMessageHubError> {
+    match options.backend {
+        SecretBackend::Plaintext => Ok(Box::new(PlaintextSecretStore::new(store_directory))),
+        SecretBackend::Encrypted => {
+            let key = match (&options.key_file, &options.keyring_key) {
+                (Some(key_file), _) => read_key_file(key_file)?,
+                (None, Some(description)) => read_keyring_key(description)?,
+                (None, None) => {
+                    return Err(AstarteMessageHubError::FatalError(
+                        "missing the key of the encrypted secret store".to_string(),
+                    ))
+                }
+            };
+
+            let store = EncryptedSecretStore::new(store_directory, &key)?;
+            store.migrate()?;
+
+            Ok(Box::new(store))
+        }
+    }
+}
+
+/// Secrets stored in plaintext files, only readable by the owner.
+#[derive(Debug, Clone)]
+pub struct PlaintextSecretStore {
+    directory: PathBuf,
+}
+
+impl PlaintextSecretStore {
+    /// Create a store keeping the secrets in the given directory.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl SecretStore for PlaintextSecretStore {
+    fn read(&self, name: &str) -> Result<Option<String>, AstarteMessageHubError> {
+        let path = self.directory.join(name);
+
+        match fs::read_to_string(&path) {
+            Ok(secret) => Ok(Some(secret)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(AstarteMessageHubError::FatalError(format!(
+                "failed to read {}: {}",
+                path.to_string_lossy(),
+                err
+            ))),
+        }
+    }
+
+    fn write(&self, name: &str, secret: &str) -> Result<(), AstarteMessageHubError> {
+        let path = self.directory.join(name);
+
+        persist::write_private(&path, secret).map_err(|err| {
+            AstarteMessageHubError::FatalError(format!(
+                "failed to write {}: {}",
+                path.to_string_lossy(),
+                err
+            ))
+        })
+    }
+}
+
+/// Secrets stored in files encrypted with AES-256-GCM.
+///
+/// Each file contains the version of the format, the random nonce and the encrypted secret,
+/// authenticated together with the name of the secret.
+pub struct EncryptedSecretStore {
+    plaintext: PlaintextSecretStore,
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl EncryptedSecretStore {
+    /// Create a store keeping the secrets in the given directory, with a key derived from the
+    /// given material.
+    pub fn new(directory: impl Into<PathBuf>, key: &[u8]) -> Result<Self, AstarteMessageHubError> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(AstarteMessageHubError::FatalError(format!(
+                "the key of the encrypted secret store must be at least {} bytes",
+                MIN_KEY_LEN
+            )));
+        }
+
+        let key = digest(&SHA256, key);
+        let key = UnboundKey::new(&AES_256_GCM, key.as_ref())
+            .map_err(|_| AstarteMessageHubError::FatalError("invalid encryption key".into()))?;
+
+        Ok(Self {
+            plaintext: PlaintextSecretStore::new(directory),
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn encrypted_name(name: &str) -> String {
+        format!("{}.{}", name, ENCRYPTED_EXTENSION)
+    }
+
+    /// Encrypt the plaintext secrets left in the directory, removing them.
+    ///
+    /// If a secret is both in plaintext and encrypted with different values, as after switching
+    /// the backend to plaintext and back, the most recently written one is kept.
+    pub fn migrate(&self) -> Result<(), AstarteMessageHubError> {
+        for name in SECRETS {
+            let secret = match self.plaintext.read(name)? {
+                Some(secret) => secret,
+                None => continue,
+            };
+
+            let plaintext_path = self.plaintext.directory.join(name);
+
+            match self.read(name)? {
+                None => self.write(name, &secret)?,
+                Some(encrypted) if encrypted == secret => {}
+                Some(_) => {
+                    let encrypted_path = self.plaintext.directory.join(Self::encrypted_name(name));
+                    let plaintext_modified = fs::metadata(&plaintext_path)?.modified()?;
+                    let encrypted_modified = fs::metadata(encrypted_path)?.modified()?;
+
+                    if plaintext_modified > encrypted_modified {
+                        warn!(
+                            "replacing the encrypted {} with the newer plaintext one",
+                            name
+                        );
+
+                        self.write(name, &secret)?;
+                    } else {
+                        warn!(
+                            "discarding the plaintext {}, older than the encrypted one",
+                            name
+                        );
+                    }
+                }
+            }
+
+            fs::remove_file(plaintext_path)?;
+
+            info!("encrypted the plaintext {} secret", name);
+        }
+
+        Ok(())
+    }
+}
+
+impl SecretStore for EncryptedSecretStore {
+    fn read(&self, name: &str) -> Result<Option<String>, AstarteMessageHubError> {
+        let file_name = Self::encrypted_name(name);
+        let path = self.plaintext.directory.join(&file_name);
+
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let invalid = || {
+            AstarteMessageHubError::FatalError(format!(
+                "unable to decrypt {}, the file is corrupted or the key changed",
+                path.to_string_lossy()
+            ))
+        };
+
+        let (version, content) = content.split_first().ok_or_else(invalid)?;
+        if *version != ENCRYPTED_VERSION || content.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+
+        let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?;
+
+        let mut buf = ciphertext.to_vec();
+        let secret = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut buf)
+            .map_err(|_| invalid())?;
+
+        String::from_utf8(secret.to_vec())
+            .map(Some)
+            .map_err(|_| invalid())
+    }
+
+    fn write(&self, name: &str, secret: &str) -> Result<(), AstarteMessageHubError> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| {
+            AstarteMessageHubError::FatalError("unable to generate a random nonce".into())
+        })?;
+
+        let mut buf = secret.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut buf,
+            )
+            .map_err(|_| AstarteMessageHubError::FatalError("unable to encrypt a secret".into()))?;
+
+        let mut content = Vec::with_capacity(1 + NONCE_LEN + buf.len());
+        content.push(ENCRYPTED_VERSION);
+        content.extend_from_slice(&nonce);
+        content.extend_from_slice(&buf);
+
+        let path = self.plaintext.directory.join(Self::encrypted_name(name));
+        persist::write_private(&path, content)?;
+
+        Ok(())
+    }
+}
+
+/// Read the key material from a file, which must not be accessible by the other users.
+fn read_key_file(path: &Path) -> Result<Vec<u8>, AstarteMessageHubError> {
+    match persist::exposure(path)? {
+        Some(Exposure::Private) => {}
+        Some(_) => {
+            return Err(AstarteMessageHubError::InsecurePermissions(
+                path.to_path_buf(),
+            ))
+        }
+        None => {
+            return Err(AstarteMessageHubError::FatalError(format!(
+                "missing the key file {}",
+                path.to_string_lossy()
+            )))
+        }
+    }
+
+    Ok(fs::read(path)?)
+}
+
+/// Read the key material from a `user` key of the kernel keyring, searched in the keyrings of the
+/// process.
+fn read_keyring_key(description: &str) -> Result<Vec<u8>, AstarteMessageHubError> {
+    const KEYCTL_READ: c_long = 11;
+
+    let key_type = CString::new("user").expect("the key type has no nul bytes");
+    let description = CString::new(description).map_err(|_| {
+        AstarteMessageHubError::FatalError("invalid keyring key description".into())
+    })?;
+
+    // SAFETY: the strings are nul terminated and the callout info can be null.
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_request_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            std::ptr::null::<c_char>(),
+            0,
+        )
+    };
+    if serial < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        // SAFETY: the buffer is valid for its length, the key is read up to the length.
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                serial,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let len = len as usize;
+        if len <= buf.len() {
+            buf.truncate(len);
+
+            return Ok(buf);
+        }
+
+        // The key is larger than the buffer, read it again
+        buf.resize(len, 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn plaintext_secrets() {
+        let dir = TempDir::new().unwrap();
+        let store = PlaintextSecretStore::new(dir.path());
+
+        assert_eq!(store.read(CREDENTIALS_SECRET).unwrap(), None);
+
+        store.write(CREDENTIALS_SECRET, "secret").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join(CREDENTIALS_SECRET)).unwrap(),
+            "secret"
+        );
+        assert_eq!(
+            store.read(CREDENTIALS_SECRET).unwrap(),
+            Some("secret".to_string())
+        );
+    }
+
+    #[test]
+    fn encrypted_secrets() {
+        let dir = TempDir::new().unwrap();
+        let store = EncryptedSecretStore::new(dir.path(), KEY).unwrap();
+
+        assert_eq!(store.read(PAIRING_TOKEN).unwrap(), None);
+
+        store.write(PAIRING_TOKEN, "token").unwrap();
+
+        let content = fs::read(dir.path().join("pairing_token.enc")).unwrap();
+        assert!(!content.windows(5).any(|window| window == b"token"));
+        assert!(!dir.path().join(PAIRING_TOKEN).exists());
+
+        assert_eq!(
+            store.read(PAIRING_TOKEN).unwrap(),
+            Some("token".to_string())
+        );
+
+        // The secret is bound to its name and to the key
+        fs::copy(
+            dir.path().join("pairing_token.enc"),
+            dir.path().join("credentials_secret.enc"),
+        )
+        .unwrap();
+        assert!(store.read(CREDENTIALS_SECRET).is_err());
+
+        let other =
+            EncryptedSecretStore::new(dir.path(), b"another key, long at least 32 bytes").unwrap();
+        assert!(other.read(PAIRING_TOKEN).is_err());
+
+        assert!(EncryptedSecretStore::new(dir.path(), b"short").is_err());
+    }
+
+    #[test]
+    fn migrate_plaintext_secrets() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(CREDENTIALS_SECRET), "secret").unwrap();
+
+        let key_file = dir.path().join("secrets.key");
+        fs::write(&key_file, KEY).unwrap();
+        fs::set_permissions(&key_file, Permissions::from_mode(0o644)).unwrap();
+
+        let options = SecretsOptions {
+            backend: SecretBackend::Encrypted,
+            key_file: Some(key_file.clone()),
+            keyring_key: None,
+        };
+
+        // The key must be private
+        assert!(matches!(
+            open(&options, dir.path()),
+            Err(AstarteMessageHubError::InsecurePermissions(_))
+        ));
+
+        fs::set_permissions(&key_file, Permissions::from_mode(0o600)).unwrap();
+
+        let store = open(&options, dir.path()).unwrap();
+
+        assert!(!dir.path().join(CREDENTIALS_SECRET).exists());
+        assert!(dir.path().join("credentials_secret.enc").exists());
+        assert_eq!(
+            store.read(CREDENTIALS_SECRET).unwrap(),
+            Some("secret".to_string())
+        );
+    }
+
+    #[test]
+    fn migrate_keeps_newer_secret() {
+        let dir = TempDir::new().unwrap();
+        let store = EncryptedSecretStore::new(dir.path(), KEY).unwrap();
+        // Let the files written one after the other have different modification times
+        let wait = || std::thread::sleep(std::time::Duration::from_millis(20));
+
+        // A newer plaintext secret, written with the plaintext backend, replaces the encrypted one
+        store.write(CREDENTIALS_SECRET, "old").unwrap();
+        wait();
+        fs::write(dir.path().join(CREDENTIALS_SECRET), "new").unwrap();
+
+        store.migrate().unwrap();
+
+        assert!(!dir.path().join(CREDENTIALS_SECRET).exists());
+        assert_eq!(
+            store.read(CREDENTIALS_SECRET).unwrap(),
+            Some("new".to_string())
+        );
+
+        // An older plaintext secret is discarded
+        fs::write(dir.path().join(CREDENTIALS_SECRET), "old").unwrap();
+        wait();
+        store.write(CREDENTIALS_SECRET, "new").unwrap();
+
+        store.migrate().unwrap();
+
+        assert!(!dir.path().join(CREDENTIALS_SECRET).exists());
+        assert_eq!(
+            store.read(CREDENTIALS_SECRET).unwrap(),
+            Some("new".to_string())
+        );
+    }
+}